@@ -0,0 +1,9 @@
+use std::fmt;
+
+/// Decides whether the server should accept new connections right now, e.g. to stop taking on
+/// more work while file descriptors or memory run low and pick up again once they recover.
+/// Checked every `Config::accept_gate_interval` from the accept loop, so it should be cheap.
+/// Connections already accepted are unaffected by the answer.
+pub trait AcceptGate: fmt::Debug + Send + Sync {
+    fn should_accept(&self) -> bool;
+}
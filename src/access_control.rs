@@ -0,0 +1,310 @@
+use std::fs;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessAction {
+    Allow,
+    Deny,
+}
+
+/// What part of a destination an `AccessRule` looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessTarget {
+    /// Every destination.
+    All,
+    /// Destination IPs in the network `addr/prefix_len`. Checked against the address actually
+    /// connected to, including every address a domain name resolved to.
+    Network {
+        addr: IpAddr,
+        prefix_len: u8,
+    },
+    /// A domain name and all of its subdomains, matched case-insensitively. Only applies to
+    /// requests made by domain name.
+    Domain(String),
+    Ports(RangeInclusive<u16>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRule {
+    pub action: AccessAction,
+    pub target: AccessTarget,
+}
+
+/// Rules deciding which destinations clients may connect to. Rules are evaluated in order and
+/// the first one that matches decides; destinations no rule matches are allowed, so end with
+/// `deny all` to only allow what is listed.
+///
+/// The text format has one rule per line, `#` starting a comment:
+///
+/// ```text
+/// allow 10.0.0.0/8
+/// deny 192.168.1.1
+/// deny example.com
+/// allow-port 443
+/// deny-port 6000-6063
+/// deny all
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessControl {
+    pub rules: Vec<AccessRule>,
+}
+
+impl AccessControl {
+    pub fn new(rules: Vec<AccessRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Reads rules from a file in the text format. Malformed lines are skipped with a warning
+    /// rather than failing the whole file.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::parse(&fs::read_to_string(path)?))
+    }
+
+    /// Parses rules in the text format, skipping malformed lines with a warning.
+    pub fn parse(text: &str) -> Self {
+        let mut rules = Vec::new();
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            match parse_rule(line) {
+                Some(rule) => rules.push(rule),
+                None => eprintln!(
+                    "Skipping malformed access control rule on line {}: {}",
+                    line_number + 1,
+                    line
+                ),
+            }
+        }
+
+        Self { rules }
+    }
+
+    /// Whether a connection to `addr` is allowed, `domain` being the name it was resolved from
+    /// for requests made by domain name.
+    pub fn is_allowed(&self, domain: Option<&str>, addr: SocketAddr) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.target.matches(domain, addr))
+            .is_none_or(|rule| rule.action == AccessAction::Allow)
+    }
+}
+
+impl AccessTarget {
+    pub(crate) fn matches(&self, domain: Option<&str>, addr: SocketAddr) -> bool {
+        match self {
+            AccessTarget::All => true,
+            AccessTarget::Network {
+                addr: network,
+                prefix_len,
+            } => network_contains(*network, *prefix_len, addr.ip()),
+            AccessTarget::Domain(rule_domain) => domain.is_some_and(|domain| {
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                let rule_domain = rule_domain.trim_end_matches('.').to_ascii_lowercase();
+                domain == rule_domain || domain.ends_with(&format!(".{}", rule_domain))
+            }),
+            AccessTarget::Ports(ports) => ports.contains(&addr.port()),
+        }
+    }
+}
+
+fn network_contains(network: IpAddr, prefix_len: u8, ip: IpAddr) -> bool {
+    match (network.to_canonical(), ip.to_canonical()) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+fn parse_rule(line: &str) -> Option<AccessRule> {
+    let mut words = line.split_whitespace();
+    let keyword = words.next()?;
+    let value = words.next()?;
+    if words.next().is_some() {
+        return None;
+    }
+
+    let (action, is_port_rule) = match keyword {
+        "allow" => (AccessAction::Allow, false),
+        "deny" => (AccessAction::Deny, false),
+        "allow-port" => (AccessAction::Allow, true),
+        "deny-port" => (AccessAction::Deny, true),
+        _ => return None,
+    };
+
+    let target = if is_port_rule {
+        AccessTarget::Ports(parse_ports(value)?)
+    } else if value == "all" {
+        AccessTarget::All
+    } else if let Some(network) = parse_network(value) {
+        network
+    } else if value.contains('.') && !value.contains('/') && !value.contains(':') {
+        AccessTarget::Domain(value.to_string())
+    } else {
+        return None;
+    };
+
+    Some(AccessRule { action, target })
+}
+
+// A single port or an inclusive range such as `6000-6063`.
+fn parse_ports(value: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = value.split_once('-').unwrap_or((value, value));
+    let (start, end) = (start.parse().ok()?, end.parse().ok()?);
+
+    (start <= end).then_some(start..=end)
+}
+
+// A bare IP address, or a network in CIDR notation such as `10.0.0.0/8`.
+fn parse_network(value: &str) -> Option<AccessTarget> {
+    let (addr, prefix_len) = match value.split_once('/') {
+        Some((addr, prefix_len)) => (addr.parse::<IpAddr>().ok()?, prefix_len.parse().ok()?),
+        None => {
+            let addr = value.parse::<IpAddr>().ok()?;
+            (addr, if addr.is_ipv4() { 32 } else { 128 })
+        }
+    };
+
+    let max_prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+    (prefix_len <= max_prefix_len).then_some(AccessTarget::Network { addr, prefix_len })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: AccessAction, target: AccessTarget) -> AccessRule {
+        AccessRule { action, target }
+    }
+
+    #[test]
+    fn parses_each_rule_type() {
+        let access_control = AccessControl::parse(
+            "allow 10.0.0.0/8\n\
+             deny 192.168.1.1\n\
+             deny 2001:db8::/32\n\
+             deny example.com\n\
+             allow-port 443\n\
+             deny-port 6000-6063\n\
+             deny all\n",
+        );
+
+        assert_eq!(
+            access_control.rules,
+            [
+                rule(
+                    AccessAction::Allow,
+                    AccessTarget::Network {
+                        addr: "10.0.0.0".parse().unwrap(),
+                        prefix_len: 8,
+                    },
+                ),
+                rule(
+                    AccessAction::Deny,
+                    AccessTarget::Network {
+                        addr: "192.168.1.1".parse().unwrap(),
+                        prefix_len: 32,
+                    },
+                ),
+                rule(
+                    AccessAction::Deny,
+                    AccessTarget::Network {
+                        addr: "2001:db8::".parse().unwrap(),
+                        prefix_len: 32,
+                    },
+                ),
+                rule(
+                    AccessAction::Deny,
+                    AccessTarget::Domain("example.com".to_string()),
+                ),
+                rule(AccessAction::Allow, AccessTarget::Ports(443..=443)),
+                rule(AccessAction::Deny, AccessTarget::Ports(6000..=6063)),
+                rule(AccessAction::Deny, AccessTarget::All),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_and_malformed_lines() {
+        let access_control = AccessControl::parse(
+            "# internal networks\n\
+             \n\
+             allow 10.0.0.0/8 # office\n\
+             permit 10.0.0.0/8\n\
+             deny 10.0.0.0/33\n\
+             deny-port 80-79\n\
+             deny localhost\n\
+             deny all extra\n",
+        );
+
+        assert_eq!(
+            access_control.rules,
+            [rule(
+                AccessAction::Allow,
+                AccessTarget::Network {
+                    addr: "10.0.0.0".parse().unwrap(),
+                    prefix_len: 8,
+                },
+            )]
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let access_control = AccessControl::parse(
+            "allow 10.1.0.0/16\n\
+             deny 10.0.0.0/8\n\
+             deny-port 25\n",
+        );
+
+        assert!(access_control.is_allowed(None, "10.1.2.3:25".parse().unwrap()));
+        assert!(!access_control.is_allowed(None, "10.2.3.4:443".parse().unwrap()));
+        assert!(!access_control.is_allowed(None, "192.0.2.1:25".parse().unwrap()));
+        // Nothing matches, so it is allowed.
+        assert!(access_control.is_allowed(None, "192.0.2.1:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn matches_domain_and_its_subdomains_only() {
+        let access_control = AccessControl::parse("deny example.com");
+        let addr = "192.0.2.1:443".parse().unwrap();
+
+        assert!(!access_control.is_allowed(Some("example.com"), addr));
+        assert!(!access_control.is_allowed(Some("WWW.Example.com."), addr));
+        assert!(access_control.is_allowed(Some("notexample.com"), addr));
+        assert!(access_control.is_allowed(None, addr));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_against_ipv4_networks() {
+        let access_control = AccessControl::parse("deny 127.0.0.0/8");
+
+        assert!(!access_control.is_allowed(None, "[::ffff:127.0.0.1]:80".parse().unwrap()));
+        assert!(access_control.is_allowed(None, "[::1]:80".parse().unwrap()));
+    }
+
+    #[test]
+    fn reads_rules_from_file() {
+        let path = std::env::temp_dir().join(format!("access-control-{}.txt", std::process::id()));
+        fs::write(&path, "deny all\n").unwrap();
+
+        let access_control = AccessControl::from_file(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            access_control.unwrap().rules,
+            [rule(AccessAction::Deny, AccessTarget::All)]
+        );
+    }
+}
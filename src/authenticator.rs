@@ -0,0 +1,14 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+
+/// Future returned by `Authenticator::authenticate`.
+pub type AuthenticateFuture<'a> = Pin<Box<dyn Future<Output = io::Result<bool>> + Send + 'a>>;
+
+/// Checks username/password credentials against a store of its own, e.g. a database, for
+/// logins that aren't listed in `AuthParams::logins`. Resolves to whether the credentials are
+/// valid; an error counts as rejecting them.
+pub trait Authenticator: fmt::Debug + Send + Sync {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> AuthenticateFuture<'a>;
+}
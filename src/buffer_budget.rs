@@ -0,0 +1,114 @@
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Notify;
+
+// Bytes relays of a server have read but not written out yet, across all connections. With a
+// limit, reads pause while the total is at or above it and resume as writes drain it. Every
+// relay direction that found room may still read one more chunk, so the total can go over the
+// limit by up to one chunk per direction, but not further.
+#[derive(Debug)]
+pub(crate) struct BufferBudget {
+    limit: Option<u64>,
+    buffered: AtomicU64,
+    high_water_mark: AtomicU64,
+    drained: Notify,
+}
+
+impl BufferBudget {
+    pub fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit,
+            buffered: AtomicU64::new(0),
+            high_water_mark: AtomicU64::new(0),
+            drained: Notify::new(),
+        }
+    }
+
+    // Waits until the total is below the limit, which is right away without one.
+    pub async fn wait_for_room(&self) {
+        let Some(limit) = self.limit else {
+            return;
+        };
+
+        loop {
+            // Registered before checking, so a drain between the check and the wait isn't missed.
+            let mut drained = pin!(self.drained.notified());
+            drained.as_mut().enable();
+            if self.buffered.load(Ordering::Relaxed) < limit {
+                return;
+            }
+            drained.await;
+        }
+    }
+
+    // Counts `n` freshly read bytes as buffered until the returned guard is dropped.
+    pub fn fill(&self, n: usize) -> Buffered<'_> {
+        let n = n as u64;
+        let buffered = self.buffered.fetch_add(n, Ordering::Relaxed) + n;
+        self.high_water_mark.fetch_max(buffered, Ordering::Relaxed);
+
+        Buffered { budget: self, n }
+    }
+
+    pub fn buffered(&self) -> u64 {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    // Highest number of bytes that were buffered at the same time.
+    pub fn high_water_mark(&self) -> u64 {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+}
+
+// Bytes counted against a `BufferBudget`, released once they have been written out or dropped.
+pub(crate) struct Buffered<'a> {
+    budget: &'a BufferBudget,
+    n: u64,
+}
+
+impl Drop for Buffered<'_> {
+    fn drop(&mut self) {
+        self.budget.buffered.fetch_sub(self.n, Ordering::Relaxed);
+        self.budget.drained.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn waits_for_room_until_buffered_bytes_drain() {
+        let budget = BufferBudget::new(Some(100));
+        let first = budget.fill(60);
+        budget.wait_for_room().await;
+        let second = budget.fill(60);
+        assert_eq!(budget.buffered(), 120);
+
+        assert!(timeout(Duration::from_millis(50), budget.wait_for_room())
+            .await
+            .is_err());
+        drop(first);
+        timeout(Duration::from_secs(1), budget.wait_for_room())
+            .await
+            .unwrap();
+
+        drop(second);
+        assert_eq!(budget.buffered(), 0);
+        assert_eq!(budget.high_water_mark(), 120);
+    }
+
+    #[tokio::test]
+    async fn never_waits_without_a_limit() {
+        let budget = BufferBudget::new(None);
+        let _buffered = budget.fill(1 << 30);
+        timeout(Duration::from_secs(1), budget.wait_for_room())
+            .await
+            .unwrap();
+    }
+}
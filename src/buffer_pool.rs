@@ -0,0 +1,96 @@
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Relay buffers shared by all connections of a server, to avoid allocating and freeing one
+// per direction of every connection. At most `max_pooled` idle buffers are kept, which bounds
+// the memory the pool holds on to. When every pooled buffer is in use a new one is allocated
+// instead of waiting, and it joins the pool on release if there is room for it.
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffer_size: usize,
+    max_pooled: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+    in_use: AtomicUsize,
+    high_water_mark: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_pooled: usize) -> Self {
+        Self {
+            buffer_size,
+            max_pooled,
+            idle: Mutex::new(Vec::with_capacity(max_pooled)),
+            in_use: AtomicUsize::new(0),
+            high_water_mark: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn checkout(self: &Arc<Self>) -> RelayBuffer {
+        let in_use = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water_mark.fetch_max(in_use, Ordering::Relaxed);
+
+        let buf = self
+            .idle
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_else(|| vec![0; self.buffer_size].into_boxed_slice());
+
+        RelayBuffer {
+            buf,
+            pool: Some(Arc::clone(self)),
+        }
+    }
+
+    // Highest number of buffers that were checked out at the same time.
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark.load(Ordering::Relaxed)
+    }
+
+    fn release(&self, buf: Box<[u8]>) {
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.max_pooled {
+            idle.push(buf);
+        }
+    }
+}
+
+// A relay buffer, returned to its pool (if it came from one) when dropped.
+pub(crate) struct RelayBuffer {
+    buf: Box<[u8]>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl RelayBuffer {
+    pub fn unpooled(buffer_size: usize) -> Self {
+        Self {
+            buf: vec![0; buffer_size].into_boxed_slice(),
+            pool: None,
+        }
+    }
+}
+
+impl Deref for RelayBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for RelayBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for RelayBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.take() {
+            pool.release(std::mem::take(&mut self.buf));
+        }
+    }
+}
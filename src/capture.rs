@@ -0,0 +1,218 @@
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+// Handshake bytes exchanged with a client, in the order they went over the wire. Each entry is
+// a single read (client to server) or write (server to client) on the socket.
+#[derive(Debug, Default)]
+pub(crate) struct HandshakeBytes {
+    chunks: Vec<(Direction, Vec<u8>)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+impl HandshakeBytes {
+    fn record(&mut self, direction: Direction, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+
+        match self.chunks.last_mut() {
+            Some((last_direction, chunk)) if *last_direction == direction => {
+                chunk.extend_from_slice(bytes)
+            }
+            _ => self.chunks.push((direction, bytes.to_vec())),
+        }
+    }
+
+    // Renders one line per chunk, prefixed with `C>` for client bytes and `S<` for server
+    // bytes, followed by the bytes in hex.
+    fn render(&self) -> String {
+        let mut output = String::new();
+        for (direction, chunk) in &self.chunks {
+            output.push_str(match direction {
+                Direction::ClientToServer => "C>",
+                Direction::ServerToClient => "S<",
+            });
+            write_hex(&mut output, chunk);
+            output.push('\n');
+        }
+
+        output
+    }
+
+    // Writes the transcript to its own file inside `dir`, named after the capture time and the
+    // client address.
+    pub async fn save(&self, dir: &Path, client_addr: SocketAddr) -> io::Result<()> {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+        let client = client_addr.to_string().replace([':', '[', ']'], "_");
+        let path = dir.join(format!("handshake-{}-{}.txt", millis, client));
+
+        fs::write(path, self.render()).await
+    }
+}
+
+fn write_hex(output: &mut String, bytes: &[u8]) {
+    for byte in bytes {
+        let _ = write!(output, " {:02x}", byte);
+    }
+}
+
+fn sent_bytes_log_line(client_addr: SocketAddr, bytes: &[u8]) -> String {
+    let mut line = format!("Sent to {}:", client_addr);
+    write_hex(&mut line, bytes);
+    line
+}
+
+// Passes reads and writes through to `inner`, recording them into `transcript` when one is
+// given, and logging every write in hex when `log_writes_to` names the client.
+pub(crate) struct RecordingStream<'a, S> {
+    inner: &'a mut S,
+    transcript: Option<&'a mut HandshakeBytes>,
+    log_writes_to: Option<SocketAddr>,
+}
+
+impl<'a, S> RecordingStream<'a, S> {
+    pub fn new(
+        inner: &'a mut S,
+        transcript: Option<&'a mut HandshakeBytes>,
+        log_writes_to: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            inner,
+            transcript,
+            log_writes_to,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for RecordingStream<'_, S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+
+        let poll = Pin::new(&mut *this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(transcript)) = (&poll, this.transcript.as_mut()) {
+            transcript.record(Direction::ClientToServer, &buf.filled()[filled_before..]);
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for RecordingStream<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            if let Some(transcript) = this.transcript.as_mut() {
+                transcript.record(Direction::ServerToClient, &buf[..*n]);
+            }
+            if let Some(client_addr) = this.log_writes_to {
+                println!("{}", sent_bytes_log_line(client_addr, &buf[..*n]));
+            }
+        }
+
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::packets::server_reply::ServerReply;
+
+    #[tokio::test]
+    async fn records_reads_and_writes_in_order() {
+        let (mut client, mut server) = duplex(64);
+        let mut transcript = HandshakeBytes::default();
+        let mut stream = RecordingStream::new(&mut server, Some(&mut transcript), None);
+
+        client.write_all(&[5, 1]).await.unwrap();
+        let mut hello = [0; 3];
+        stream.read_exact(&mut hello[..2]).await.unwrap();
+        client.write_all(&[0]).await.unwrap();
+        stream.read_exact(&mut hello[2..]).await.unwrap();
+        stream.write_all(&[5, 0]).await.unwrap();
+        stream.write_all(&[5, 0, 0, 1]).await.unwrap();
+
+        // Consecutive chunks in the same direction are merged into one line.
+        assert_eq!(transcript.render(), "C> 05 01 00\nS< 05 00 05 00 00 01\n");
+    }
+
+    #[tokio::test]
+    async fn saves_transcript_into_capture_dir() {
+        let dir = std::env::temp_dir().join(format!("socks-capture-{}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        let mut transcript = HandshakeBytes::default();
+        transcript.record(Direction::ClientToServer, &[5, 1, 0]);
+
+        transcript
+            .save(&dir, "[::1]:4242".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut entries = fs::read_dir(&dir).await.unwrap();
+        let entry = entries.next_entry().await.unwrap().unwrap();
+        let name = entry.file_name().into_string().unwrap();
+        assert!(name.starts_with("handshake-"));
+        assert!(name.ends_with("-___1__4242.txt"));
+        assert_eq!(
+            fs::read_to_string(entry.path()).await.unwrap(),
+            "C> 05 01 00\n"
+        );
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn logs_sent_reply_in_hex() {
+        let reply = ServerReply::new_successful_reply("192.0.2.1:1080".parse().unwrap());
+
+        let bytes = reply.as_bytes();
+        let line = sent_bytes_log_line("127.0.0.1:4242".parse().unwrap(), &bytes);
+
+        assert_eq!(
+            line,
+            "Sent to 127.0.0.1:4242: 05 00 00 01 c0 00 02 01 04 38"
+        );
+        let logged: Vec<u8> = line
+            .split_whitespace()
+            .skip(3)
+            .map(|byte| u8::from_str_radix(byte, 16).unwrap())
+            .collect();
+        assert_eq!(logged, bytes);
+    }
+}
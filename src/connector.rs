@@ -0,0 +1,44 @@
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+
+/// Future returned by `Connector::connect`.
+pub type ConnectFuture<'a> = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send + 'a>>;
+
+/// Opens outbound connections in place of the server's own connect logic, e.g. to hand out
+/// connections from a pool warmed up ahead of time.
+pub trait Connector: fmt::Debug + Send + Sync {
+    fn connect(&self, addr: SocketAddr) -> ConnectFuture<'_>;
+
+    /// Called once the connection returned for `addr` is done with, after its relay ended or
+    /// the request failed after connecting. The stream itself is closed by then, so this is
+    /// the place to replace it, for instance by warming up a new one.
+    fn release(&self, _addr: SocketAddr) {}
+}
+
+// Calls `Connector::release` for a connection when dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectorLease {
+    connector: Arc<dyn Connector>,
+    addr: SocketAddr,
+}
+
+impl ConnectorLease {
+    pub fn new(connector: &Arc<dyn Connector>, addr: SocketAddr) -> Self {
+        Self {
+            connector: Arc::clone(connector),
+            addr,
+        }
+    }
+}
+
+impl Drop for ConnectorLease {
+    fn drop(&mut self) {
+        self.connector.release(self.addr);
+    }
+}
@@ -0,0 +1,86 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::OnceLock;
+
+use crate::DestinationAddress;
+
+/// How request destinations are written to the server's log, see `Config::log_destination`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DestinationLogMode {
+    /// The address and port as requested, e.g. `example.com:443`.
+    #[default]
+    Full,
+    /// A salted hash of the address followed by the port, e.g. `#4f1c2a9e07b3d615:443`. The
+    /// salt is picked at random when the process starts, so the same destination hashes to the
+    /// same value for the lifetime of the process but can't be looked up in precomputed tables.
+    Hashed,
+    /// Only the port, e.g. `*:443`.
+    PortOnly,
+    /// Destinations are left out of the log entirely.
+    None,
+}
+
+impl DestinationLogMode {
+    // Returns `None` when the destination must not be logged at all.
+    pub(crate) fn render(self, addr: &DestinationAddress, port: u16) -> Option<String> {
+        match self {
+            DestinationLogMode::Full => Some(format!("{}:{}", addr, port)),
+            DestinationLogMode::Hashed => Some(format!("#{:016x}:{}", hash_salted(addr), port)),
+            DestinationLogMode::PortOnly => Some(format!("*:{}", port)),
+            DestinationLogMode::None => None,
+        }
+    }
+}
+
+// `RandomState` is seeded with random keys, so it doubles as the per-process salt as long as
+// the same instance is used for every hash.
+fn hash_salted(addr: &DestinationAddress) -> u64 {
+    static SALT: OnceLock<RandomState> = OnceLock::new();
+
+    SALT.get_or_init(RandomState::new).hash_one(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use super::*;
+
+    #[test]
+    fn renders_destination_in_each_mode() {
+        let domain = DestinationAddress::DomainName("example.com".to_string());
+        let ipv6 = DestinationAddress::Ipv6(Ipv6Addr::LOCALHOST);
+
+        assert_eq!(
+            DestinationLogMode::Full.render(&domain, 443).as_deref(),
+            Some("example.com:443")
+        );
+        assert_eq!(
+            DestinationLogMode::Full.render(&ipv6, 443).as_deref(),
+            Some("[::1]:443")
+        );
+        assert_eq!(
+            DestinationLogMode::PortOnly.render(&domain, 443).as_deref(),
+            Some("*:443")
+        );
+        assert_eq!(DestinationLogMode::None.render(&domain, 443), None);
+
+        let hashed = DestinationLogMode::Hashed.render(&domain, 443).unwrap();
+        let (hash, port) = hashed.strip_prefix('#').unwrap().split_once(':').unwrap();
+        assert_eq!(hash.len(), 16);
+        assert!(hash.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(port, "443");
+        assert!(!hashed.contains("example"));
+    }
+
+    #[test]
+    fn hashes_destinations_consistently_within_a_process() {
+        let render = |domain: &str| {
+            let addr = DestinationAddress::DomainName(domain.to_string());
+            DestinationLogMode::Hashed.render(&addr, 443).unwrap()
+        };
+
+        assert_eq!(render("example.com"), render("example.com"));
+        assert_ne!(render("example.com"), render("example.org"));
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::{AuthMethod, CloseReason, DestinationAddress, DirectionalStats, Reply, RequestCommand};
+
+/// Events published over the course of a connection, in the order below. `Closed` is always
+/// the last event of a connection, whatever stage it reached.
+#[derive(Debug, Clone)]
+pub enum ConnectionEvent {
+    Accepted {
+        id: u64,
+        client_addr: SocketAddr,
+    },
+    /// The client negotiated an auth method and, for `UserPassword`, passed authentication.
+    Authenticated {
+        id: u64,
+        method: AuthMethod,
+        username: Option<String>,
+    },
+    /// The request was answered with a failure reply.
+    RequestDenied {
+        id: u64,
+        reply: Reply,
+    },
+    /// `destination_addr` and `destination_port` are the destination as requested by the client,
+    /// `remote_addr` is the address the outbound connection actually ended up connected to,
+    /// e.g. the one a domain name resolved to.
+    RelayStarted {
+        id: u64,
+        destination_addr: DestinationAddress,
+        destination_port: u16,
+        remote_addr: SocketAddr,
+    },
+    Closed {
+        id: u64,
+        stats: ConnectionStats,
+    },
+}
+
+/// Outcome of a connection, as returned by `SocksServer::handle_connection`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionSummary {
+    /// Same ID as in the connection's events.
+    pub id: u64,
+    /// Client address, as given by the PROXY header when `Config::expect_proxy_protocol` is set.
+    pub client_addr: SocketAddr,
+    pub stats: ConnectionStats,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Command the client requested, or `None` if the connection closed before a request was
+    /// read.
+    pub command: Option<RequestCommand>,
+    pub bytes_client_to_remote: u64,
+    pub bytes_remote_to_client: u64,
+    /// Time from accepting the connection until it closed.
+    pub duration: Duration,
+    /// Why the relay ended, or `None` if the connection closed before relaying anything.
+    pub close_reason: Option<CloseReason>,
+    /// How each direction of the relay went, or `None` if the connection closed before
+    /// relaying anything.
+    pub directions: Option<DirectionalStats>,
+    pub handshake: HandshakeTranscript,
+}
+
+/// What the client and server said to each other during the SOCKS handshake, e.g. to check
+/// interop with a client automatically. Each field is `None` (or empty) if the connection
+/// closed before that point, and for connections served in transparent mode, which have no
+/// handshake. The requested command is in `ConnectionStats::command`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakeTranscript {
+    /// Protocol version from the client hello.
+    pub version: Option<u8>,
+    pub offered_methods: Vec<AuthMethod>,
+    /// Method bytes offered that don't correspond to any `AuthMethod`, e.g. private methods.
+    pub unknown_methods: Vec<u8>,
+    /// Method the server picked, `NoAcceptableMethod` when none of the offered ones fit.
+    pub selected_method: Option<AuthMethod>,
+    /// Username sent in the username/password subnegotiation. The password is never recorded.
+    pub username: Option<String>,
+    /// Whether the username/password credentials were accepted.
+    pub authenticated: Option<bool>,
+    pub destination_addr: Option<DestinationAddress>,
+    pub destination_port: Option<u16>,
+    /// Reply sent to the request.
+    pub reply: Option<Reply>,
+}
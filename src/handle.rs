@@ -0,0 +1,100 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, watch};
+use tokio::task;
+
+use crate::{
+    AuthMethod, AuthSettings, Config, ConfigError, ConnectionEvent, Metrics, RelayProgress,
+    SocksServer,
+};
+
+/// Handle to a server started with `SocksServer::spawn`. Clones are cheap and all control the
+/// same server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    server: Arc<SocksServer>,
+    local_addr: SocketAddr,
+    // Flips to `true` once the accept loop has returned.
+    stopped: watch::Receiver<bool>,
+}
+
+impl ServerHandle {
+    pub(crate) fn spawn(
+        server: SocksServer,
+        listener: TcpListener,
+        local_addr: SocketAddr,
+    ) -> Self {
+        let server = Arc::new(server);
+        let (stopped_tx, stopped) = watch::channel(false);
+
+        let accept_server = Arc::clone(&server);
+        task::spawn(async move {
+            accept_server.serve(listener, local_addr).await;
+            stopped_tx.send_replace(true);
+        });
+
+        Self {
+            server,
+            local_addr,
+            stopped,
+        }
+    }
+
+    /// Address the server is listening on, with the actual port when it was spawned on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        self.server.metrics()
+    }
+
+    /// Number of connections currently being served.
+    pub fn active_connections(&self) -> u64 {
+        self.server.metrics().active_connections
+    }
+
+    /// Subscribes to connection events, see `SocksServer::subscribe`.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.server.subscribe()
+    }
+
+    /// Watches a connection's relay progress, see `SocksServer::relay_progress`.
+    pub fn relay_progress(&self, id: u64) -> Option<watch::Receiver<RelayProgress>> {
+        self.server.relay_progress(id)
+    }
+
+    /// Pauses a connection's relay, see `SocksServer::pause_connection`.
+    pub fn pause_connection(&self, id: u64) -> bool {
+        self.server.pause_connection(id)
+    }
+
+    /// Resumes a paused connection's relay, see `SocksServer::resume_connection`.
+    pub fn resume_connection(&self, id: u64) -> bool {
+        self.server.resume_connection(id)
+    }
+
+    /// Replaces the server's settings for new connections, see `SocksServer::reload`.
+    pub fn reload(&self, auth_settings: AuthSettings, config: Config) -> Result<(), ConfigError> {
+        self.server.reload(auth_settings, config)
+    }
+
+    /// Auth methods the server accepts, see `SocksServer::accepted_auth_methods`.
+    pub fn accepted_auth_methods(&self) -> Vec<AuthMethod> {
+        self.server.accepted_auth_methods()
+    }
+
+    /// Starts a graceful shutdown, see `SocksServer::shutdown`.
+    pub fn shutdown(&self) {
+        self.server.shutdown();
+    }
+
+    /// Waits until the server has stopped accepting and every connection it accepted has
+    /// closed.
+    pub async fn stopped(&self) {
+        // An error means the accept loop's task is gone, which also means it has stopped.
+        let _ = self.stopped.clone().wait_for(|&stopped| stopped).await;
+    }
+}
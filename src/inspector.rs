@@ -0,0 +1,13 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+/// Looks at the first bytes a client sends through its relay before the destination gets any
+/// of them, e.g. to detect the application protocol or to apply policy based on a TLS server
+/// name. Called inline on the connection task, so it should decide quickly.
+pub trait Inspector: fmt::Debug + Send + Sync {
+    /// `data` is what the client had sent when the relay started, at most
+    /// `Config::initial_peek_bytes` of it. It's left in place and relayed unchanged, so an
+    /// inspector only ever decides, never alters. Returns whether to go on relaying; `false`
+    /// closes the connection before anything reaches `remote_addr`.
+    fn inspect(&self, client_addr: SocketAddr, remote_addr: SocketAddr, data: &[u8]) -> bool;
+}
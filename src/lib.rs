@@ -1,25 +1,98 @@
 #![cfg_attr(feature = "unstable", feature(io_error_more))]
 
-use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::future::{self, Future};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::{Deref, RangeInclusive};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::Duration;
 
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::task;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::{broadcast, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+// All timing goes through `tokio::time` so it follows the runtime clock, which can be paused
+// and advanced deterministically (`tokio::time::pause`) instead of waiting on the wall clock.
+use tokio::time::{self, Instant, MissedTickBehavior};
 
+mod accept_gate;
+mod access_control;
+mod authenticator;
+mod buffer_budget;
+mod buffer_pool;
+mod capture;
+mod connector;
+mod destination_log;
+mod events;
+mod handle;
+mod inspector;
+mod limits;
+mod metrics;
+mod negative_cache;
+mod outbound_pool;
+#[cfg(feature = "tls")]
+mod outbound_tls;
 mod packets;
+mod relay;
+mod resolver;
+mod startup_report;
+mod udp_association;
+mod user_quota;
 
+pub use accept_gate::AcceptGate;
+pub use access_control::{AccessAction, AccessControl, AccessRule, AccessTarget};
+pub use authenticator::{AuthenticateFuture, Authenticator};
+use buffer_budget::BufferBudget;
+use buffer_pool::BufferPool;
+use capture::{HandshakeBytes, RecordingStream};
+use connector::ConnectorLease;
+pub use connector::{ConnectFuture, Connector};
+pub use destination_log::DestinationLogMode;
+pub use events::{ConnectionEvent, ConnectionStats, ConnectionSummary, HandshakeTranscript};
+pub use handle::ServerHandle;
+pub use inspector::Inspector;
+use limits::{ConnectionCounter, ConnectionPermit};
+use metrics::MetricsRegistry;
+pub use metrics::{MetricEvent, Metrics, MetricsSink};
+use negative_cache::ConnectFailureCache;
+use outbound_pool::OutboundBindPool;
+pub use outbound_pool::OutboundBindStrategy;
+#[cfg(feature = "tls")]
+pub use outbound_tls::OutboundTls;
+use relay::{handle_packet_relay, DEFAULT_RELAY_CHUNK_SIZE};
+pub use relay::{
+    relay, CloseReason, DirectionEnd, DirectionStats, DirectionalStats, RelayDirection, RelayMode,
+    RelayOutcome, RelayProgress,
+};
+pub use resolver::{ResolveFuture, Resolver, SystemResolver};
+pub use startup_report::StartupReport;
+use udp_association::UdpAssociation;
+pub use user_quota::UserByteQuota;
+use user_quota::{UserAccount, UserUsage};
+
+use packets::client_hello::{is_partial_http_method, ClientHello};
+use packets::client_request::ClientRequest;
+pub use packets::client_request::RequestCommand;
 use packets::client_user_pass_auth::ClientUserPassAuth;
 use packets::errors::{
-    ClientHelloError, ClientRequestError, ServerHelloError, ServerReplyError, UserPassAuthError,
+    ClientHelloError, ClientRequestError, HandshakeBudgetError, ProxyHeaderError, ServerHelloError,
+    ServerReplyError, UserPassAuthError,
 };
+pub use packets::errors::{ConfigError, ResolveError};
+use packets::proxy_header::{ProxyHeader, PROXY_V1_MAX_LEN, PROXY_V1_PREFIX, PROXY_V2_SIGNATURE};
 use packets::server_hello::ServerHello;
-use packets::server_reply::{Reply, ServerReply};
+pub use packets::server_reply::Reply;
+use packets::server_reply::ServerReply;
 use packets::server_user_pass_response::ServerUserPassResponse;
+use packets::tls_client_hello::{parse_server_name, ServerName};
 pub use packets::AuthMethod;
-use packets::DestinationAddress;
-use packets::{client_hello::ClientHello, client_request::ClientRequest};
+pub use packets::{AddressType, DestinationAddress};
 
 #[derive(Debug, Clone)]
 pub struct AuthParams {
@@ -32,26 +105,839 @@ pub struct AuthSettings {
     pub params: Option<AuthParams>,
 }
 
+/// What to do about relays where no data moves in either direction, see `Config::idle_policy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdlePolicy {
+    /// Close the relay once no data has moved either way for this long.
+    Close(Duration),
+    /// Never close a relay for being idle, since e.g. an idle SSH session is still in use, and
+    /// enable TCP keepalive on both of its connections instead. A peer that is actually gone
+    /// stops answering the probes, which fails the relay with a `TimedOut` error.
+    KeepAlive(KeepaliveSettings),
+}
+
+/// TCP keepalive parameters, see `Config::outbound_keepalive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveSettings {
+    /// How long the connection must be idle before the first probe is sent.
+    pub idle: Duration,
+    /// Time between unanswered probes.
+    pub interval: Duration,
+    /// Number of unanswered probes after which the connection is considered dead.
+    pub retries: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Expect every connection to start with a PROXY protocol (v1 or v2) header, as sent by
+    /// load balancers such as HAProxy. The source address it carries replaces the socket's
+    /// peer address, and connections without a valid header are rejected.
+    pub expect_proxy_protocol: bool,
+    /// How long a connection may take to send its PROXY protocol header before it is closed.
+    pub proxy_header_timeout: Duration,
+    /// Advertise the bound address in the reply using the same address family the client
+    /// connected over (e.g. an IPv4-mapped IPv6 address for IPv6 clients), for clients that
+    /// get confused by a mismatched family on dual-stack hosts.
+    pub match_client_address_family: bool,
+    /// Log how long each stage of a connection took (hello, auth, request, outbound connect,
+    /// success reply and relay) when it closes. The reply stage runs from the moment the
+    /// outbound connect completes until the reply is written, i.e. the extra setup latency the
+    /// client sees. Connections that fail early only report the stages reached.
+    pub log_timings: bool,
+    /// Only log one in this many accepted connections, to keep busy servers' logs readable.
+    /// Rejections and errors are always logged, and metrics count every connection. 0 is
+    /// treated as 1, which logs every connection.
+    pub accept_log_sample_rate: u32,
+    /// Source ports outbound connections must originate from, for firewalls and NAT setups
+    /// that only allow a specific range. Ports in use are skipped; if none is free the request
+    /// fails with a general server failure reply. The OS picks the port when unset.
+    pub outbound_source_port_range: Option<RangeInclusive<u16>>,
+    /// Maximum number of simultaneous connections from a single client IP. Connections over
+    /// the limit are closed before any handshake takes place. With `expect_proxy_protocol`, the
+    /// client IP is the one from the PROXY header.
+    pub max_connections_per_ip: Option<usize>,
+    /// Maximum number of connections served at once. Connections over the limit wait in a
+    /// first-come, first-served queue for up to `queue_wait_timeout`, after which they are sent
+    /// a general server failure reply and closed.
+    pub max_connections: Option<usize>,
+    /// Maximum number of connections still negotiating at once, counted from the moment they
+    /// are accepted until their relay starts. Connections over the limit are closed right
+    /// away, before their client hello is read, so a flood of clients that never finish the
+    /// handshake can't crowd out established relays.
+    pub max_handshakes_in_progress: Option<usize>,
+    /// Maximum number of simultaneous outbound connections to a single destination IP and
+    /// port, across all clients, so the server can't be used to pile connections onto one
+    /// target. Domain name requests count against the address they resolved to. Requests over
+    /// the limit are answered with a connection not allowed reply.
+    pub max_connections_per_destination: Option<usize>,
+    /// How long a connection may wait in the queue for `max_connections` to let it in.
+    pub queue_wait_timeout: Duration,
+    /// Directory to record the raw handshake bytes of every connection into, one file per
+    /// connection, for replaying client interop issues. Only the SOCKS negotiation is
+    /// captured, never the relayed traffic.
+    pub handshake_capture: Option<PathBuf>,
+    /// Log the exact bytes of every control packet sent to a client during the handshake
+    /// (server hello, user/password response and request reply) in hex, to check what went
+    /// over the wire without a packet capture. Relayed traffic is never logged.
+    pub log_control_packets: bool,
+    /// Log client hellos that can't be parsed. They are counted in `malformed_hellos` either
+    /// way; turning this off keeps scanners and broken clients from flooding the log.
+    pub log_malformed_hellos: bool,
+    /// Maximum number of bytes a single connection may relay, counting both directions
+    /// together. The connection is cut off as soon as the limit is reached, even mid-transfer.
+    pub max_bytes_per_connection: Option<u64>,
+    /// Limit on the bytes each authenticated user may relay, summed over all their connections.
+    /// Connections without a username aren't limited by it.
+    pub user_byte_quota: Option<UserByteQuota>,
+    /// Answer HTTP requests sent to the SOCKS port (a client configured to use the server as an
+    /// HTTP proxy, or a browser pointed at it) with a short HTTP error explaining the mistake,
+    /// instead of just closing the connection.
+    pub http_misconnect_response: bool,
+    /// Close relays where neither side has sent anything this long after the connection was
+    /// established. Once the first byte flows in either direction the timeout no longer
+    /// applies, so it only catches connections stalled right at the start.
+    pub first_byte_timeout: Option<Duration>,
+    /// How to tell relays that are idle but alive from dead ones. With `None`, idle relays are
+    /// left alone and only the OS notices dead peers, if it ever does.
+    pub idle_policy: Option<IdlePolicy>,
+    /// When above 0, wait for the client's first bytes once a relay starts and show up to
+    /// this many of them to `inspector` before relaying anything. The bytes are peeked at,
+    /// not consumed, so the destination still gets them as sent. Destinations that speak
+    /// first (SSH, SMTP, ...) skip the inspection instead of waiting on the client.
+    pub initial_peek_bytes: usize,
+    /// Decides from the client's first bytes whether to relay the connection at all, see
+    /// `initial_peek_bytes`.
+    pub inspector: Option<Arc<dyn Inspector>>,
+    /// Check the server name (SNI) of TLS connections against `access_control` as if the
+    /// client had requested that name, closing connections whose name the rules deny. Catches
+    /// clients asking for an allowed IP but a denied host once TLS starts. The ClientHello is
+    /// read through `initial_peek_bytes`, which must be large enough to hold it (about 2 KiB
+    /// is plenty for common clients). Traffic that isn't TLS, and ClientHellos without a
+    /// server name, aren't checked.
+    pub check_tls_server_name: bool,
+    /// Close relays that move less than this many bytes per second, measured over 5-second
+    /// windows, in a direction where data is waiting to be written, e.g. because the receiving
+    /// peer reads it back at a crawl to tie up the server. A direction with nothing to write is
+    /// idle rather than slow and is never closed for it, which also means peers sending slowly
+    /// can't be told apart from idle ones.
+    pub min_transfer_rate: Option<u64>,
+    /// Close relays where a write goes this long without the receiving peer accepting a single
+    /// byte, e.g. because its receive window is frozen and the connection would otherwise hold
+    /// its slot forever. Any progress restarts the wait, so writes that are slow but moving are
+    /// never cut off.
+    pub write_stall_timeout: Option<Duration>,
+    /// How many more times to try an outbound connect that failed with a transient error
+    /// (reset, aborted or timed out), waiting `connect_retry_delay` between attempts. Refused
+    /// connections and other definitive failures are never retried.
+    pub connect_retries: u32,
+    pub connect_retry_delay: Duration,
+    /// When a destination resolves to several addresses, start connecting to the next one if
+    /// the current attempt hasn't finished after this long, keeping the earlier attempts going
+    /// ("happy eyeballs"). The first connection to succeed is used and the rest are dropped. A
+    /// failed attempt starts the next one right away. With `None`, addresses are tried strictly
+    /// one after the other, each until it succeeds or fails.
+    pub connect_attempt_delay: Option<Duration>,
+    /// After connecting, wait up to this long for the destination to reset or close the
+    /// connection before sending the success reply, so that destinations that accept and then
+    /// hang up right away (overloaded servers, honeypots) get a connection refused reply
+    /// instead of a misleading success. Such an address counts as a failed attempt, so the
+    /// next one the destination resolved to is tried. Adds this delay to every request.
+    pub destination_liveness_check: Option<Duration>,
+    /// Log outbound connects that succeed but take longer than this, resolving the destination
+    /// included, and count them in `Metrics::slow_connects`. Tells destinations that are
+    /// reachable but slow apart from fast ones; connects that fail are logged either way.
+    pub slow_connect_threshold: Option<Duration>,
+    /// Share relay buffers between connections through a pool that keeps at most this many
+    /// idle buffers, instead of allocating fresh ones for every connection. When all pooled
+    /// buffers are busy, extra ones are allocated on demand rather than making relays wait.
+    pub relay_buffer_pool: Option<usize>,
+    /// Cap on the bytes relays have read but not yet written out, summed over all connections.
+    /// Once the total reaches it, relays stop reading until writes bring it back under, so a
+    /// burst of fast senders towards slow receivers can't grow memory without bound. Each
+    /// relay direction may overshoot the cap by at most one `relay_chunk_size` chunk.
+    pub max_buffered_bytes: Option<u64>,
+    /// Most bytes read from one side of a relay before they are written to the other, i.e. the
+    /// size of each relay buffer. Reads never wait for a chunk to fill up, but smaller chunks
+    /// pass large bursts on in smaller pieces, so the other side starts receiving sooner. Larger
+    /// chunks move bulk transfers with fewer syscalls, at the cost of more memory per
+    /// connection (two buffers each). Must not be zero.
+    pub relay_chunk_size: usize,
+    /// Whether relayed data is written out as it is read or line by line.
+    pub relay_mode: RelayMode,
+    /// Remember destinations whose connect was refused or timed out for this long, and fail
+    /// further requests to them right away with the same error instead of retrying the connect.
+    /// Keep it short, since a destination that comes back up stays unreachable until it expires.
+    pub connect_failure_cache_ttl: Option<Duration>,
+    /// Size to request for the send buffer (`SO_SNDBUF`) of both client and outbound sockets,
+    /// for tuning throughput on links with a high bandwidth-delay product. The kernel treats it
+    /// as a hint: Linux doubles the value and clamps it to `net.core.wmem_max`, and other
+    /// platforms round or cap it their own way. The OS default is used when unset.
+    pub socket_send_buffer: Option<usize>,
+    /// Size to request for the receive buffer (`SO_RCVBUF`) of both client and outbound
+    /// sockets, with the same caveats as `socket_send_buffer` (clamped to `net.core.rmem_max`
+    /// on Linux).
+    pub socket_recv_buffer: Option<usize>,
+    /// Enable TCP keepalive on outbound connections, so that a destination that silently went
+    /// away (e.g. behind a NAT or firewall that dropped the mapping) is detected after about
+    /// `idle + interval * retries` and the connection fails instead of hanging forever. The
+    /// interval and retry count are only applied on Linux, Android, the BSDs, macOS, iOS and
+    /// Windows; elsewhere the system defaults are used for them.
+    pub outbound_keepalive: Option<KeepaliveSettings>,
+    /// Resolver for domain name destinations. Defaults to the operating system's.
+    pub resolver: Arc<dyn Resolver>,
+    /// How long resolving a domain name destination may take before the request fails with a
+    /// TTL expired reply. Kept apart from connecting so that a hung DNS server fails fast
+    /// instead of eating into the time allowed for the connection itself.
+    pub resolve_timeout: Duration,
+    /// How many of the addresses a domain name resolves to are tried, in the order the
+    /// resolver returned them. The rest are ignored, so a hostile DNS server can't make a
+    /// single request fan out into an arbitrary number of connect attempts.
+    pub max_resolved_addrs: usize,
+    /// Maximum number of domain names being resolved at once, across all connections, so a
+    /// flood of domain name requests can't overwhelm the resolver. Requests over the limit
+    /// wait for a lookup to finish, and the wait counts against `resolve_timeout`.
+    pub max_concurrent_resolutions: Option<usize>,
+    /// Local addresses to spread outbound connections across, picked per connection according
+    /// to `outbound_bind_strategy`, e.g. to stay under per-IP rate limits at destinations. Only
+    /// addresses of the destination's family are used; the OS picks the address when there is
+    /// none. The chosen address is the one advertised in the success reply.
+    pub outbound_bind_pool: Vec<IpAddr>,
+    pub outbound_bind_strategy: OutboundBindStrategy,
+    /// How long to wait before answering a failed username/password authentication, so that
+    /// failures take about the same time whether or not the username exists. Successful
+    /// authentications are answered right away.
+    pub auth_failure_delay: Duration,
+    /// How long a client that picked username/password authentication may take to send its
+    /// credentials before the connection is closed. Kept apart from the other handshake steps
+    /// since typing a password in can legitimately take a while.
+    pub auth_timeout: Duration,
+    /// Checked in order when username/password credentials aren't found in
+    /// `AuthParams::logins`, until one of them accepts. Useful to move logins from one store
+    /// to another without downtime, by keeping both around for a while.
+    pub authenticators: Vec<Arc<dyn Authenticator>>,
+    /// Opens outbound connections instead of the server. When set, it takes over connecting
+    /// completely, so `outbound_source_port_range`, `outbound_bind_pool`, `connect_retries`,
+    /// `outbound_keepalive` and the socket buffer sizes don't apply to outbound sockets.
+    pub connector: Option<Arc<dyn Connector>>,
+    /// Originate TLS to the destinations it lists, for CONNECT requests. The handshake takes
+    /// place before the success reply, so a destination that fails it is answered with a
+    /// general server failure reply. TLS 1.3 destinations send session tickets right after
+    /// the handshake, which makes them count as speaking first for `initial_peek_bytes`.
+    #[cfg(feature = "tls")]
+    pub outbound_tls: Option<OutboundTls>,
+    /// Version byte sent in request replies. This is deliberately non-standard: it only exists
+    /// to work around broken clients that expect something other than the SOCKS5 version (5)
+    /// there, such as 0 in some SOCKS4-compatible modes. Leave it at the default otherwise.
+    pub reply_version: u8,
+    /// Rules restricting which destinations clients may connect to. Requests for a destination
+    /// the rules deny are answered with a connection not allowed reply.
+    pub access_control: Option<AccessControl>,
+    /// How destinations appear in log lines about a connection, for deployments where full
+    /// destinations would reveal too much about what clients are browsing.
+    pub log_destination: DestinationLogMode,
+    /// Domain name that, when requested as a CONNECT destination (on any port), is answered
+    /// with a success reply and then closed, without connecting anywhere. Lets monitoring check
+    /// the whole handshake, authentication included, e.g. with `ping.socks.local`.
+    pub health_check_domain: Option<String>,
+    /// Connect to the unspecified address (`0.0.0.0`, `::` or `::ffff:0.0.0.0`) when a client
+    /// asks for it, directly or through a domain name resolving to it. What that connects to
+    /// depends on the OS, so such requests are answered with a connection not allowed reply
+    /// unless this is set.
+    pub allow_unspecified_destinations: bool,
+    /// Receives every metric event as it happens, for push-based monitoring. The counters
+    /// returned by `SocksServer::metrics` are kept either way.
+    pub metrics_sink: Option<Arc<dyn MetricsSink>>,
+    /// How long established relays may keep running once the server starts shutting down.
+    /// Relays still running after that are closed in two steps: both peers are sent a FIN to
+    /// let them wrap up, and the connections are dropped once both peers have closed their
+    /// side or `close_grace` has passed, whichever comes first. With `None`, shutdown waits
+    /// for relays to finish on their own.
+    pub shutdown_grace: Option<Duration>,
+    /// How long closing a relay may wait on a peer, both for the FIN to be sent and, when the
+    /// server shuts down, for the peer to close its side in turn.
+    pub close_grace: Duration,
+    /// Act as a transparent proxy for connections redirected to the server by the firewall
+    /// (e.g. with iptables' `REDIRECT` target): there is no SOCKS handshake, and each
+    /// connection is relayed to the destination it was originally headed to, as reported by
+    /// `SO_ORIGINAL_DST`. Only supported on Linux; elsewhere every connection is closed with
+    /// an error.
+    pub transparent: bool,
+    /// Asked every `accept_gate_interval` whether to keep accepting connections. While it
+    /// says no, listeners leave new connections waiting in the OS backlog, and they're picked
+    /// up once it says yes again.
+    pub accept_gate: Option<Arc<dyn AcceptGate>>,
+    pub accept_gate_interval: Duration,
+    /// Print a `StartupReport` for every listener once it's bound, as a single line of
+    /// `key=value` pairs that log tooling can pick up.
+    pub log_startup_report: bool,
+    /// Carry out BIND requests, used by protocols such as active-mode FTP where the
+    /// destination connects back to the client. The server listens on a fresh port of the
+    /// address the client reached it on, sends the client that address in a first reply and
+    /// the address of the peer that connected in a second one, then relays between the two.
+    /// Without it, BIND requests are answered with a command not supported reply.
+    pub allow_bind: bool,
+    /// How long a BIND listener waits for its peer before the request fails with a TTL
+    /// expired reply.
+    pub bind_accept_timeout: Duration,
+    /// Relay the first connection a BIND listener accepts, whoever it's from. Otherwise only
+    /// the host named as the request's destination may connect, unless the client left it
+    /// unspecified (`0.0.0.0` or `::`); connections from any other host fail the request with a
+    /// connection not allowed reply. Only the peer's address is checked, not its port, since
+    /// clients such as FTP ones name the port of their control connection there.
+    pub bind_allow_any_peer: bool,
+    /// Maximum number of BIND requests waiting for their peer at once, across all clients,
+    /// so clients can't tie up the server's ports with listeners. Requests over the limit are
+    /// answered with a general server failure reply.
+    pub max_pending_binds: Option<usize>,
+    /// Carry out UDP ASSOCIATE requests, for clients relaying DNS, QUIC or other UDP traffic.
+    /// The server binds a UDP socket on the address the client reached it on and sends its
+    /// address in the reply. Datagrams the client sends there are forwarded to the destination
+    /// named in their header, and datagrams coming back are passed on to the client with a
+    /// header naming where they came from. Only datagrams from the client's IP are accepted.
+    /// The association lasts until the client closes the connection it was requested over.
+    /// Without it, UDP ASSOCIATE requests are answered with a command not supported reply.
+    pub allow_udp_associate: bool,
+    /// Address to advertise in UDP ASSOCIATE replies instead of the one the relay socket is
+    /// bound to, for servers behind NAT whose local address clients can't reach. The socket
+    /// still binds locally. Only the IP is overridden: the advertised port is always the one
+    /// the socket is bound to, which the OS picks anew for every association, so port
+    /// forwarding must map the ephemeral port range through to the same port numbers.
+    pub udp_public_addr: Option<IpAddr>,
+    /// Maximum number of UDP associations open at once, across all clients, so clients can't
+    /// tie up the server's ports with relay sockets. Requests over the limit are answered with
+    /// a general server failure reply.
+    pub max_pending_associations: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            expect_proxy_protocol: false,
+            proxy_header_timeout: Duration::from_secs(5),
+            match_client_address_family: false,
+            log_timings: false,
+            accept_log_sample_rate: 1,
+            outbound_source_port_range: None,
+            max_connections_per_ip: None,
+            max_connections: None,
+            max_handshakes_in_progress: None,
+            max_connections_per_destination: None,
+            queue_wait_timeout: Duration::from_secs(10),
+            handshake_capture: None,
+            log_control_packets: false,
+            log_malformed_hellos: true,
+            max_bytes_per_connection: None,
+            user_byte_quota: None,
+            http_misconnect_response: false,
+            first_byte_timeout: None,
+            idle_policy: None,
+            initial_peek_bytes: 0,
+            inspector: None,
+            check_tls_server_name: false,
+            min_transfer_rate: None,
+            write_stall_timeout: None,
+            connect_retries: 0,
+            connect_retry_delay: Duration::ZERO,
+            destination_liveness_check: None,
+            slow_connect_threshold: None,
+            connect_attempt_delay: None,
+            relay_buffer_pool: None,
+            max_buffered_bytes: None,
+            relay_chunk_size: DEFAULT_RELAY_CHUNK_SIZE,
+            relay_mode: RelayMode::default(),
+            connect_failure_cache_ttl: None,
+            socket_send_buffer: None,
+            socket_recv_buffer: None,
+            outbound_keepalive: None,
+            resolver: Arc::new(SystemResolver),
+            resolve_timeout: Duration::from_secs(5),
+            max_resolved_addrs: 8,
+            max_concurrent_resolutions: None,
+            outbound_bind_pool: Vec::new(),
+            outbound_bind_strategy: OutboundBindStrategy::default(),
+            auth_failure_delay: Duration::ZERO,
+            auth_timeout: Duration::from_secs(30),
+            authenticators: Vec::new(),
+            connector: None,
+            #[cfg(feature = "tls")]
+            outbound_tls: None,
+            reply_version: packets::SOCKS_VERSION,
+            access_control: None,
+            log_destination: DestinationLogMode::default(),
+            health_check_domain: None,
+            allow_unspecified_destinations: false,
+            metrics_sink: None,
+            shutdown_grace: None,
+            close_grace: Duration::from_secs(5),
+            transparent: false,
+            accept_gate: None,
+            accept_gate_interval: Duration::from_secs(1),
+            log_startup_report: false,
+            allow_bind: false,
+            bind_accept_timeout: Duration::from_secs(60),
+            bind_allow_any_peer: false,
+            max_pending_binds: None,
+            allow_udp_associate: false,
+            udp_public_addr: None,
+            max_pending_associations: None,
+        }
+    }
+}
+
 pub struct SocksServer {
+    // Configuration new connections are served under, replaced by `reload`.
+    state: watch::Sender<Arc<ServerState>>,
+}
+
+// Configuration connections are served under, along with everything they share. Connections
+// keep the one they were accepted under for their whole lifetime, even across reloads.
+struct ServerState {
     auth_settings: AuthSettings,
+    config: Config,
+    shared: Arc<SharedState>,
+}
+
+impl Deref for ServerState {
+    type Target = SharedState;
+
+    fn deref(&self) -> &SharedState {
+        &self.shared
+    }
+}
+
+// Everything connections of a server share, whatever configuration they were accepted under.
+struct SharedState {
+    connections_per_ip: Arc<ConnectionCounter<IpAddr>>,
+    connections_per_destination: Arc<ConnectionCounter<SocketAddr>>,
+    // One permit per connection allowed to be served at once, when the total is limited.
+    connection_slots: Option<Arc<Semaphore>>,
+    // One permit per connection allowed to be negotiating at once, when that is limited.
+    handshake_slots: Option<Arc<Semaphore>>,
+    // One permit per domain name allowed to be resolved at once, when that is limited.
+    resolution_slots: Option<Semaphore>,
+    // One permit per BIND request allowed to wait for its peer at once, when that is limited.
+    bind_slots: Option<Semaphore>,
+    // One permit per UDP association allowed to be open at once, when that is limited.
+    association_slots: Option<Arc<Semaphore>>,
+    metrics: Arc<MetricsRegistry>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    buffer_budget: Arc<BufferBudget>,
+    user_usage: Arc<UserUsage>,
+    connect_failures: Option<ConnectFailureCache>,
+    outbound_bind_pool: Option<OutboundBindPool>,
+    next_connection_id: AtomicU64,
+    // Connections accepted so far, for sampling the accept log.
+    accepted_connections: AtomicU64,
+    events: broadcast::Sender<ConnectionEvent>,
+    // Flips to `true` once, when the server starts shutting down.
+    shutdown: watch::Sender<bool>,
+    // Addresses of every listener currently accepting connections for this server.
+    listen_addrs: Mutex<Vec<SocketAddr>>,
+    // Every relay currently running, by connection ID.
+    relays: Mutex<HashMap<u64, RelayControl>>,
+}
+
+// Handles on a running relay for watching and steering it from outside the connection.
+struct RelayControl {
+    progress: watch::Receiver<RelayProgress>,
+    // Whether the relay is paused, see `SocksServer::pause_connection`.
+    paused: watch::Sender<bool>,
+}
+
+// Events not yet received by the slowest subscriber before it starts lagging.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+impl SharedState {
+    // Sending only fails when nobody is subscribed, in which case the event is simply dropped.
+    fn publish(&self, event: ConnectionEvent) {
+        let _ = self.events.send(event);
+    }
+
+    // Counts an accepted connection, returning whether it is the one in `sample_rate` to log.
+    fn sample_accepted_connection(&self, sample_rate: u32) -> bool {
+        let accepted = self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+        accepted.is_multiple_of(sample_rate.max(1) as u64)
+    }
+
+    // Whether connecting to `addr` would loop back into one of the server's own listeners.
+    // A listener on an unspecified address accepts on every local address, of which only the
+    // loopback ones and the address the client reached the server on are known here.
+    fn is_own_listen_addr(&self, addr: SocketAddr, client_local_addr: SocketAddr) -> bool {
+        let ip = addr.ip().to_canonical();
+        self.listen_addrs.lock().unwrap().iter().any(|listen_addr| {
+            let listen_ip = listen_addr.ip().to_canonical();
+            listen_addr.port() == addr.port()
+                && (ip == listen_ip
+                    || (listen_ip.is_unspecified()
+                        && (ip.is_loopback()
+                            || ip.is_unspecified()
+                            || ip == client_local_addr.ip().to_canonical())))
+        })
+    }
+}
+
+impl Config {
+    /// Checks for settings that can't work, or that contradict each other.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let limits = [
+            ("max_connections", self.max_connections),
+            ("max_connections_per_ip", self.max_connections_per_ip),
+            (
+                "max_handshakes_in_progress",
+                self.max_handshakes_in_progress,
+            ),
+            (
+                "max_connections_per_destination",
+                self.max_connections_per_destination,
+            ),
+            (
+                "max_concurrent_resolutions",
+                self.max_concurrent_resolutions,
+            ),
+            ("max_pending_binds", self.max_pending_binds),
+            ("max_pending_associations", self.max_pending_associations),
+        ];
+        for (name, limit) in limits {
+            if limit == Some(0) {
+                return Err(ConfigError::ZeroLimit(name));
+            }
+        }
+        if self.max_buffered_bytes == Some(0) {
+            return Err(ConfigError::ZeroLimit("max_buffered_bytes"));
+        }
+        if let (Some(per_ip), Some(total)) = (self.max_connections_per_ip, self.max_connections) {
+            if per_ip > total {
+                return Err(ConfigError::PerIpLimitAboveTotal { per_ip, total });
+            }
+        }
+
+        if self.relay_chunk_size == 0 {
+            return Err(ConfigError::ZeroRelayChunkSize);
+        }
+        if self.accept_gate_interval.is_zero() {
+            return Err(ConfigError::ZeroAcceptGateInterval);
+        }
+        if self.check_tls_server_name {
+            let requires = if self.access_control.is_none() {
+                Some("access_control")
+            } else if self.initial_peek_bytes == 0 {
+                Some("initial_peek_bytes")
+            } else {
+                None
+            };
+            if let Some(requires) = requires {
+                return Err(ConfigError::MissingPrerequisite {
+                    setting: "check_tls_server_name",
+                    requires,
+                });
+            }
+        }
+        if self.max_resolved_addrs == 0 {
+            return Err(ConfigError::ZeroResolvedAddrs);
+        }
+        if let Some(range) = &self.outbound_source_port_range {
+            if range.is_empty() {
+                return Err(ConfigError::EmptySourcePortRange(range.clone()));
+            }
+        }
+
+        if self.connector.is_some() {
+            if self.outbound_source_port_range.is_some() {
+                return Err(ConfigError::IgnoredByConnector(
+                    "outbound_source_port_range",
+                ));
+            }
+            if !self.outbound_bind_pool.is_empty() {
+                return Err(ConfigError::IgnoredByConnector("outbound_bind_pool"));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl SocksServer {
     pub fn new(auth_settings: AuthSettings) -> Self {
-        SocksServer { auth_settings }
+        SocksServer::from_parts(auth_settings, Config::default())
+    }
+
+    /// Creates a server with the given settings, failing if `Config::validate` finds a problem
+    /// with them.
+    pub fn with_config(auth_settings: AuthSettings, config: Config) -> Result<Self, ConfigError> {
+        config.validate()?;
+
+        Ok(SocksServer::from_parts(auth_settings, config))
+    }
+
+    fn from_parts(auth_settings: AuthSettings, config: Config) -> Self {
+        let shared = Arc::new(SharedState {
+            buffer_pool: config
+                .relay_buffer_pool
+                .map(|max_pooled| Arc::new(BufferPool::new(config.relay_chunk_size, max_pooled))),
+            buffer_budget: Arc::new(BufferBudget::new(config.max_buffered_bytes)),
+            user_usage: Arc::new(UserUsage::new()),
+            connect_failures: config
+                .connect_failure_cache_ttl
+                .map(ConnectFailureCache::new),
+            outbound_bind_pool: (!config.outbound_bind_pool.is_empty()).then(|| {
+                OutboundBindPool::new(
+                    config.outbound_bind_pool.clone(),
+                    config.outbound_bind_strategy,
+                )
+            }),
+            connection_slots: config
+                .max_connections
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            resolution_slots: config.max_concurrent_resolutions.map(Semaphore::new),
+            bind_slots: config.max_pending_binds.map(Semaphore::new),
+            association_slots: config
+                .max_pending_associations
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            handshake_slots: config
+                .max_handshakes_in_progress
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            metrics: Arc::new(MetricsRegistry::new(config.metrics_sink.clone())),
+            connections_per_ip: Arc::new(ConnectionCounter::new()),
+            connections_per_destination: Arc::new(ConnectionCounter::new()),
+            next_connection_id: AtomicU64::new(0),
+            accepted_connections: AtomicU64::new(0),
+            events: broadcast::channel(EVENT_CHANNEL_CAPACITY).0,
+            shutdown: watch::channel(false).0,
+            listen_addrs: Mutex::new(Vec::new()),
+            relays: Mutex::new(HashMap::new()),
+        });
+
+        SocksServer {
+            state: watch::channel(Arc::new(ServerState {
+                auth_settings,
+                config,
+                shared,
+            }))
+            .0,
+        }
+    }
+
+    /// Replaces the auth settings and configuration for connections accepted from now on,
+    /// failing if `Config::validate` finds a problem with the new configuration. Open
+    /// connections carry on under the settings they were accepted with. Settings sizing
+    /// resources shared by all connections keep the value the server was created with:
+    /// `max_connections`, `max_handshakes_in_progress`, `max_concurrent_resolutions`,
+    /// `max_pending_binds`, `max_pending_associations`, `relay_buffer_pool`,
+    /// `max_buffered_bytes`, `connect_failure_cache_ttl`, `outbound_bind_pool`,
+    /// `outbound_bind_strategy` and `metrics_sink`.
+    pub fn reload(&self, auth_settings: AuthSettings, config: Config) -> Result<(), ConfigError> {
+        config.validate()?;
+
+        let shared = Arc::clone(&self.state.borrow().shared);
+        self.state.send_replace(Arc::new(ServerState {
+            auth_settings,
+            config,
+            shared,
+        }));
+
+        Ok(())
+    }
+
+    /// Report on how a listener bound to `listen_addr` runs under the current settings, the
+    /// same as printed with `Config::log_startup_report`, e.g. from `listen_with_ready`'s
+    /// `on_ready`.
+    pub fn startup_report(&self, listen_addr: SocketAddr) -> StartupReport {
+        let state = self.state();
+        StartupReport::new(listen_addr, &state.auth_settings, &state.config)
+    }
+
+    /// Auth methods the server accepts from clients, most preferred first, as currently
+    /// configured (reloads included). Only one method can be configured for now.
+    pub fn accepted_auth_methods(&self) -> Vec<AuthMethod> {
+        vec![self.state().auth_settings.method]
+    }
+
+    fn state(&self) -> Arc<ServerState> {
+        Arc::clone(&self.state.borrow())
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        let state = self.state();
+        let mut metrics = state.metrics.snapshot();
+        if let Some(pool) = &state.buffer_pool {
+            metrics.relay_buffers_high_water_mark = pool.high_water_mark() as u64;
+        }
+        metrics.buffered_bytes = state.buffer_budget.buffered();
+        metrics.buffered_bytes_high_water_mark = state.buffer_budget.high_water_mark();
+
+        metrics
+    }
+
+    /// Current metrics in the OpenMetrics text format, ready to be served to a scraper.
+    pub fn metrics_openmetrics(&self) -> String {
+        self.metrics().to_openmetrics()
+    }
+
+    /// Subscribes to the lifecycle events of every connection accepted from now on. Events
+    /// are published without waiting on subscribers: one that falls more than 1024 events
+    /// behind loses the oldest ones, and its next `recv` returns `RecvError::Lagged` with the
+    /// number of events skipped before resuming with the oldest event still retained.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.state().events.subscribe()
     }
 
+    /// Watches how many bytes the connection with the given ID (as in `ConnectionEvent`) has
+    /// relayed so far. The value is updated every 100ms or so while data flows, and one last
+    /// time when the relay ends. Returns `None` unless the connection is currently relaying.
+    pub fn relay_progress(&self, id: u64) -> Option<watch::Receiver<RelayProgress>> {
+        self.state()
+            .relays
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|relay| relay.progress.clone())
+    }
+
+    /// Stops relaying data for the connection with the given ID (as in `ConnectionEvent`)
+    /// without closing it, until `resume_connection` is called. Each direction finishes
+    /// writing the chunk it's busy with, then stops reading, so TCP backpressure slows both
+    /// peers down once their buffers fill up. Timeouts keep running while paused: an
+    /// `idle_policy` that closes idle relays also closes relays paused for longer than its
+    /// timeout. Returns `false` unless the connection is currently relaying.
+    pub fn pause_connection(&self, id: u64) -> bool {
+        self.set_paused(id, true)
+    }
+
+    /// Lets a connection paused with `pause_connection` relay data again. Returns `false`
+    /// unless the connection is currently relaying.
+    pub fn resume_connection(&self, id: u64) -> bool {
+        self.set_paused(id, false)
+    }
+
+    fn set_paused(&self, id: u64, paused: bool) -> bool {
+        match self.state().relays.lock().unwrap().get(&id) {
+            Some(relay) => {
+                relay.paused.send_replace(paused);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Starts a graceful shutdown: `listen` stops accepting connections and returns once every
+    /// connection it accepted has closed. Connections still negotiating are turned down with
+    /// whatever the client is waiting for (no acceptable method before the server hello, a
+    /// failed authentication during username/password auth and a general server failure reply
+    /// once it sent its request) and closed, while established relays run to completion or
+    /// until `Config::shutdown_grace` runs out.
+    /// Call it from a signal handler (e.g. on SIGTERM) to drain the server before exiting.
+    pub fn shutdown(&self) {
+        self.state().shutdown.send_replace(true);
+    }
+
+    /// Binds `ip:port` and accepts connections until the server shuts down. Fails with an
+    /// `InvalidInput` error if `ip` isn't a valid IP address.
     pub async fn listen(&self, ip: &str, port: u16) -> Result<(), io::Error> {
-        let parsed_ip = ip
-            .parse::<IpAddr>()
-            .unwrap_or_else(|_| panic!("`{ip}` is not a valid IP address"));
+        self.listen_with_ready(ip, port, |_| {}).await
+    }
+
+    /// Like `listen`, but calls `on_ready` with the address actually bound (with the real port
+    /// when listening on port 0) once the listener is up, right before accepting the first
+    /// connection, e.g. to register the server with service discovery. `on_ready` isn't called
+    /// if binding fails.
+    pub async fn listen_with_ready<F>(&self, ip: &str, port: u16, on_ready: F) -> io::Result<()>
+    where
+        F: FnOnce(SocketAddr),
+    {
+        let parsed_ip = ip.parse::<IpAddr>().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("`{ip}` is not a valid IP address"),
+            )
+        })?;
         let bound_addr = SocketAddr::from((parsed_ip, port));
         let listener = TcpListener::bind(bound_addr).await?;
+        let listen_addr = listener.local_addr()?;
+        on_ready(listen_addr);
+        self.serve(listener, listen_addr).await;
+
+        Ok(())
+    }
 
-        println!("Server listening on port: {}", port);
+    /// Binds `addr` and runs the accept loop in a background task, returning a handle to
+    /// control and observe the running server.
+    pub async fn spawn(self, addr: SocketAddr) -> io::Result<ServerHandle> {
+        let listener = TcpListener::bind(addr).await?;
+        let listen_addr = listener.local_addr()?;
 
+        Ok(ServerHandle::spawn(self, listener, listen_addr))
+    }
+
+    /// Serves a connection accepted outside of the server's own listeners, e.g. by an
+    /// embedder's accept loop, and returns how it went once it closes. Every setting applies
+    /// as for connections the server accepts itself. Returns `None` if the connection was
+    /// dropped before it was assigned an ID, i.e. when too many handshakes were in progress, its
+    /// PROXY header was invalid, its client IP was at `max_connections_per_ip` or no connection
+    /// slot freed up in time.
+    pub async fn handle_connection(
+        &self,
+        client_conn: TcpStream,
+        client_addr: SocketAddr,
+    ) -> Option<ConnectionSummary> {
+        let state = self.state();
+        state.metrics.connection_opened();
+        let summary = handle_connection(client_conn, client_addr, &state).await;
+        state.metrics.connection_closed();
+
+        summary
+    }
+
+    async fn serve(&self, listener: TcpListener, listen_addr: SocketAddr) {
+        let mut reloads = self.state.subscribe();
+        let mut state = Arc::clone(&reloads.borrow_and_update());
+        state.listen_addrs.lock().unwrap().push(listen_addr);
+
+        println!("Server listening on port: {}", listen_addr.port());
+        if state.config.log_startup_report {
+            let report = StartupReport::new(listen_addr, &state.auth_settings, &state.config);
+            println!("Startup report: {}", report);
+        }
+
+        let mut shutdown = state.shutdown.subscribe();
+        let mut connections = JoinSet::new();
+        let mut gate_checks = accept_gate_checks(&state.config);
+        let mut accepting = true;
         loop {
-            let (client_conn, client_addr) = match listener.accept().await {
+            let accepted = tokio::select! {
+                accepted = listener.accept(), if accepting => accepted,
+                _ = gate_checks.tick(), if state.config.accept_gate.is_some() => {
+                    let should_accept = state
+                        .config
+                        .accept_gate
+                        .as_ref()
+                        .is_none_or(|gate| gate.should_accept());
+                    if should_accept != accepting {
+                        accepting = should_accept;
+                        state.metrics.set_accepting_paused(!accepting);
+                        if accepting {
+                            println!("Resumed accepting connections on port {}", listen_addr.port());
+                        } else {
+                            println!("Paused accepting connections on port {}", listen_addr.port());
+                        }
+                    }
+                    continue;
+                }
+                // Reap finished connections so the set only holds the ones still open.
+                Some(_) = connections.join_next() => continue,
+                Ok(()) = reloads.changed() => {
+                    state = Arc::clone(&reloads.borrow_and_update());
+                    gate_checks = accept_gate_checks(&state.config);
+                    if state.config.accept_gate.is_none() && !accepting {
+                        accepting = true;
+                        state.metrics.set_accepting_paused(false);
+                        println!("Resumed accepting connections on port {}", listen_addr.port());
+                    }
+                    println!("Reloaded configuration for port {}", listen_addr.port());
+                    continue;
+                }
+                _ = shutdown.wait_for(|&shutting_down| shutting_down) => break,
+            };
+            let (client_conn, client_addr) = match accepted {
                 Ok(result) => result,
                 Err(e) => {
                     eprintln!("Error while attempting to accept client connection: {}", e);
@@ -59,16 +945,39 @@ impl SocksServer {
                 }
             };
 
-            println!("Accepted connection from {}", client_addr);
+            if state.sample_accepted_connection(state.config.accept_log_sample_rate) {
+                println!("Accepted connection from {}", client_addr);
+            }
 
-            let auth_settings = self.auth_settings.clone();
-            task::spawn(async {
-                handle_connection(client_conn, auth_settings).await;
+            let state = Arc::clone(&state);
+            state.metrics.connection_opened();
+            connections.spawn(async move {
+                handle_connection(client_conn, client_addr, &state).await;
+                state.metrics.connection_closed();
             });
         }
+
+        drop(listener);
+        state
+            .listen_addrs
+            .lock()
+            .unwrap()
+            .retain(|addr| *addr != listen_addr);
+        println!(
+            "Shutting down, waiting for {} open connection(s) to close",
+            connections.len()
+        );
+        while connections.join_next().await.is_some() {}
     }
 }
 
+fn accept_gate_checks(config: &Config) -> time::Interval {
+    let mut checks = time::interval(config.accept_gate_interval);
+    checks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    checks
+}
+
 impl Default for SocksServer {
     fn default() -> Self {
         SocksServer::new(AuthSettings {
@@ -78,64 +987,248 @@ impl Default for SocksServer {
     }
 }
 
-async fn read_client_hello(stream: &mut TcpStream) -> Result<ClientHello, ClientHelloError> {
-    let mut raw_packet = [0; 512];
-    let n = stream.read(&mut raw_packet).await?;
+async fn read_proxy_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<ProxyHeader, ProxyHeaderError> {
+    // Both versions start with at least this many bytes, so this never reads past the header,
+    // and it's enough to tell a connection without a header apart right away.
+    let mut raw_packet = vec![0; PROXY_V1_PREFIX.len()];
+    stream.read_exact(&mut raw_packet).await?;
+
+    if raw_packet == PROXY_V2_SIGNATURE[..PROXY_V1_PREFIX.len()] {
+        let mut signature_rest = [0; PROXY_V2_SIGNATURE.len() - PROXY_V1_PREFIX.len()];
+        stream.read_exact(&mut signature_rest).await?;
+        raw_packet.extend_from_slice(&signature_rest);
+        if raw_packet != PROXY_V2_SIGNATURE {
+            return Err(ProxyHeaderError::MissingHeader);
+        }
+
+        let mut fixed_part = [0; 4];
+        stream.read_exact(&mut fixed_part).await?;
+        raw_packet.extend_from_slice(&fixed_part);
+
+        let addresses_len = u16::from_be_bytes([fixed_part[2], fixed_part[3]]) as usize;
+        let mut addresses = vec![0; addresses_len];
+        stream.read_exact(&mut addresses).await?;
+        raw_packet.extend_from_slice(&addresses);
+    } else if raw_packet == PROXY_V1_PREFIX {
+        // A v1 header is a text line, so read it byte by byte to avoid consuming the client hello.
+        while !raw_packet.ends_with(b"\r\n") {
+            if raw_packet.len() >= PROXY_V1_MAX_LEN {
+                return Err(ProxyHeaderError::MalformedPacket);
+            }
+            raw_packet.push(stream.read_u8().await?);
+        }
+    } else {
+        return Err(ProxyHeaderError::MissingHeader);
+    }
 
-    let packet = ClientHello::new(&raw_packet[..n])?;
+    let packet = ProxyHeader::new(&raw_packet)?;
 
     Ok(packet)
 }
 
-async fn handle_user_pass_auth(
-    stream: &mut TcpStream,
-    auth_settings: AuthSettings,
-) -> Result<(), UserPassAuthError> {
-    let mut raw_packet = [0; 513];
-    let n = stream.read(&mut raw_packet).await?;
+const HTTP_MISCONNECT_RESPONSE: &[u8] = b"HTTP/1.1 400 Bad Request\r\n\
+Content-Type: text/plain\r\n\
+Content-Length: 72\r\n\
+Connection: close\r\n\
+\r\n\
+This is a SOCKS5 proxy. Configure your client to use it as one instead.\n";
 
-    let packet = ClientUserPassAuth::new(&raw_packet[..n])?;
-    if let Some(params) = auth_settings.params {
-        if let Some(s) = params.logins.get(&packet.username) {
-            if *s == packet.password {
-                let response_packet = ServerUserPassResponse::new(true);
-                stream.write_all(&response_packet.as_bytes()).await?;
-                return Ok(());
-            }
+// Handshake packets a client may send: the hello, the user/password auth and the request.
+const MAX_HANDSHAKE_PACKETS: usize = 3;
+// The largest possible hello, user/password auth and request, back to back.
+const MAX_HANDSHAKE_BYTES: usize = (2 + 255) + (3 + 255 + 255) + (4 + 1 + 255 + 2);
+
+// Counts the handshake packets a client has sent. Each stage reads exactly one framed packet
+// and leaves whatever follows for the next stage, so clients that pipeline the handshake are
+// served, and only one that sends more than the protocol allows is cut off.
+#[derive(Debug, Default)]
+struct HandshakeBudget {
+    packets: usize,
+    bytes: usize,
+}
+
+impl HandshakeBudget {
+    fn spend(&mut self, packet_len: usize) -> Result<(), HandshakeBudgetError> {
+        self.packets += 1;
+        self.bytes += packet_len;
+        if self.packets > MAX_HANDSHAKE_PACKETS {
+            return Err(HandshakeBudgetError::TooManyPackets(MAX_HANDSHAKE_PACKETS));
+        }
+        if self.bytes > MAX_HANDSHAKE_BYTES {
+            return Err(HandshakeBudgetError::TooManyBytes(MAX_HANDSHAKE_BYTES));
+        }
+
+        Ok(())
+    }
+}
+
+// Reads the hello as framed by its NMETHODS field, however the client's segments split it up.
+async fn read_client_hello<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    budget: &mut HandshakeBudget,
+) -> Result<ClientHello, ClientHelloError> {
+    let mut raw_packet = vec![0; 2];
+    stream.read_exact(&mut raw_packet).await?;
+    if raw_packet[0] == packets::SOCKS_VERSION {
+        let n_methods = raw_packet[1] as usize;
+        raw_packet.resize(2 + n_methods, 0);
+        stream.read_exact(&mut raw_packet[2..]).await?;
+    } else {
+        // Just far enough to tell which protocol a misconfigured client speaks, without
+        // waiting on bytes it may never send.
+        while is_partial_http_method(&raw_packet) {
+            raw_packet.push(stream.read_u8().await?);
         }
     }
 
+    let packet = ClientHello::new(&raw_packet)?;
+    budget.spend(packet.len)?;
+
+    Ok(packet)
+}
+
+// Reads a user/password auth packet as framed by its ULEN and PLEN fields.
+async fn read_user_pass_auth<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut raw_packet = vec![0; 2];
+    stream.read_exact(&mut raw_packet).await?;
+    if raw_packet[0] == packets::USER_PASSWORD_AUTH_VERSION {
+        // The username, then the PLEN byte.
+        let username_len = raw_packet[1] as usize;
+        raw_packet.resize(2 + username_len + 1, 0);
+        stream.read_exact(&mut raw_packet[2..]).await?;
+
+        let password_len = raw_packet[2 + username_len] as usize;
+        let start = raw_packet.len();
+        raw_packet.resize(start + password_len, 0);
+        stream.read_exact(&mut raw_packet[start..]).await?;
+    }
+
+    Ok(raw_packet)
+}
+
+async fn handle_user_pass_auth<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    client_addr: SocketAddr,
+    auth_settings: &AuthSettings,
+    config: &Config,
+    metrics: &MetricsRegistry,
+    transcript: &mut HandshakeTranscript,
+    budget: &mut HandshakeBudget,
+) -> Result<String, UserPassAuthError> {
+    let raw_packet = time::timeout(config.auth_timeout, read_user_pass_auth(stream))
+        .await
+        .map_err(|_| UserPassAuthError::Timeout(config.auth_timeout))??;
+
+    let packet = ClientUserPassAuth::new(&raw_packet)?;
+    budget.spend(packet.len)?;
+    let accepted = check_credentials(
+        &packet.username,
+        &packet.password,
+        client_addr,
+        auth_settings,
+        config,
+    )
+    .await;
+    transcript.username = Some(packet.username.clone());
+    transcript.authenticated = Some(accepted);
+    if accepted {
+        let response_packet = ServerUserPassResponse::new(true);
+        stream.write_all(&response_packet.as_bytes()).await?;
+        return Ok(packet.username);
+    }
+
+    metrics.auth_failed();
+    time::sleep(config.auth_failure_delay).await;
     let response_packet = ServerUserPassResponse::new(false);
     stream.write_all(&response_packet.as_bytes()).await?;
 
     Err(UserPassAuthError::FailedAuth)
 }
 
-async fn send_server_hello(
-    stream: &mut TcpStream,
+// Static logins are checked first, then `Config::authenticators` in order, stopping at the
+// first one that accepts.
+async fn check_credentials(
+    username: &str,
+    password: &str,
+    client_addr: SocketAddr,
+    auth_settings: &AuthSettings,
+    config: &Config,
+) -> bool {
+    if let Some(params) = &auth_settings.params {
+        if params.logins.get(username).is_some_and(|s| s == password) {
+            return true;
+        }
+    }
+
+    for authenticator in &config.authenticators {
+        match authenticator.authenticate(username, password).await {
+            Ok(true) => return true,
+            Ok(false) => {}
+            Err(e) => eprintln!(
+                "Authenticator {:?} failed for {}, moving on to the next one: {}",
+                authenticator, client_addr, e
+            ),
+        }
+    }
+
+    false
+}
+
+async fn send_server_hello<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     client_hello: ClientHello,
-    auth_settings: AuthSettings,
-) -> Result<(), ServerHelloError> {
-    for method in client_hello.methods {
+    auth_settings: &AuthSettings,
+) -> Result<AuthMethod, ServerHelloError> {
+    for &method in &client_hello.methods {
         if method == auth_settings.method {
             let buf = ServerHello::new(auth_settings.method).as_bytes();
             stream.write_all(&buf).await?;
 
-            if auth_settings.method == AuthMethod::UserPassword {
-                handle_user_pass_auth(stream, auth_settings).await?;
-            }
-
-            return Ok(());
+            return Ok(method);
         }
     }
 
     let buf = ServerHello::new(AuthMethod::NoAcceptableMethod).as_bytes();
     stream.write_all(&buf).await?;
 
-    Err(ServerHelloError::NoAcceptableAuth)
+    // Spell out both sides, since a method mismatch is the usual reason a client can't connect.
+    let offered = client_hello
+        .methods
+        .iter()
+        .map(|method| format!("{:?}", method))
+        .chain(
+            client_hello
+                .unknown_methods
+                .iter()
+                .map(|method| format!("{:#04x}", method)),
+        )
+        .collect::<Vec<_>>()
+        .join(", ");
+    Err(ServerHelloError::NoAcceptableAuth {
+        offered,
+        accepted: format!("{:?}", auth_settings.method),
+    })
+}
+
+// Every request reply goes out through here, so that it is counted and carries the configured
+// version byte.
+async fn write_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    mut reply_packet: ServerReply,
+    state: &ServerState,
+) -> io::Result<()> {
+    reply_packet.version = state.config.reply_version;
+    state.metrics.reply_sent(reply_packet.reply);
+    stream.write_all(&reply_packet.as_bytes()).await
 }
 
-async fn handle_client_request_error(stream: &mut TcpStream, error: ClientRequestError) {
+async fn handle_client_request_error<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    error: ClientRequestError,
+    state: &ServerState,
+) -> Reply {
     use ClientRequestError::*;
 
     let reply_packet = match error {
@@ -146,10 +1239,17 @@ async fn handle_client_request_error(stream: &mut TcpStream, error: ClientReques
         _ => ServerReply::new_unsuccessful_reply(Reply::SocksServerFail),
     };
 
-    stream.write_all(&reply_packet.as_bytes()).await.unwrap();
+    let reply = reply_packet.reply;
+    let _ = write_reply(stream, reply_packet, state).await;
+
+    reply
 }
 
-async fn handle_server_reply_error(stream: &mut TcpStream, error: ServerReplyError) {
+async fn handle_server_reply_error<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    error: ServerReplyError,
+    state: &ServerState,
+) -> Reply {
     use ServerReplyError::*;
 
     let reply_packet = match error {
@@ -167,100 +1267,4940 @@ async fn handle_server_reply_error(stream: &mut TcpStream, error: ServerReplyErr
             }
             _ => ServerReply::new_unsuccessful_reply(Reply::SocksServerFail),
         },
+        SourcePortsExhausted | BindLimitReached | AssociationLimitReached => {
+            ServerReply::new_unsuccessful_reply(Reply::SocksServerFail)
+        }
+        #[cfg(feature = "tls")]
+        TlsHandshake(..) => ServerReply::new_unsuccessful_reply(Reply::SocksServerFail),
+        BindTimeout => ServerReply::new_unsuccessful_reply(Reply::TTLExpired),
+        Resolve(ResolveError::NxDomain) => {
+            ServerReply::new_unsuccessful_reply(Reply::HostUnreachable)
+        }
+        Resolve(ResolveError::Timeout) => ServerReply::new_unsuccessful_reply(Reply::TTLExpired),
+        Resolve(ResolveError::Failed(_)) => {
+            ServerReply::new_unsuccessful_reply(Reply::SocksServerFail)
+        }
+        ConnectionToSelf(_)
+        | ConnectionNotAllowed(_)
+        | BindPeerMismatch(_)
+        | DestinationLimitReached(_)
+        | UnspecifiedDestination(_)
+        | UserQuotaExceeded(_)
+        | ZeroDestinationPort => ServerReply::new_unsuccessful_reply(Reply::ConnNotAllowed),
+        DestinationHungUp(_) => ServerReply::new_unsuccessful_reply(Reply::ConnRefused),
     };
 
-    stream.write_all(&reply_packet.as_bytes()).await.unwrap();
+    let reply = reply_packet.reply;
+    let _ = write_reply(stream, reply_packet, state).await;
+
+    reply
 }
 
-async fn read_client_request(stream: &mut TcpStream) -> Result<ClientRequest, ClientRequestError> {
-    let mut raw_packet = [0; 512];
-    let n = stream.read(&mut raw_packet).await?;
+async fn read_client_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    budget: &mut HandshakeBudget,
+) -> Result<ClientRequest, ClientRequestError> {
+    let mut reader = ClientRequestReader {
+        stream,
+        raw_packet: Vec::with_capacity(4 + 256 + 2),
+    };
+
+    let address_type = reader.read_fixed_header().await?;
+    reader.read_address(address_type).await?;
+    reader.read_port().await?;
 
-    let packet = ClientRequest::new(&raw_packet[..n])?;
+    let packet = ClientRequest::new(&reader.raw_packet)?;
+    budget.spend(packet.len)?;
 
     Ok(packet)
 }
 
-async fn send_server_reply(
-    stream: &mut TcpStream,
-    client_request: ClientRequest,
-) -> Result<TcpStream, ServerReplyError> {
-    let remote_conn = match client_request.destination_addr {
-        DestinationAddress::Ipv4(v4_addr) => {
-            TcpStream::connect(format!("{}:{}", v4_addr, client_request.destination_port)).await?
-        }
-        DestinationAddress::Ipv6(v6_addr) => {
-            TcpStream::connect(format!("{}:{}", v6_addr, client_request.destination_port)).await?
-        }
-        DestinationAddress::DomainName(domain) => {
-            TcpStream::connect(format!("{}:{}", domain, client_request.destination_port)).await?
+// Reads a client request one field at a time, with each step reading exactly the bytes the
+// previous ones say are coming. Requests split across any number of segments are read whole,
+// and nothing sent after the request (such as data for the destination) is consumed.
+struct ClientRequestReader<'a, S> {
+    stream: &'a mut S,
+    raw_packet: Vec<u8>,
+}
+
+impl<S: AsyncRead + Unpin> ClientRequestReader<'_, S> {
+    async fn read_exact(&mut self, n: usize) -> io::Result<&[u8]> {
+        let start = self.raw_packet.len();
+        self.raw_packet.resize(start + n, 0);
+        self.stream
+            .read_exact(&mut self.raw_packet[start..])
+            .await?;
+
+        Ok(&self.raw_packet[start..])
+    }
+
+    // VER, CMD, RSV and ATYP. The version and address type are checked straight away, since
+    // nothing after them can be framed without a known address type.
+    async fn read_fixed_header(&mut self) -> Result<AddressType, ClientRequestError> {
+        let header = self.read_exact(4).await?;
+
+        let version = header[0];
+        if version != packets::SOCKS_VERSION {
+            return Err(ClientRequestError::UnexpectedProtocolVersion(version));
         }
+
+        AddressType::try_from(header[3]).map_err(|_| ClientRequestError::ErrUnknownAddressType)
+    }
+
+    // DST.ADDR, which for domain names starts with their length.
+    async fn read_address(&mut self, address_type: AddressType) -> io::Result<()> {
+        let address_len = match address_type {
+            AddressType::Ipv4 => 4,
+            AddressType::Ipv6 => 16,
+            AddressType::DomainName => self.read_exact(1).await?[0] as usize,
+        };
+        self.read_exact(address_len).await?;
+
+        Ok(())
+    }
+
+    // DST.PORT.
+    async fn read_port(&mut self) -> io::Result<()> {
+        self.read_exact(2).await?;
+
+        Ok(())
+    }
+}
+
+// Re-expresses `bound_addr` in the address family of `client_local_addr` when an equivalent
+// address exists. IPv6 addresses that are not IPv4-mapped have no IPv4 form and are kept as is.
+fn match_address_family(bound_addr: SocketAddr, client_local_addr: SocketAddr) -> SocketAddr {
+    let ip = match (bound_addr.ip(), client_local_addr.ip()) {
+        (IpAddr::V4(v4_addr), IpAddr::V6(_)) => IpAddr::V6(v4_addr.to_ipv6_mapped()),
+        (IpAddr::V6(v6_addr), IpAddr::V4(_)) => match v6_addr.to_ipv4_mapped() {
+            Some(v4_addr) => IpAddr::V4(v4_addr),
+            None => IpAddr::V6(v6_addr),
+        },
+        (ip, _) => ip,
     };
 
-    let local_addr = remote_conn.local_addr()?;
-    let buf = ServerReply::new_successful_reply(local_addr).as_bytes();
+    SocketAddr::new(ip, bound_addr.port())
+}
 
-    stream.write_all(&buf).await?;
+fn set_socket_buffer_sizes(socket: SockRef, config: &Config) -> io::Result<()> {
+    if let Some(size) = config.socket_send_buffer {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.socket_recv_buffer {
+        socket.set_recv_buffer_size(size)?;
+    }
 
-    Ok(remote_conn)
+    Ok(())
 }
 
-async fn handle_connection(mut client_conn: TcpStream, auth_settings: AuthSettings) {
-    let client_hello = match read_client_hello(&mut client_conn).await {
-        Ok(packet) => packet,
-        Err(e) => {
-            eprintln!("Error encountered: {}. Closing connection.", e);
-            return;
-        }
+fn set_keepalive(socket: SockRef, settings: &KeepaliveSettings) -> io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(settings.idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let keepalive = keepalive
+        .with_interval(settings.interval)
+        .with_retries(settings.retries);
+
+    socket.set_tcp_keepalive(&keepalive)
+}
+
+// Buffer sizes are set before connecting, since the TCP window scale is negotiated in the
+// handshake and a larger receive buffer set afterwards may not be fully usable.
+fn new_outbound_socket(addr: SocketAddr, config: &Config) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
     };
+    set_socket_buffer_sizes(SockRef::from(&socket), config)?;
+    if let Some(keepalive) = &config.outbound_keepalive {
+        set_keepalive(SockRef::from(&socket), keepalive)?;
+    }
 
-    if let Err(e) = send_server_hello(&mut client_conn, client_hello, auth_settings).await {
-        eprintln!("Error encountered: {}. Closing connection.", e);
-        return;
+    Ok(socket)
+}
+
+async fn connect_from_port_range(
+    addr: SocketAddr,
+    bind_ip: IpAddr,
+    port_range: RangeInclusive<u16>,
+    config: &Config,
+) -> Result<TcpStream, ServerReplyError> {
+    for port in port_range {
+        let socket = new_outbound_socket(addr, config)?;
+
+        if let Err(e) = socket.bind(SocketAddr::new(bind_ip, port)) {
+            if e.kind() == io::ErrorKind::AddrInUse {
+                continue;
+            }
+            return Err(e.into());
+        }
+
+        match socket.connect(addr).await {
+            Ok(conn) => return Ok(conn),
+            // The port is free locally but already used for a connection to this destination.
+            Err(e) if e.kind() == io::ErrorKind::AddrNotAvailable => continue,
+            Err(e) => return Err(e.into()),
+        }
     }
 
-    let client_request = match read_client_request(&mut client_conn).await {
-        Ok(packet) => packet,
-        Err(e) => {
-            eprintln!("Error encountered: {}. Closing connection.", e);
-            handle_client_request_error(&mut client_conn, e).await;
-            return;
+    Err(ServerReplyError::SourcePortsExhausted)
+}
+
+fn is_transient_connect_error(error: &ServerReplyError) -> bool {
+    match error {
+        ServerReplyError::IoError(io_err) => matches!(
+            io_err.kind(),
+            io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::Interrupted
+        ),
+        _ => false,
+    }
+}
+
+async fn connect_unranged(
+    addr: SocketAddr,
+    bind_ip: Option<IpAddr>,
+    config: &Config,
+) -> Result<TcpStream, ServerReplyError> {
+    let socket = new_outbound_socket(addr, config)?;
+    if let Some(bind_ip) = bind_ip {
+        socket.bind(SocketAddr::new(bind_ip, 0))?;
+    }
+
+    Ok(socket.connect(addr).await?)
+}
+
+async fn connect_to_addr(
+    addr: SocketAddr,
+    state: &ServerState,
+) -> Result<TcpStream, ServerReplyError> {
+    let config = &state.config;
+    let bind_ip = state
+        .outbound_bind_pool
+        .as_ref()
+        .and_then(|pool| pool.select(addr));
+
+    retry_transient_failures(config, || async move {
+        match &config.outbound_source_port_range {
+            Some(port_range) => {
+                let bind_ip = bind_ip.unwrap_or(match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                });
+                connect_from_port_range(addr, bind_ip, port_range.clone(), config).await
+            }
+            None => connect_unranged(addr, bind_ip, config).await,
         }
+    })
+    .await
+}
+
+// Runs `connect` again after a transient failure, as long as `Config::connect_retries` allows.
+async fn retry_transient_failures<T, F, Fut>(
+    config: &Config,
+    mut connect: F,
+) -> Result<T, ServerReplyError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ServerReplyError>>,
+{
+    let mut retries_left = config.connect_retries;
+    loop {
+        match connect().await {
+            Err(e) if retries_left > 0 && is_transient_connect_error(&e) => {
+                retries_left -= 1;
+                time::sleep(config.connect_retry_delay).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn connect_to_destination(
+    client_request: ClientRequest,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+) -> ConnectResult {
+    let port = client_request.destination_port;
+    // Port 0 can't be connected to, and what `connect` does with it depends on the OS. Only
+    // CONNECT goes through here: a BIND request may legitimately ask for port 0, meaning any.
+    if port == 0 {
+        return Err(ServerReplyError::ZeroDestinationPort);
+    }
+    let domain = match &client_request.destination_addr {
+        DestinationAddress::DomainName(domain) => Some(domain.clone()),
+        _ => None,
     };
-    let remote_conn = match send_server_reply(&mut client_conn, client_request).await {
-        Ok(conn) => conn,
-        Err(e) => {
-            eprintln!("Error encountered: {}. Closing connection.", e);
-            handle_server_reply_error(&mut client_conn, e).await;
-            return;
+    let addrs: Vec<SocketAddr> = match client_request.destination_addr {
+        DestinationAddress::Ipv4(v4_addr) => vec![SocketAddr::from((v4_addr, port))],
+        DestinationAddress::Ipv6(v6_addr) => vec![SocketAddr::from((v6_addr, port))],
+        DestinationAddress::DomainName(domain) => {
+            let config = &state.config;
+            let mut addrs = time::timeout(
+                config.resolve_timeout,
+                resolve_destination(&domain, port, state),
+            )
+            .await
+            .map_err(|_| ResolveError::Timeout)??;
+            addrs.truncate(config.max_resolved_addrs);
+            addrs
         }
     };
 
-    handle_packet_relay(client_conn, remote_conn).await;
+    // The name is resolved exactly once and only these addresses are connected to, so whatever
+    // is decided about an address below holds for the connection made to it, even if the name
+    // would resolve differently by now (DNS rebinding).
+    let attempt_delay = state.config.connect_attempt_delay;
+    let start_attempt = |addr| -> ConnectAttempt<'_> {
+        Box::pin(connect_to_resolved_addr(
+            addr,
+            domain.as_deref(),
+            client_local_addr,
+            state,
+        ))
+    };
+    let mut addrs = VecDeque::from(addrs);
+    let mut attempts = Vec::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match addrs.pop_front() {
+                Some(addr) => attempts.push(start_attempt(addr)),
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            result = first_finished(&mut attempts) => match result {
+                Ok(conn) => return Ok(conn),
+                Err(e) => {
+                    last_error = Some(e);
+                    if let Some(addr) = addrs.pop_front() {
+                        attempts.push(start_attempt(addr));
+                    }
+                }
+            },
+            _ = time::sleep(attempt_delay.unwrap_or_default()),
+                if attempt_delay.is_some() && !addrs.is_empty() =>
+            {
+                attempts.push(start_attempt(addrs.pop_front().unwrap()));
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+        .into()
+    }))
 }
 
-async fn relay_packets(mut src: OwnedReadHalf, mut dst: OwnedWriteHalf) {
-    loop {
-        let n = match io::copy(&mut src, &mut dst).await {
-            Ok(bytes_read) => bytes_read,
-            Err(_) => return,
-        };
+// Outbound connection along with its slot under `Config::max_connections_per_destination`.
+type ConnectResult = Result<(TcpStream, Option<ConnectionPermit<SocketAddr>>), ServerReplyError>;
 
-        if n == 0 {
-            return;
+// What a successful handshake leads to.
+enum Negotiated {
+    Relay(RemoteConn),
+    UdpAssociation(UdpAssociation),
+}
+
+// Outbound side of a relay, with TLS on top when the server originates it.
+enum RemoteConn {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::client::TlsStream<TcpStream>>),
+}
+
+impl RemoteConn {
+    // The TCP connection underneath.
+    fn tcp(&self) -> &TcpStream {
+        match self {
+            RemoteConn::Tcp(conn) => conn,
+            #[cfg(feature = "tls")]
+            RemoteConn::Tls(conn) => conn.get_ref().0,
         }
     }
 }
 
-async fn handle_packet_relay(client_conn: TcpStream, remote_conn: TcpStream) {
-    let (client_conn_rx, client_conn_tx) = client_conn.into_split();
-    let (remote_conn_rx, remote_conn_tx) = remote_conn.into_split();
+type ConnectAttempt<'a> = Pin<Box<dyn Future<Output = ConnectResult> + Send + 'a>>;
 
-    let client_to_remote =
-        task::spawn(async { relay_packets(client_conn_rx, remote_conn_tx).await });
-    let remote_to_client =
-        task::spawn(async { relay_packets(remote_conn_rx, client_conn_tx).await });
+// Waits for a resolution slot first, when their number is limited.
+async fn resolve_destination(
+    domain: &str,
+    port: u16,
+    state: &ServerState,
+) -> Result<Vec<SocketAddr>, ResolveError> {
+    let _slot = match &state.resolution_slots {
+        // Acquiring only fails once the semaphore is closed, which never happens.
+        Some(slots) => slots.acquire().await.ok(),
+        None => None,
+    };
+    let _in_flight = state.metrics.resolution_started();
 
-    client_to_remote.await.unwrap();
-    remote_to_client.await.unwrap();
+    state.config.resolver.resolve(domain, port).await
+}
+
+// Resolves to the result of whichever attempt finishes first, removing it from `attempts`.
+// Never resolves while `attempts` is empty.
+pub(crate) async fn first_finished<T>(
+    attempts: &mut Vec<Pin<Box<dyn Future<Output = T> + Send + '_>>>,
+) -> T {
+    future::poll_fn(|cx| {
+        for i in 0..attempts.len() {
+            if let Poll::Ready(result) = attempts[i].as_mut().poll(cx) {
+                drop(attempts.swap_remove(i));
+                return Poll::Ready(result);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+fn is_unspecified(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4_addr) => v4_addr.is_unspecified(),
+        IpAddr::V6(v6_addr) => {
+            v6_addr.is_unspecified()
+                || v6_addr
+                    .to_ipv4_mapped()
+                    .is_some_and(|v4_addr| v4_addr.is_unspecified())
+        }
+    }
+}
+
+// Connects to one of the addresses a request's destination stands for, unless something rules
+// it out first.
+async fn connect_to_resolved_addr(
+    addr: SocketAddr,
+    domain: Option<&str>,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+) -> ConnectResult {
+    if !state.config.allow_unspecified_destinations && is_unspecified(addr.ip()) {
+        return Err(ServerReplyError::UnspecifiedDestination(addr));
+    }
+    if state.is_own_listen_addr(addr, client_local_addr) {
+        return Err(ServerReplyError::ConnectionToSelf(addr));
+    }
+    if let Some(access_control) = &state.config.access_control {
+        if !access_control.is_allowed(domain, addr) {
+            return Err(ServerReplyError::ConnectionNotAllowed(addr));
+        }
+    }
+
+    if let Some(kind) = state.connect_failures.as_ref().and_then(|c| c.get(&addr)) {
+        return Err(io::Error::new(kind, format!("connecting to {} failed recently", addr)).into());
+    }
+
+    // Taken before connecting, so that connections over the limit never reach the
+    // destination. It is given back as soon as the connect fails.
+    let destination_permit = match state.config.max_connections_per_destination {
+        Some(limit) => match state.connections_per_destination.try_acquire(addr, limit) {
+            Some(permit) => Some(permit),
+            None => return Err(ServerReplyError::DestinationLimitReached(addr)),
+        },
+        None => None,
+    };
+
+    let result = match &state.config.connector {
+        Some(connector) => connector
+            .connect(addr)
+            .await
+            .map_err(ServerReplyError::from),
+        None => connect_to_addr(addr, state).await,
+    };
+    let result = match (result, state.config.destination_liveness_check) {
+        (Ok(conn), Some(window)) => check_destination_liveness(conn, addr, window).await,
+        // A reset sent right after accepting can beat the connect to completing, in which case
+        // it fails the connect itself.
+        (Err(ServerReplyError::IoError(e)), Some(_))
+            if e.kind() == io::ErrorKind::ConnectionReset =>
+        {
+            Err(ServerReplyError::DestinationHungUp(addr))
+        }
+        (result, _) => result,
+    };
+    match result {
+        Ok(conn) => Ok((conn, destination_permit)),
+        Err(e) => {
+            if let (Some(cache), ServerReplyError::IoError(io_err)) = (&state.connect_failures, &e)
+            {
+                if ConnectFailureCache::is_cacheable(io_err.kind()) {
+                    cache.insert(addr, io_err.kind());
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+// Fails if the destination resets or closes the connection within `window`. Data the
+// destination sends right away is only peeked at, so it is still relayed to the client.
+async fn check_destination_liveness(
+    conn: TcpStream,
+    addr: SocketAddr,
+    window: Duration,
+) -> Result<TcpStream, ServerReplyError> {
+    let mut buf = [0; 1];
+    match time::timeout(window, conn.peek(&mut buf)).await {
+        Err(_) | Ok(Ok(1..)) => Ok(conn),
+        Ok(Ok(0)) => Err(ServerReplyError::DestinationHungUp(addr)),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::ConnectionReset => {
+            Err(ServerReplyError::DestinationHungUp(addr))
+        }
+        Ok(Err(e)) => Err(e.into()),
+    }
+}
+
+// Listens for the peer of a BIND request, sending the client the listening address in a first
+// reply and the peer's address in a second one once it connected. Failures before or after the
+// first reply are answered the same way, with a single failure reply. Returns the connection
+// from the peer along with its address.
+async fn accept_bound_connection<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    client_request: ClientRequest,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+) -> Result<(TcpStream, SocketAddr), ServerReplyError> {
+    let config = &state.config;
+    let _slot = match &state.bind_slots {
+        Some(slots) => Some(
+            slots
+                .try_acquire()
+                .map_err(|_| ServerReplyError::BindLimitReached)?,
+        ),
+        None => None,
+    };
+    let _pending = state.metrics.bind_started();
+
+    let domain = match &client_request.destination_addr {
+        DestinationAddress::DomainName(domain) => Some(domain.clone()),
+        _ => None,
+    };
+    let expected_peers = expected_bind_peers(client_request, state).await?;
+
+    let listener = TcpListener::bind((client_local_addr.ip(), 0)).await?;
+    let mut bound_addr = listener.local_addr()?;
+    if config.match_client_address_family {
+        bound_addr = match_address_family(bound_addr, client_local_addr);
+    }
+    write_reply(stream, ServerReply::new_successful_reply(bound_addr), state).await?;
+
+    let (peer_conn, peer_addr) = time::timeout(config.bind_accept_timeout, listener.accept())
+        .await
+        .map_err(|_| ServerReplyError::BindTimeout)??;
+    // Only one connection is accepted per request, so nobody else gets to connect in the
+    // meantime.
+    drop(listener);
+
+    let peer_ip = peer_addr.ip().to_canonical();
+    let checked_domain = match &expected_peers {
+        Some(ips) if !ips.contains(&peer_ip) => {
+            return Err(ServerReplyError::BindPeerMismatch(peer_addr));
+        }
+        Some(_) => domain.as_deref(),
+        None => None,
+    };
+    if let Some(access_control) = &config.access_control {
+        if !access_control.is_allowed(checked_domain, peer_addr) {
+            return Err(ServerReplyError::ConnectionNotAllowed(peer_addr));
+        }
+    }
+
+    let mut reply_addr = peer_addr;
+    if config.match_client_address_family {
+        reply_addr = match_address_family(reply_addr, client_local_addr);
+    }
+    write_reply(stream, ServerReply::new_successful_reply(reply_addr), state).await?;
+
+    Ok((peer_conn, peer_addr))
+}
+
+// Addresses a BIND request allows its peer to connect from, or `None` for any address. Domain
+// names are resolved the same way as for CONNECT requests.
+async fn expected_bind_peers(
+    client_request: ClientRequest,
+    state: &ServerState,
+) -> Result<Option<Vec<IpAddr>>, ServerReplyError> {
+    if state.config.bind_allow_any_peer {
+        return Ok(None);
+    }
+
+    let ips = match client_request.destination_addr {
+        DestinationAddress::Ipv4(v4_addr) => vec![IpAddr::V4(v4_addr)],
+        DestinationAddress::Ipv6(v6_addr) => vec![IpAddr::V6(v6_addr)],
+        DestinationAddress::DomainName(domain) => {
+            let port = client_request.destination_port;
+            time::timeout(
+                state.config.resolve_timeout,
+                resolve_destination(&domain, port, state),
+            )
+            .await
+            .map_err(|_| ResolveError::Timeout)??
+            .into_iter()
+            .map(|addr| addr.ip())
+            .collect()
+        }
+    };
+    if ips.iter().any(|&ip| is_unspecified(ip)) {
+        return Ok(None);
+    }
+
+    Ok(Some(ips.into_iter().map(|ip| ip.to_canonical()).collect()))
+}
+
+// Opens the UDP association a request asks for, telling the client where to send its datagrams.
+async fn open_udp_association<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    client_request: ClientRequest,
+    client_addr: SocketAddr,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+) -> Result<UdpAssociation, ServerReplyError> {
+    let config = &state.config;
+    let slot = match &state.association_slots {
+        Some(slots) => Some(
+            Arc::clone(slots)
+                .try_acquire_owned()
+                .map_err(|_| ServerReplyError::AssociationLimitReached)?,
+        ),
+        None => None,
+    };
+
+    let socket = UdpSocket::bind((client_local_addr.ip(), 0)).await?;
+    let mut bound_addr = socket.local_addr()?;
+    if config.match_client_address_family {
+        bound_addr = match_address_family(bound_addr, client_local_addr);
+    }
+    if let Some(public_addr) = config.udp_public_addr {
+        bound_addr.set_ip(public_addr);
+    }
+    write_reply(stream, ServerReply::new_successful_reply(bound_addr), state).await?;
+
+    // The request names where the client will send its datagrams from, with zeros when it
+    // doesn't know yet. Only a port is taken from it, since datagrams are only accepted from
+    // the IP the client connected from anyway.
+    let client_port =
+        (client_request.destination_port != 0).then_some(client_request.destination_port);
+
+    Ok(UdpAssociation::new(
+        socket,
+        client_addr.ip(),
+        client_port,
+        slot,
+    ))
+}
+
+// Puts TLS on top of the outbound connection when `Config::outbound_tls` lists its destination.
+#[cfg(feature = "tls")]
+async fn originate_tls(
+    remote_conn: TcpStream,
+    destination_addr: &DestinationAddress,
+    state: &ServerState,
+) -> Result<RemoteConn, ServerReplyError> {
+    let domain = match destination_addr {
+        DestinationAddress::DomainName(domain) => Some(domain.as_str()),
+        _ => None,
+    };
+    let remote_addr = remote_conn.peer_addr()?;
+    match &state.config.outbound_tls {
+        Some(tls) if tls.applies_to(domain, remote_addr) => {
+            let conn = tls
+                .connect(domain, remote_conn)
+                .await
+                .map_err(|e| ServerReplyError::TlsHandshake(remote_addr, e))?;
+            Ok(RemoteConn::Tls(Box::new(conn)))
+        }
+        _ => Ok(RemoteConn::Tcp(remote_conn)),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+async fn originate_tls(
+    remote_conn: TcpStream,
+    _destination_addr: &DestinationAddress,
+    _state: &ServerState,
+) -> Result<RemoteConn, ServerReplyError> {
+    Ok(RemoteConn::Tcp(remote_conn))
+}
+
+// Returns the address the outbound connection is connected to.
+async fn send_server_reply<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    remote_conn: &TcpStream,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+) -> Result<SocketAddr, ServerReplyError> {
+    let remote_addr = remote_conn.peer_addr()?;
+    let mut local_addr = remote_conn.local_addr()?;
+    if state.config.match_client_address_family {
+        local_addr = match_address_family(local_addr, client_local_addr);
+    }
+    let reply_packet = ServerReply::new_successful_reply(local_addr);
+    write_reply(stream, reply_packet, state).await?;
+
+    Ok(remote_addr)
+}
+
+#[derive(Debug, Default)]
+struct ConnectionTimings {
+    hello: Option<Duration>,
+    auth: Option<Duration>,
+    request: Option<Duration>,
+    connect: Option<Duration>,
+    reply: Option<Duration>,
+    relay: Option<Duration>,
+}
+
+impl fmt::Display for ConnectionTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let stages = [
+            ("hello", self.hello),
+            ("auth", self.auth),
+            ("request", self.request),
+            ("connect", self.connect),
+            ("reply", self.reply),
+            ("relay", self.relay),
+        ];
+
+        let mut first = true;
+        for (stage, elapsed) in stages {
+            if let Some(elapsed) = elapsed {
+                if !first {
+                    write!(f, " ")?;
+                }
+                write!(f, "{}={:?}", stage, elapsed)?;
+                first = false;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// What is known about a connection so far, filled in as it goes through its stages.
+#[derive(Debug)]
+struct ConnectionRecord {
+    id: u64,
+    timings: ConnectionTimings,
+    stats: ConnectionStats,
+    // Hands the outbound connection back to `Config::connector` when the record is dropped.
+    connector_lease: Option<ConnectorLease>,
+    // Counts the connection against `Config::max_connections_per_destination` while held.
+    destination_permit: Option<ConnectionPermit<SocketAddr>>,
+    // Requested destination, rendered according to `Config::log_destination`.
+    destination: Option<String>,
+    // Counts the connection as negotiating until its relay starts.
+    handshake: Option<Handshake>,
+    // Charges relayed bytes to the authenticated user, under `Config::user_byte_quota`.
+    user_account: Option<UserAccount>,
+    // What the client is waiting for from the server while negotiating.
+    stage: HandshakeStage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HandshakeStage {
+    // Waiting for the server hello.
+    Hello,
+    // Waiting for the answer to its username/password.
+    Auth,
+    // Waiting for the reply to its request.
+    Request,
+}
+
+// Counts a connection as negotiating, and holds its `Config::max_handshakes_in_progress`
+// permit, until dropped.
+#[derive(Debug)]
+struct Handshake {
+    _permit: Option<OwnedSemaphorePermit>,
+    metrics: Arc<MetricsRegistry>,
+}
+
+impl Handshake {
+    fn new(permit: Option<OwnedSemaphorePermit>, metrics: &Arc<MetricsRegistry>) -> Self {
+        metrics.handshake_started();
+        Self {
+            _permit: permit,
+            metrics: Arc::clone(metrics),
+        }
+    }
+}
+
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.metrics.handshake_finished();
+    }
+}
+
+impl ConnectionRecord {
+    fn request_denied(&mut self, reply: Reply, state: &ServerState) {
+        self.stats.handshake.reply = Some(reply);
+        state.publish(ConnectionEvent::RequestDenied { id: self.id, reply });
+    }
+
+    // Fragment naming the destination in log lines, empty when there's none to name.
+    fn logged_destination(&self) -> String {
+        match &self.destination {
+            Some(destination) => format!(" to {}", destination),
+            None => String::new(),
+        }
+    }
+}
+
+// Waits for a free slot when the number of connections is limited. Tokio's semaphore hands out
+// permits in the order they were asked for, so queued connections are served in arrival order.
+async fn wait_for_connection_slot(
+    slots: &Arc<Semaphore>,
+    state: &ServerState,
+) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = Arc::clone(slots).try_acquire_owned() {
+        return Some(permit);
+    }
+
+    state.metrics.connection_queued();
+    let permit = time::timeout(
+        state.config.queue_wait_timeout,
+        Arc::clone(slots).acquire_owned(),
+    )
+    .await;
+    state.metrics.connection_dequeued();
+
+    permit.ok().and_then(Result::ok)
+}
+
+async fn handle_connection(
+    mut client_conn: TcpStream,
+    mut client_addr: SocketAddr,
+    state: &Arc<ServerState>,
+) -> Option<ConnectionSummary> {
+    let config = &state.config;
+    let handshake_permit = match &state.handshake_slots {
+        Some(slots) => match Arc::clone(slots).try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                println!(
+                    "Dropped connection from {}: too many handshakes in progress",
+                    client_addr
+                );
+                return None;
+            }
+        },
+        None => None,
+    };
+    let handshake = Handshake::new(handshake_permit, &state.metrics);
+
+    if let Err(e) = set_socket_buffer_sizes(SockRef::from(&client_conn), config) {
+        eprintln!(
+            "Error encountered for {}: {}. Closing connection.",
+            client_addr, e
+        );
+        return None;
+    }
+
+    if config.expect_proxy_protocol {
+        let mut shutdown = state.shutdown.subscribe();
+        let proxy_header = tokio::select! {
+            proxy_header = time::timeout(config.proxy_header_timeout, read_proxy_header(&mut client_conn)) => {
+                proxy_header.unwrap_or(Err(ProxyHeaderError::Timeout(config.proxy_header_timeout)))
+            }
+            _ = shutdown.wait_for(|&shutting_down| shutting_down) => return None,
+        };
+        match proxy_header {
+            Ok(ProxyHeader {
+                version,
+                source_addr: Some(source_addr),
+            }) => {
+                println!(
+                    "PROXY v{} header from {} carries client address {}",
+                    version, client_addr, source_addr
+                );
+                client_addr = source_addr;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!(
+                    "Error encountered for {}: {}. Closing connection.",
+                    client_addr, e
+                );
+                return None;
+            }
+        }
+    }
+
+    // Only taken now, so that behind a load balancer the limit applies to each client rather
+    // than to the load balancer's own address.
+    let _ip_permit = match config.max_connections_per_ip {
+        Some(limit) => match state
+            .connections_per_ip
+            .try_acquire(client_addr.ip(), limit)
+        {
+            Some(permit) => Some(permit),
+            None => {
+                println!(
+                    "Dropped connection from {}: per-IP connection limit reached",
+                    client_addr
+                );
+                return None;
+            }
+        },
+        None => None,
+    };
+
+    let _slot = match &state.connection_slots {
+        Some(slots) => match wait_for_connection_slot(slots, state).await {
+            Some(permit) => Some(permit),
+            None => {
+                println!(
+                    "Rejected connection from {}: no connection slot freed up within {:?}",
+                    client_addr, config.queue_wait_timeout
+                );
+                let reply_packet = ServerReply::new_unsuccessful_reply(Reply::SocksServerFail);
+                let _ = write_reply(&mut client_conn, reply_packet, state).await;
+                return None;
+            }
+        },
+        None => None,
+    };
+
+    let accepted_at = Instant::now();
+    let mut record = ConnectionRecord {
+        id: state.next_connection_id.fetch_add(1, Ordering::Relaxed),
+        timings: ConnectionTimings::default(),
+        stats: ConnectionStats::default(),
+        connector_lease: None,
+        destination_permit: None,
+        destination: None,
+        handshake: Some(handshake),
+        user_account: None,
+        stage: HandshakeStage::Hello,
+    };
+    state.publish(ConnectionEvent::Accepted {
+        id: record.id,
+        client_addr,
+    });
+
+    let mut transcript = config
+        .handshake_capture
+        .as_ref()
+        .map(|_| HandshakeBytes::default());
+
+    serve_connection(
+        client_conn,
+        client_addr,
+        state,
+        &mut record,
+        transcript.as_mut(),
+    )
+    .await;
+
+    if let (Some(dir), Some(transcript)) = (&config.handshake_capture, transcript) {
+        if let Err(e) = transcript.save(dir, client_addr).await {
+            eprintln!(
+                "Failed to save handshake capture for {}: {}",
+                client_addr, e
+            );
+        }
+    }
+
+    if config.log_timings {
+        println!(
+            "Connection from {}{} closed: {}",
+            client_addr,
+            record.logged_destination(),
+            record.timings
+        );
+    }
+
+    record.stats.duration = accepted_at.elapsed();
+    state.publish(ConnectionEvent::Closed {
+        id: record.id,
+        stats: record.stats.clone(),
+    });
+
+    Some(ConnectionSummary {
+        id: record.id,
+        client_addr,
+        stats: record.stats,
+    })
+}
+
+async fn serve_connection(
+    mut client_conn: TcpStream,
+    client_addr: SocketAddr,
+    state: &Arc<ServerState>,
+    record: &mut ConnectionRecord,
+    transcript: Option<&mut HandshakeBytes>,
+) {
+    let client_local_addr = match client_conn.local_addr() {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            return;
+        }
+    };
+
+    let mut shutdown = state.shutdown.subscribe();
+    let negotiated = if state.config.transparent {
+        let connected = tokio::select! {
+            remote_conn = connect_transparently(&client_conn, client_addr, client_local_addr, state, record) => {
+                remote_conn.map(|conn| Negotiated::Relay(RemoteConn::Tcp(conn)))
+            }
+            _ = shutdown.wait_for(|&shutting_down| shutting_down) => None,
+        };
+        match connected {
+            Some(conn) => conn,
+            None => return,
+        }
+    } else {
+        // Everything up to the relay goes through `stream`, which records the handshake bytes
+        // when capturing is enabled.
+        let log_writes_to = state.config.log_control_packets.then_some(client_addr);
+        let mut stream = RecordingStream::new(&mut client_conn, transcript, log_writes_to);
+
+        let handshake = tokio::select! {
+            negotiated = negotiate(&mut stream, client_addr, client_local_addr, state, record) => {
+                Some(negotiated)
+            }
+            _ = shutdown.wait_for(|&shutting_down| shutting_down) => None,
+        };
+        match handshake {
+            Some(Some(negotiated)) => negotiated,
+            Some(None) => return,
+            None => {
+                println!(
+                    "Interrupted handshake with {}: server is shutting down",
+                    client_addr
+                );
+                // Turn the client down with whatever packet it is waiting for, since it can't
+                // make sense of a request reply before it gets to send its request.
+                match record.stage {
+                    HandshakeStage::Hello => {
+                        let buf = ServerHello::new(AuthMethod::NoAcceptableMethod).as_bytes();
+                        let _ = stream.write_all(&buf).await;
+                    }
+                    HandshakeStage::Auth => {
+                        let buf = ServerUserPassResponse::new(false).as_bytes();
+                        let _ = stream.write_all(&buf).await;
+                    }
+                    HandshakeStage::Request => {
+                        let reply_packet =
+                            ServerReply::new_unsuccessful_reply(Reply::SocksServerFail);
+                        let _ = write_reply(&mut stream, reply_packet, state).await;
+                        record.request_denied(Reply::SocksServerFail, state);
+                    }
+                }
+                return;
+            }
+        }
+    };
+
+    record.handshake = None;
+    let remote_conn = match negotiated {
+        Negotiated::Relay(remote_conn) => remote_conn,
+        Negotiated::UdpAssociation(association) => {
+            let stage_start = Instant::now();
+            let user_account = record.user_account.take();
+            let outcome = association.run(&mut client_conn, state, user_account).await;
+            record.timings.relay = Some(stage_start.elapsed());
+
+            record.stats.bytes_client_to_remote = outcome.bytes_client_to_remote;
+            record.stats.bytes_remote_to_client = outcome.bytes_remote_to_client;
+            record.stats.close_reason = Some(outcome.reason);
+            if outcome.reason != CloseReason::Completed {
+                println!(
+                    "Closed UDP association from {}: {}",
+                    client_addr, outcome.reason
+                );
+            }
+            return;
+        }
+    };
+
+    let refused = tokio::select! {
+        refused = inspect_initial_data(&client_conn, remote_conn.tcp(), client_addr, state) => refused,
+        _ = shutdown.wait_for(|&shutting_down| shutting_down) => None,
+    };
+    if let Some(reason) = refused {
+        println!(
+            "Closed connection from {}{}: {}",
+            client_addr,
+            record.logged_destination(),
+            reason
+        );
+        record.stats.close_reason = Some(reason);
+        return;
+    }
+
+    let (progress, progress_rx) = watch::channel(RelayProgress::default());
+    let (paused, paused_rx) = watch::channel(false);
+    state.relays.lock().unwrap().insert(
+        record.id,
+        RelayControl {
+            progress: progress_rx,
+            paused,
+        },
+    );
+
+    state.metrics.relay_started();
+    let stage_start = Instant::now();
+    let user_account = record.user_account.take();
+    let outcome = handle_packet_relay(
+        client_conn,
+        remote_conn,
+        state,
+        progress,
+        paused_rx,
+        user_account,
+    )
+    .await;
+    record.timings.relay = Some(stage_start.elapsed());
+    state.metrics.relay_finished();
+
+    state.relays.lock().unwrap().remove(&record.id);
+
+    record.stats.bytes_client_to_remote = outcome.bytes_client_to_remote;
+    record.stats.bytes_remote_to_client = outcome.bytes_remote_to_client;
+    record.stats.close_reason = Some(outcome.reason);
+    record.stats.directions = Some(outcome.directions);
+    if outcome.reason != CloseReason::Completed {
+        println!(
+            "Closed connection from {}{}: {} ({})",
+            client_addr,
+            record.logged_destination(),
+            outcome.reason,
+            outcome.directions
+        );
+    }
+}
+
+// How many more times, and how often, to peek at a ClientHello that hasn't fully arrived.
+const TLS_PEEK_RETRIES: u32 = 20;
+const TLS_PEEK_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+// Looks at the client's first bytes, when `Config::initial_peek_bytes` is set and there's
+// something to check them with, returning why the relay must not go ahead, if it mustn't. The
+// bytes are peeked at and stay in the socket to be relayed. There's nothing to inspect when the
+// destination speaks (or hangs up) first, or when the client closes without sending anything,
+// and the relay goes ahead then.
+async fn inspect_initial_data(
+    client_conn: &TcpStream,
+    remote_conn: &TcpStream,
+    client_addr: SocketAddr,
+    state: &ServerState,
+) -> Option<CloseReason> {
+    let config = &state.config;
+    let server_name_rules = config
+        .access_control
+        .as_ref()
+        .filter(|_| config.check_tls_server_name);
+    if config.initial_peek_bytes == 0 || (config.inspector.is_none() && server_name_rules.is_none())
+    {
+        return None;
+    }
+    let remote_addr = remote_conn.peer_addr().ok()?;
+
+    let mut data = vec![0; config.initial_peek_bytes];
+    let mut n = tokio::select! {
+        peeked = client_conn.peek(&mut data) => match peeked {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => n,
+        },
+        _ = remote_conn.readable() => return None,
+    };
+
+    if let Some(rules) = server_name_rules {
+        let mut server_name = parse_server_name(&data[..n]);
+        // A ClientHello split over several segments may not have arrived in full yet.
+        for _ in 0..TLS_PEEK_RETRIES {
+            if server_name != ServerName::Incomplete || n == data.len() {
+                break;
+            }
+            time::sleep(TLS_PEEK_RETRY_DELAY).await;
+            n = client_conn.peek(&mut data).await.ok()?;
+            server_name = parse_server_name(&data[..n]);
+        }
+
+        if let ServerName::Name(name) = server_name {
+            if !rules.is_allowed(Some(&name), remote_addr) {
+                return Some(CloseReason::ServerNameNotAllowed);
+            }
+        }
+    }
+
+    match &config.inspector {
+        Some(inspector) if !inspector.inspect(client_addr, remote_addr, &data[..n]) => {
+            Some(CloseReason::Rejected)
+        }
+        _ => None,
+    }
+}
+
+// Stands in for the SOCKS handshake in transparent mode, connecting to wherever the client was
+// connecting to before the firewall redirected it here.
+async fn connect_transparently(
+    client_conn: &TcpStream,
+    client_addr: SocketAddr,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+    record: &mut ConnectionRecord,
+) -> Option<TcpStream> {
+    let destination = match original_destination(client_conn) {
+        Ok(addr) => addr,
+        Err(e) => {
+            eprintln!(
+                "Error encountered for {}: failed to read the original destination: {}. Closing connection.",
+                client_addr, e
+            );
+            return None;
+        }
+    };
+    let destination_addr = match destination.ip() {
+        IpAddr::V4(v4_addr) => DestinationAddress::Ipv4(v4_addr),
+        IpAddr::V6(v6_addr) => DestinationAddress::Ipv6(v6_addr),
+    };
+
+    state
+        .metrics
+        .request_received(destination.port(), destination_addr.address_type());
+    record.stats.command = Some(RequestCommand::Connect);
+    record.destination = state
+        .config
+        .log_destination
+        .render(&destination_addr, destination.port());
+
+    let client_request = ClientRequest {
+        version: packets::SOCKS_VERSION,
+        command: RequestCommand::Connect,
+        destination_addr: destination_addr.clone(),
+        destination_port: destination.port(),
+        // Transparent clients never send a request, so none was read off the wire.
+        len: 0,
+    };
+    let stage_start = Instant::now();
+    let connected = connect_to_destination(client_request, client_local_addr, state).await;
+    record.timings.connect = Some(stage_start.elapsed());
+    let (remote_conn, destination_permit) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            return None;
+        }
+    };
+    if let Some(connector) = &state.config.connector {
+        record.connector_lease = Some(ConnectorLease::new(connector, destination));
+    }
+    record.destination_permit = destination_permit;
+
+    state.publish(ConnectionEvent::RelayStarted {
+        id: record.id,
+        destination_addr,
+        destination_port: destination.port(),
+        remote_addr: remote_conn.peer_addr().unwrap_or(destination),
+    });
+
+    Some(remote_conn)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn original_destination(client_conn: &TcpStream) -> io::Result<SocketAddr> {
+    let socket = SockRef::from(client_conn);
+    let original_dst = match client_conn.local_addr()? {
+        SocketAddr::V4(_) => socket.original_dst_v4()?,
+        SocketAddr::V6(_) => socket.original_dst_v6()?,
+    };
+
+    original_dst.as_socket().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "original destination is not an IP address",
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn original_destination(_client_conn: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "transparent mode relies on SO_ORIGINAL_DST, which is only available on Linux",
+    ))
+}
+
+// Runs the SOCKS handshake up to and including the success reply, returning the connection to
+// the destination, or `None` once the connection has been dealt with because of an error.
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    client_addr: SocketAddr,
+    client_local_addr: SocketAddr,
+    state: &ServerState,
+    record: &mut ConnectionRecord,
+) -> Option<Negotiated> {
+    let config = &state.config;
+    let mut budget = HandshakeBudget::default();
+    let stage_start = Instant::now();
+    let client_hello = match read_client_hello(stream, &mut budget).await {
+        Ok(packet) => packet,
+        Err(e) => {
+            let malformed = !matches!(e, ClientHelloError::IoError(_));
+            if !malformed || config.log_malformed_hellos {
+                eprintln!(
+                    "Error encountered for {}: {}. Closing connection.",
+                    client_addr, e
+                );
+            }
+            // There is no way to answer a hello that can't be parsed, so the connection is
+            // simply closed, but it is still counted.
+            if malformed {
+                state.metrics.malformed_hello();
+            }
+            if matches!(e, ClientHelloError::UnexpectedHttpRequest)
+                && config.http_misconnect_response
+            {
+                let _ = stream.write_all(HTTP_MISCONNECT_RESPONSE).await;
+            }
+            return None;
+        }
+    };
+    let transcript = &mut record.stats.handshake;
+    transcript.version = Some(client_hello.version);
+    transcript.offered_methods = client_hello.methods.clone();
+    transcript.unknown_methods = client_hello.unknown_methods.clone();
+    if client_hello.duplicate_methods > 0 {
+        println!(
+            "Client hello from {} repeats {} auth method(s), ignoring the repeats",
+            client_addr, client_hello.duplicate_methods
+        );
+    }
+
+    let selected_method = send_server_hello(stream, client_hello, &state.auth_settings).await;
+    record.timings.hello = Some(stage_start.elapsed());
+    let selected_method = match selected_method {
+        Ok(method) => method,
+        Err(e) => {
+            if let ServerHelloError::NoAcceptableAuth { .. } = e {
+                record.stats.handshake.selected_method = Some(AuthMethod::NoAcceptableMethod);
+            }
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            return None;
+        }
+    };
+
+    record.stats.handshake.selected_method = Some(selected_method);
+    record.stage = match selected_method {
+        AuthMethod::UserPassword => HandshakeStage::Auth,
+        _ => HandshakeStage::Request,
+    };
+
+    // Every method is matched explicitly, so that the user/password subnegotiation can only
+    // ever be read after that method was selected. Methods without a subnegotiation go straight
+    // on to the request.
+    let username = match selected_method {
+        AuthMethod::NoAuth => None,
+        AuthMethod::UserPassword => {
+            let stage_start = Instant::now();
+            let auth_result = handle_user_pass_auth(
+                stream,
+                client_addr,
+                &state.auth_settings,
+                config,
+                &state.metrics,
+                &mut record.stats.handshake,
+                &mut budget,
+            )
+            .await;
+            record.timings.auth = Some(stage_start.elapsed());
+
+            match auth_result {
+                Ok(name) => {
+                    record.stage = HandshakeStage::Request;
+                    Some(name)
+                }
+                Err(e) => {
+                    eprintln!(
+                        "Error encountered for {}: {}. Closing connection.",
+                        client_addr, e
+                    );
+                    return None;
+                }
+            }
+        }
+        AuthMethod::Gssapi | AuthMethod::NoAcceptableMethod => {
+            eprintln!(
+                "Error encountered for {}: {:?} authentication is not supported. Closing connection.",
+                client_addr, selected_method
+            );
+            return None;
+        }
+    };
+    state.publish(ConnectionEvent::Authenticated {
+        id: record.id,
+        method: selected_method,
+        username: username.clone(),
+    });
+
+    let stage_start = Instant::now();
+    let client_request = read_client_request(stream, &mut budget).await;
+    record.timings.request = Some(stage_start.elapsed());
+    let client_request = match client_request {
+        Ok(packet) => packet,
+        Err(e) => {
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            let reply = handle_client_request_error(stream, e, state).await;
+            record.request_denied(reply, state);
+            return None;
+        }
+    };
+
+    state.metrics.request_received(
+        client_request.destination_port,
+        client_request.destination_addr.address_type(),
+    );
+    record.stats.command = Some(client_request.command);
+    record.stats.handshake.destination_addr = Some(client_request.destination_addr.clone());
+    record.stats.handshake.destination_port = Some(client_request.destination_port);
+    record.destination = config.log_destination.render(
+        &client_request.destination_addr,
+        client_request.destination_port,
+    );
+
+    let unsupported_command_error = match client_request.command {
+        RequestCommand::Connect => None,
+        RequestCommand::Bind if config.allow_bind => None,
+        RequestCommand::Bind => Some(ClientRequestError::ErrUnsupportedBindCommand),
+        RequestCommand::UdpAssociate if config.allow_udp_associate => None,
+        RequestCommand::UdpAssociate => Some(ClientRequestError::ErrUnsupportedUDPAssociateCommand),
+    };
+    if let Some(e) = unsupported_command_error {
+        eprintln!(
+            "Rejected {:?} request from {}{}: {}. Closing connection.",
+            client_request.command,
+            client_addr,
+            record.logged_destination(),
+            e
+        );
+        let reply = handle_client_request_error(stream, e, state).await;
+        record.request_denied(reply, state);
+        return None;
+    }
+
+    if let (Some(health_check_domain), DestinationAddress::DomainName(domain)) = (
+        &config.health_check_domain,
+        &client_request.destination_addr,
+    ) {
+        if client_request.command == RequestCommand::Connect
+            && domain.eq_ignore_ascii_case(health_check_domain)
+        {
+            let unspecified = SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0));
+            let reply_packet = ServerReply::new_successful_reply(unspecified);
+            let _ = write_reply(stream, reply_packet, state).await;
+            record.stats.handshake.reply = Some(Reply::Succeeded);
+            return None;
+        }
+    }
+
+    if let (Some(quota), Some(username)) = (config.user_byte_quota, username) {
+        if state.user_usage.used(&username, &quota) >= quota.max_bytes {
+            let e = ServerReplyError::UserQuotaExceeded(username);
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            let reply = handle_server_reply_error(stream, e, state).await;
+            record.request_denied(reply, state);
+            return None;
+        }
+        record.user_account = Some(UserAccount::new(&state.user_usage, username, quota));
+    }
+
+    let destination_addr = client_request.destination_addr.clone();
+    let destination_port = client_request.destination_port;
+
+    if client_request.command == RequestCommand::Bind {
+        let bound = accept_bound_connection(stream, client_request, client_local_addr, state).await;
+        let (remote_conn, remote_addr) = match bound {
+            Ok(bound) => bound,
+            Err(e) => {
+                eprintln!(
+                    "Error encountered for {}: {}. Closing connection.",
+                    client_addr, e
+                );
+                let reply = handle_server_reply_error(stream, e, state).await;
+                record.request_denied(reply, state);
+                return None;
+            }
+        };
+        record.stats.handshake.reply = Some(Reply::Succeeded);
+
+        state.publish(ConnectionEvent::RelayStarted {
+            id: record.id,
+            destination_addr,
+            destination_port,
+            remote_addr,
+        });
+
+        return Some(Negotiated::Relay(RemoteConn::Tcp(remote_conn)));
+    }
+
+    if client_request.command == RequestCommand::UdpAssociate {
+        let association = open_udp_association(
+            stream,
+            client_request,
+            client_addr,
+            client_local_addr,
+            state,
+        )
+        .await;
+        return match association {
+            Ok(association) => {
+                record.stats.handshake.reply = Some(Reply::Succeeded);
+                Some(Negotiated::UdpAssociation(association))
+            }
+            Err(e) => {
+                eprintln!(
+                    "Error encountered for {}: {}. Closing connection.",
+                    client_addr, e
+                );
+                let reply = handle_server_reply_error(stream, e, state).await;
+                record.request_denied(reply, state);
+                None
+            }
+        };
+    }
+
+    let stage_start = Instant::now();
+    let remote_conn = connect_to_destination(client_request, client_local_addr, state).await;
+    let connected_at = Instant::now();
+    record.timings.connect = Some(connected_at - stage_start);
+    let remote_conn = match remote_conn {
+        Ok((conn, destination_permit)) => {
+            if let Some(threshold) = config.slow_connect_threshold {
+                let took = connected_at - stage_start;
+                if took > threshold {
+                    println!(
+                        "Slow connect for {}{}: took {:?}",
+                        client_addr,
+                        record.logged_destination(),
+                        took
+                    );
+                    state.metrics.slow_connect();
+                }
+            }
+            if let (Some(connector), Ok(addr)) = (&config.connector, conn.peer_addr()) {
+                record.connector_lease = Some(ConnectorLease::new(connector, addr));
+            }
+            record.destination_permit = destination_permit;
+            conn
+        }
+        Err(e) => {
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            let reply = handle_server_reply_error(stream, e, state).await;
+            record.request_denied(reply, state);
+            return None;
+        }
+    };
+
+    // Besides originating TLS, nothing else is awaited between the connect completing and the
+    // success reply going out.
+    let reply_result = async {
+        let remote_conn = originate_tls(remote_conn, &destination_addr, state).await?;
+        let remote_addr =
+            send_server_reply(stream, remote_conn.tcp(), client_local_addr, state).await?;
+        Ok::<_, ServerReplyError>((remote_conn, remote_addr))
+    }
+    .await;
+    record.timings.reply = Some(connected_at.elapsed());
+    let (remote_conn, remote_addr) = match reply_result {
+        Ok(connected) => connected,
+        Err(e) => {
+            eprintln!(
+                "Error encountered for {}: {}. Closing connection.",
+                client_addr, e
+            );
+            let reply = handle_server_reply_error(stream, e, state).await;
+            record.request_denied(reply, state);
+            return None;
+        }
+    };
+    record.stats.handshake.reply = Some(Reply::Succeeded);
+
+    state.publish(ConnectionEvent::RelayStarted {
+        id: record.id,
+        destination_addr,
+        destination_port,
+        remote_addr,
+    });
+
+    Some(Negotiated::Relay(remote_conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::atomic::AtomicBool;
+
+    use super::*;
+    use packets::udp_relay::UdpHeader;
+    use packets::SOCKS_VERSION;
+
+    fn no_auth() -> AuthSettings {
+        AuthSettings {
+            method: AuthMethod::NoAuth,
+            params: None,
+        }
+    }
+
+    async fn spawn_server(config: Config) -> ServerHandle {
+        SocksServer::with_config(no_auth(), config)
+            .unwrap()
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn server_state(config: Config) -> Arc<ServerState> {
+        SocksServer::with_config(no_auth(), config).unwrap().state()
+    }
+
+    fn connection_record() -> ConnectionRecord {
+        ConnectionRecord {
+            id: 0,
+            timings: ConnectionTimings::default(),
+            stats: ConnectionStats::default(),
+            connector_lease: None,
+            destination_permit: None,
+            destination: None,
+            handshake: None,
+            user_account: None,
+            stage: HandshakeStage::Hello,
+        }
+    }
+
+    // Reads until the server closes the connection, returning everything it wrote.
+    async fn read_until_closed(stream: &mut TcpStream) -> Vec<u8> {
+        let mut received = Vec::new();
+        time::timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+            .await
+            .expect("server did not close the connection")
+            .unwrap();
+
+        received
+    }
+
+    fn request(command: RequestCommand, destination: SocketAddr) -> Vec<u8> {
+        let mut packet = vec![SOCKS_VERSION, command as u8, 0];
+        match destination.ip() {
+            IpAddr::V4(v4_addr) => {
+                packet.push(AddressType::Ipv4 as u8);
+                packet.extend_from_slice(&v4_addr.octets());
+            }
+            IpAddr::V6(v6_addr) => {
+                packet.push(AddressType::Ipv6 as u8);
+                packet.extend_from_slice(&v6_addr.octets());
+            }
+        }
+        packet.extend_from_slice(&destination.port().to_be_bytes());
+        packet
+    }
+
+    // Reads a reply to a request, returning its reply code and bound address.
+    async fn read_reply(stream: &mut TcpStream) -> (u8, SocketAddr) {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], SOCKS_VERSION);
+        let ip = match AddressType::try_from(header[3]) {
+            Ok(AddressType::Ipv4) => IpAddr::V4(Ipv4Addr::from(stream.read_u32().await.unwrap())),
+            Ok(AddressType::Ipv6) => IpAddr::V6(Ipv6Addr::from(stream.read_u128().await.unwrap())),
+            _ => panic!("unexpected bound address type {}", header[3]),
+        };
+        let port = stream.read_u16().await.unwrap();
+
+        (header[1], SocketAddr::new(ip, port))
+    }
+
+    fn domain_request(command: RequestCommand, domain: &str, port: u16) -> Vec<u8> {
+        let mut packet = vec![
+            SOCKS_VERSION,
+            command as u8,
+            0,
+            AddressType::DomainName as u8,
+            domain.len() as u8,
+        ];
+        packet.extend_from_slice(domain.as_bytes());
+        packet.extend_from_slice(&port.to_be_bytes());
+        packet
+    }
+
+    // Connects to `server` over a NoAuth handshake and sends `request`, returning the reply
+    // code and bound address.
+    async fn send_request(server: SocketAddr, request: &[u8]) -> (TcpStream, u8, SocketAddr) {
+        let mut client = TcpStream::connect(server).await.unwrap();
+        client
+            .write_all(&[SOCKS_VERSION, 1, AuthMethod::NoAuth as u8])
+            .await
+            .unwrap();
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+        assert_eq!(server_hello, [SOCKS_VERSION, AuthMethod::NoAuth as u8]);
+        client.write_all(request).await.unwrap();
+        let (reply, bound_addr) = read_reply(&mut client).await;
+
+        (client, reply, bound_addr)
+    }
+
+    async fn socks_request(
+        server: SocketAddr,
+        command: RequestCommand,
+        destination: SocketAddr,
+    ) -> (TcpStream, u8, SocketAddr) {
+        send_request(server, &request(command, destination)).await
+    }
+
+    async fn socks_connect(
+        server: SocketAddr,
+        destination: SocketAddr,
+    ) -> (TcpStream, u8, SocketAddr) {
+        socks_request(server, RequestCommand::Connect, destination).await
+    }
+
+    #[tokio::test]
+    async fn reads_v1_proxy_header_without_consuming_hello() {
+        let mut stream: &[u8] = b"PROXY TCP4 203.0.113.7 10.0.0.1 4242 1080\r\n\x05\x01\x00";
+
+        let header = read_proxy_header(&mut stream).await.unwrap();
+
+        assert_eq!(header.version, 1);
+        assert_eq!(
+            header.source_addr,
+            Some("203.0.113.7:4242".parse().unwrap())
+        );
+        assert_eq!(stream, b"\x05\x01\x00");
+    }
+
+    #[tokio::test]
+    async fn reads_v2_proxy_header_without_consuming_hello() {
+        let mut packet = PROXY_V2_SIGNATURE.to_vec();
+        packet.extend_from_slice(&[0x21, 0x11, 0, 12]);
+        packet.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1]);
+        packet.extend_from_slice(&4242u16.to_be_bytes());
+        packet.extend_from_slice(&1080u16.to_be_bytes());
+        packet.extend_from_slice(b"\x05\x01\x00");
+        let mut stream = &packet[..];
+
+        let header = read_proxy_header(&mut stream).await.unwrap();
+
+        assert_eq!(header.version, 2);
+        assert_eq!(
+            header.source_addr,
+            Some("203.0.113.7:4242".parse().unwrap())
+        );
+        assert_eq!(stream, b"\x05\x01\x00");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_proxy_header_after_six_bytes() {
+        // The writer is kept open, so reading past the first 6 bytes would hang the test.
+        let (mut client, mut server) = io::duplex(64);
+        client.write_all(b"\x05\x01\x00\x05\x01\x00").await.unwrap();
+
+        let result = time::timeout(Duration::from_secs(5), read_proxy_header(&mut server))
+            .await
+            .expect("rejection waited for more bytes");
+
+        assert!(matches!(result, Err(ProxyHeaderError::MissingHeader)));
+    }
+
+    #[tokio::test]
+    async fn closes_connection_that_sends_no_proxy_header() {
+        let handle = spawn_server(Config {
+            expect_proxy_protocol: true,
+            proxy_header_timeout: Duration::from_millis(50),
+            ..Config::default()
+        })
+        .await;
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+        assert!(read_until_closed(&mut client).await.is_empty());
+    }
+
+    #[test]
+    fn matches_ipv6_client_family() {
+        let client_local_addr = "[::1]:1080".parse().unwrap();
+
+        assert_eq!(
+            match_address_family("203.0.113.7:4242".parse().unwrap(), client_local_addr),
+            "[::ffff:203.0.113.7]:4242".parse().unwrap()
+        );
+        assert_eq!(
+            match_address_family("[2001:db8::1]:4242".parse().unwrap(), client_local_addr),
+            "[2001:db8::1]:4242".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn matches_ipv4_client_family() {
+        let client_local_addr = "127.0.0.1:1080".parse().unwrap();
+
+        assert_eq!(
+            match_address_family(
+                "[::ffff:203.0.113.7]:4242".parse().unwrap(),
+                client_local_addr
+            ),
+            "203.0.113.7:4242".parse().unwrap()
+        );
+        // There's no IPv4 form of a native IPv6 address, so it's kept.
+        assert_eq!(
+            match_address_family("[2001:db8::1]:4242".parse().unwrap(), client_local_addr),
+            "[2001:db8::1]:4242".parse().unwrap()
+        );
+        assert_eq!(
+            match_address_family("203.0.113.7:4242".parse().unwrap(), client_local_addr),
+            "203.0.113.7:4242".parse().unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn advertises_ipv6_to_ipv6_client_of_ipv4_destination() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = SocksServer::with_config(
+            no_auth(),
+            Config {
+                match_client_address_family: true,
+                ..Config::default()
+            },
+        )
+        .unwrap()
+        .spawn("[::1]:0".parse().unwrap())
+        .await
+        .unwrap();
+
+        let (_client, reply, bound_addr) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        let (remote_conn, _) = destination.accept().await.unwrap();
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let IpAddr::V6(bound_ip) = bound_addr.ip() else {
+            panic!("bound address {} is not IPv6", bound_addr);
+        };
+        assert_eq!(bound_ip.to_ipv4_mapped(), Some(Ipv4Addr::LOCALHOST));
+        assert_eq!(bound_addr.port(), remote_conn.peer_addr().unwrap().port());
+    }
+
+    #[tokio::test]
+    async fn records_timings_of_each_stage_reached() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = request(RequestCommand::Connect, destination.local_addr().unwrap());
+        let state = server_state(Config::default());
+        let (mut client, mut server) = io::duplex(1024);
+        let client_task = tokio::spawn(async move {
+            client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+            let mut server_hello = [0; 2];
+            client.read_exact(&mut server_hello).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            let mut reply = [0; 10];
+            client.read_exact(&mut reply).await.unwrap();
+            reply
+        });
+
+        let mut record = connection_record();
+        let negotiated = negotiate(
+            &mut server,
+            "127.0.0.1:4242".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+            &state,
+            &mut record,
+        )
+        .await;
+
+        assert!(matches!(negotiated, Some(Negotiated::Relay(_))));
+        assert_eq!(client_task.await.unwrap()[1], Reply::Succeeded as u8);
+        let timings = &record.timings;
+        assert!(timings.hello.is_some());
+        assert!(timings.request.is_some());
+        assert!(timings.connect.is_some());
+        assert!(timings.reply.is_some());
+        // NoAuth has no auth stage, and the relay hasn't started yet.
+        assert!(timings.auth.is_none());
+        assert!(timings.relay.is_none());
+    }
+
+    #[tokio::test]
+    async fn records_timings_only_for_stages_reached() {
+        let state = server_state(Config::default());
+        let (mut client, mut server) = io::duplex(1024);
+        let client_task = tokio::spawn(async move {
+            client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+            let mut server_hello = [0; 2];
+            client.read_exact(&mut server_hello).await.unwrap();
+            // A SOCKS4 request, which fails to parse.
+            client
+                .write_all(&[4, 1, 0, 80, 127, 0, 0, 1])
+                .await
+                .unwrap();
+            let mut reply = Vec::new();
+            client.read_to_end(&mut reply).await.unwrap();
+        });
+
+        let mut record = connection_record();
+        let negotiated = negotiate(
+            &mut server,
+            "127.0.0.1:4242".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+            &state,
+            &mut record,
+        )
+        .await;
+        drop(server);
+        client_task.await.unwrap();
+
+        assert!(negotiated.is_none());
+        let timings = &record.timings;
+        assert!(timings.hello.is_some());
+        assert!(timings.request.is_some());
+        assert!(timings.connect.is_none());
+        assert!(timings.reply.is_none());
+        assert_eq!(
+            timings.to_string(),
+            format!(
+                "hello={:?} request={:?}",
+                timings.hello.unwrap(),
+                timings.request.unwrap()
+            )
+        );
+    }
+
+    // Waits until the server has no more open connections than `count`.
+    async fn wait_for_active_connections(handle: &ServerHandle, count: u64) {
+        time::timeout(Duration::from_secs(5), async {
+            while handle.active_connections() > count {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connections did not close");
+    }
+
+    // Connects and completes the hello, so the connection is known to hold its per-IP permit.
+    async fn say_hello(server: SocketAddr, proxy_header: &[u8]) -> TcpStream {
+        let mut client = TcpStream::connect(server).await.unwrap();
+        client.write_all(proxy_header).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn limits_connections_per_ip() {
+        let handle = spawn_server(Config {
+            max_connections_per_ip: Some(2),
+            ..Config::default()
+        })
+        .await;
+        let first = say_hello(handle.local_addr(), &[]).await;
+        let _second = say_hello(handle.local_addr(), &[]).await;
+
+        let mut third = TcpStream::connect(handle.local_addr()).await.unwrap();
+        assert!(read_until_closed(&mut third).await.is_empty());
+
+        drop(first);
+        wait_for_active_connections(&handle, 1).await;
+        say_hello(handle.local_addr(), &[]).await;
+    }
+
+    #[tokio::test]
+    async fn limits_connections_per_proxied_ip() {
+        let handle = spawn_server(Config {
+            expect_proxy_protocol: true,
+            max_connections_per_ip: Some(1),
+            ..Config::default()
+        })
+        .await;
+        let header = |ip: &str| format!("PROXY TCP4 {} 127.0.0.1 4242 1080\r\n", ip);
+
+        // Every connection comes from the load balancer's address, but only repeats of the
+        // same client count against the limit.
+        let _first = say_hello(handle.local_addr(), header("203.0.113.7").as_bytes()).await;
+        let _second = say_hello(handle.local_addr(), header("203.0.113.8").as_bytes()).await;
+
+        let mut third = TcpStream::connect(handle.local_addr()).await.unwrap();
+        third
+            .write_all(header("203.0.113.7").as_bytes())
+            .await
+            .unwrap();
+        assert!(read_until_closed(&mut third).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_disabled_bind_after_parsing_it() {
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+
+        let (client, reply, _) = socks_request(
+            handle.local_addr(),
+            RequestCommand::Bind,
+            "203.0.113.7:21".parse().unwrap(),
+        )
+        .await;
+        drop(client);
+
+        assert_eq!(reply, Reply::CmdNotSupported as u8);
+        loop {
+            match events.recv().await.unwrap() {
+                ConnectionEvent::RequestDenied { reply, .. } => {
+                    assert_eq!(reply, Reply::CmdNotSupported);
+                }
+                ConnectionEvent::Closed { stats, .. } => {
+                    assert_eq!(stats.command, Some(RequestCommand::Bind));
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn io_failure(kind: io::ErrorKind) -> ServerReplyError {
+        ServerReplyError::IoError(io::Error::from(kind))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_connect_that_fails_once() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = destination.local_addr().unwrap();
+        let config = Config {
+            connect_retries: 2,
+            connect_retry_delay: Duration::from_secs(1),
+            ..Config::default()
+        };
+        let attempts = AtomicU64::new(0);
+
+        let started_at = Instant::now();
+        let result = retry_transient_failures(&config, || async {
+            if attempts.fetch_add(1, Ordering::Relaxed) == 0 {
+                Err(io_failure(io::ErrorKind::ConnectionReset))
+            } else {
+                Ok(TcpStream::connect(addr).await?)
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap().peer_addr().unwrap(), addr);
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert!(started_at.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn does_not_retry_refused_connect() {
+        let config = Config {
+            connect_retries: 2,
+            ..Config::default()
+        };
+        let attempts = AtomicU64::new(0);
+
+        let result: Result<(), _> = retry_transient_failures(&config, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(io_failure(io::ErrorKind::ConnectionRefused))
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_connect_retries_run_out() {
+        let config = Config {
+            connect_retries: 2,
+            ..Config::default()
+        };
+        let attempts = AtomicU64::new(0);
+
+        let result: Result<(), _> = retry_transient_failures(&config, || async {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            Err(io_failure(io::ErrorKind::TimedOut))
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+    }
+
+    #[tokio::test]
+    async fn captures_handshake_but_not_relayed_data() {
+        let dir = std::env::temp_dir().join(format!("socks-handshake-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config {
+            handshake_capture: Some(dir.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        let (mut client, reply, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        client.write_all(b"secret").await.unwrap();
+        let mut relayed = [0; 6];
+        remote_conn.read_exact(&mut relayed).await.unwrap();
+        drop(client);
+        drop(remote_conn);
+        wait_for_active_connections(&handle, 0).await;
+
+        let entry = std::fs::read_dir(&dir).unwrap().next().unwrap().unwrap();
+        let capture = std::fs::read_to_string(entry.path()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        let lines: Vec<&str> = capture.lines().collect();
+        assert_eq!(lines.len(), 4, "{}", capture);
+        assert_eq!(lines[0], "C> 05 01 00");
+        assert_eq!(lines[1], "S< 05 00");
+        assert!(lines[2].starts_with("C> 05 01 00 01 7f 00 00 01"));
+        assert!(lines[3].starts_with("S< 05 00 00 01"));
+        // "secret" in hex.
+        assert!(!capture.contains("73 65 63 72 65 74"));
+    }
+
+    // Opens connections only after `delay`, like a destination that is slow to accept.
+    #[derive(Debug)]
+    struct SlowConnector {
+        delay: Duration,
+    }
+
+    impl Connector for SlowConnector {
+        fn connect(&self, addr: SocketAddr) -> ConnectFuture<'_> {
+            Box::pin(async move {
+                time::sleep(self.delay).await;
+                TcpStream::connect(addr).await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_reply_right_after_connecting() {
+        let delay = Duration::from_millis(200);
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let request = request(RequestCommand::Connect, destination.local_addr().unwrap());
+        let state = server_state(Config {
+            connector: Some(Arc::new(SlowConnector { delay })),
+            ..Config::default()
+        });
+        let (mut client, mut server) = io::duplex(1024);
+        let client_task = tokio::spawn(async move {
+            client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+            let mut server_hello = [0; 2];
+            client.read_exact(&mut server_hello).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            let mut reply = [0; 10];
+            client.read_exact(&mut reply).await.unwrap();
+        });
+
+        let mut record = connection_record();
+        let negotiated = negotiate(
+            &mut server,
+            "127.0.0.1:4242".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+            &state,
+            &mut record,
+        )
+        .await;
+        client_task.await.unwrap();
+
+        assert!(matches!(negotiated, Some(Negotiated::Relay(_))));
+        assert!(record.timings.connect.unwrap() >= delay);
+        assert!(record.timings.reply.unwrap() < delay / 4);
+    }
+
+    #[tokio::test]
+    async fn answers_recently_refused_destination_from_cache() {
+        let handle = spawn_server(Config {
+            connect_failure_cache_ttl: Some(Duration::from_secs(60)),
+            ..Config::default()
+        })
+        .await;
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = destination.local_addr().unwrap();
+        drop(destination);
+
+        let (_client, reply, _) = socks_connect(handle.local_addr(), addr).await;
+        assert_eq!(reply, Reply::ConnRefused as u8);
+
+        // The destination is up now, but the cached failure is still answered.
+        let _destination = TcpListener::bind(addr).await.unwrap();
+        let (_client, reply, _) = socks_connect(handle.local_addr(), addr).await;
+        assert_eq!(reply, Reply::ConnRefused as u8);
+    }
+
+    #[tokio::test]
+    async fn publishes_events_of_a_connection_in_order() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+
+        let (mut client, _, _) = socks_connect(handle.local_addr(), destination_addr).await;
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        remote_conn.read_exact(&mut ping).await.unwrap();
+        drop(client);
+        drop(remote_conn);
+
+        let ConnectionEvent::Accepted { id, client_addr } = events.recv().await.unwrap() else {
+            panic!("expected Accepted first");
+        };
+        assert_eq!(client_addr.ip(), Ipv4Addr::LOCALHOST);
+        match events.recv().await.unwrap() {
+            ConnectionEvent::Authenticated {
+                id: event_id,
+                method,
+                username,
+            } => {
+                assert_eq!(event_id, id);
+                assert_eq!(method, AuthMethod::NoAuth);
+                assert_eq!(username, None);
+            }
+            event => panic!("expected Authenticated, got {:?}", event),
+        }
+        match events.recv().await.unwrap() {
+            ConnectionEvent::RelayStarted {
+                id: event_id,
+                destination_addr: requested_addr,
+                destination_port,
+                remote_addr,
+            } => {
+                assert_eq!(event_id, id);
+                assert_eq!(
+                    requested_addr,
+                    DestinationAddress::Ipv4(Ipv4Addr::LOCALHOST)
+                );
+                assert_eq!(destination_port, destination_addr.port());
+                assert_eq!(remote_addr, destination_addr);
+            }
+            event => panic!("expected RelayStarted, got {:?}", event),
+        }
+        match events.recv().await.unwrap() {
+            ConnectionEvent::Closed {
+                id: event_id,
+                stats,
+            } => {
+                assert_eq!(event_id, id);
+                assert_eq!(stats.command, Some(RequestCommand::Connect));
+                assert_eq!(stats.bytes_client_to_remote, 4);
+                assert_eq!(stats.close_reason, Some(CloseReason::Completed));
+            }
+            event => panic!("expected Closed, got {:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_socket_buffer_sizes() {
+        let config = Config {
+            socket_send_buffer: Some(64 * 1024),
+            socket_recv_buffer: Some(32 * 1024),
+            ..Config::default()
+        };
+
+        // Kernels may round the sizes up (Linux doubles them), but never go below them.
+        let socket = new_outbound_socket("127.0.0.1:80".parse().unwrap(), &config).unwrap();
+        let socket = SockRef::from(&socket);
+        assert!(socket.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(socket.recv_buffer_size().unwrap() >= 32 * 1024);
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = listener.accept().await.unwrap();
+        set_socket_buffer_sizes(SockRef::from(&accepted), &config).unwrap();
+        let accepted = SockRef::from(&accepted);
+        assert!(accepted.send_buffer_size().unwrap() >= 64 * 1024);
+        assert!(accepted.recv_buffer_size().unwrap() >= 32 * 1024);
+    }
+
+    // Never answers, like a DNS server that drops queries.
+    #[derive(Debug)]
+    struct HangingResolver;
+
+    impl Resolver for HangingResolver {
+        fn resolve<'a>(&'a self, _host: &'a str, _port: u16) -> ResolveFuture<'a> {
+            Box::pin(future::pending())
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_request_once_resolve_timeout_runs_out() {
+        let handle = spawn_server(Config {
+            resolver: Arc::new(HangingResolver),
+            resolve_timeout: Duration::from_millis(50),
+            ..Config::default()
+        })
+        .await;
+
+        let request = domain_request(RequestCommand::Connect, "example.com", 80);
+        let (_client, reply, _) = time::timeout(
+            Duration::from_secs(5),
+            send_request(handle.local_addr(), &request),
+        )
+        .await
+        .expect("request hung on the resolver");
+
+        assert_eq!(reply, Reply::TTLExpired as u8);
+        assert_eq!(handle.metrics().resolutions_in_flight, 0);
+    }
+
+    // Resolves every name to 127.0.0.1, but each lookup only finishes once `release` hands out
+    // a permit for it.
+    #[derive(Debug)]
+    struct GatedResolver {
+        started: AtomicU64,
+        release: Semaphore,
+    }
+
+    impl Resolver for GatedResolver {
+        fn resolve<'a>(&'a self, _host: &'a str, port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                self.started.fetch_add(1, Ordering::Relaxed);
+                self.release.acquire().await.unwrap().forget();
+                Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)])
+            })
+        }
+    }
+
+    // Waits until `resolver` has started `n` lookups.
+    async fn wait_for_lookups(resolver: &GatedResolver, n: u64) {
+        time::timeout(Duration::from_secs(5), async {
+            while resolver.started.load(Ordering::Relaxed) < n {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("lookup did not start");
+    }
+
+    #[tokio::test]
+    async fn resolves_one_name_at_a_time_with_single_slot() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        let resolver = Arc::new(GatedResolver {
+            started: AtomicU64::new(0),
+            release: Semaphore::new(0),
+        });
+        let handle = spawn_server(Config {
+            resolver: resolver.clone(),
+            max_concurrent_resolutions: Some(1),
+            ..Config::default()
+        })
+        .await;
+
+        let server = handle.local_addr();
+        let requests: Vec<_> = ["one.example", "two.example"]
+            .into_iter()
+            .map(|domain| {
+                let request = domain_request(RequestCommand::Connect, domain, port);
+                tokio::spawn(async move { send_request(server, &request).await })
+            })
+            .collect();
+        wait_for_lookups(&resolver, 1).await;
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(resolver.started.load(Ordering::Relaxed), 1);
+        assert_eq!(handle.metrics().resolutions_in_flight, 1);
+
+        resolver.release.add_permits(1);
+        wait_for_lookups(&resolver, 2).await;
+        resolver.release.add_permits(1);
+        for request in requests {
+            let (_client, reply, _) = request.await.unwrap();
+            assert_eq!(reply, Reply::Succeeded as u8);
+        }
+        assert_eq!(handle.metrics().resolutions_in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn binds_outbound_connections_round_robin() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let pool: Vec<IpAddr> = vec![
+            Ipv4Addr::LOCALHOST.into(),
+            Ipv4Addr::new(127, 0, 0, 2).into(),
+        ];
+        let handle = spawn_server(Config {
+            outbound_bind_pool: pool.clone(),
+            ..Config::default()
+        })
+        .await;
+
+        for expected_ip in [pool[0], pool[1], pool[0]] {
+            let (_client, reply, bound_addr) =
+                socks_connect(handle.local_addr(), destination_addr).await;
+            let (_remote_conn, peer_addr) = destination.accept().await.unwrap();
+
+            assert_eq!(reply, Reply::Succeeded as u8);
+            assert_eq!(peer_addr.ip(), expected_ip);
+            assert_eq!(bound_addr, peer_addr);
+        }
+    }
+
+    // Waits until the server has a connection negotiating.
+    async fn wait_for_handshake(handle: &ServerHandle) {
+        time::timeout(Duration::from_secs(5), async {
+            while handle.metrics().handshakes_in_progress == 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection did not start negotiating");
+    }
+
+    #[tokio::test]
+    async fn turns_down_hello_on_shutdown() {
+        let handle = spawn_server(Config::default()).await;
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        wait_for_handshake(&handle).await;
+
+        handle.shutdown();
+
+        assert_eq!(
+            read_until_closed(&mut client).await,
+            [SOCKS_VERSION, AuthMethod::NoAcceptableMethod as u8]
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_auth_on_shutdown() {
+        let handle = SocksServer::with_config(user_pass_settings(), Config::default())
+            .unwrap()
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client
+            .write_all(&[SOCKS_VERSION, 1, AuthMethod::UserPassword as u8])
+            .await
+            .unwrap();
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+
+        handle.shutdown();
+
+        assert_eq!(read_until_closed(&mut client).await, [1, 1]);
+    }
+
+    #[tokio::test]
+    async fn fails_request_on_shutdown() {
+        let handle = spawn_server(Config::default()).await;
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+
+        handle.shutdown();
+
+        let reply = read_until_closed(&mut client).await;
+        assert_eq!(reply.len(), 10);
+        assert_eq!(reply[1], Reply::SocksServerFail as u8);
+    }
+
+    // Sends a BIND request for `peer_addr` and connects to the address the first reply
+    // advertises from the loopback address, returning the client and the second reply.
+    async fn bind_and_connect_peer(
+        config: Config,
+        peer_addr: SocketAddr,
+    ) -> (TcpStream, TcpStream, u8, SocketAddr) {
+        let handle = spawn_server(Config {
+            allow_bind: true,
+            ..config
+        })
+        .await;
+        let (mut client, reply, listen_addr) =
+            socks_request(handle.local_addr(), RequestCommand::Bind, peer_addr).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+
+        let peer = TcpStream::connect(listen_addr).await.unwrap();
+        let (reply, bound_addr) = read_reply(&mut client).await;
+
+        (client, peer, reply, bound_addr)
+    }
+
+    #[tokio::test]
+    async fn accepts_bind_peer_from_requested_address() {
+        let (_client, peer, reply, peer_addr) =
+            bind_and_connect_peer(Config::default(), "127.0.0.1:0".parse().unwrap()).await;
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(peer_addr, peer.local_addr().unwrap());
+    }
+
+    #[tokio::test]
+    async fn rejects_bind_peer_from_other_address() {
+        let (mut client, _peer, reply, _) =
+            bind_and_connect_peer(Config::default(), "203.0.113.7:0".parse().unwrap()).await;
+
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+        assert!(read_until_closed(&mut client).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepts_any_bind_peer_when_relaxed() {
+        let config = Config {
+            bind_allow_any_peer: true,
+            ..Config::default()
+        };
+        let (_client, _peer, reply, _) =
+            bind_and_connect_peer(config, "203.0.113.7:0".parse().unwrap()).await;
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+    }
+
+    fn user_pass_settings() -> AuthSettings {
+        AuthSettings {
+            method: AuthMethod::UserPassword,
+            params: Some(AuthParams {
+                logins: HashMap::from([("alice".to_string(), "secret".to_string())]),
+            }),
+        }
+    }
+
+    fn user_pass_auth(username: &str, password: &str) -> Vec<u8> {
+        let mut packet = vec![1, username.len() as u8];
+        packet.extend_from_slice(username.as_bytes());
+        packet.push(password.len() as u8);
+        packet.extend_from_slice(password.as_bytes());
+        packet
+    }
+
+    // Runs the username/password subnegotiation for `auth` and returns its outcome along with
+    // how long the server took to answer.
+    async fn time_user_pass_auth(auth: &[u8], config: &Config) -> (bool, Duration) {
+        let (mut client, mut server) = io::duplex(1024);
+        client.write_all(auth).await.unwrap();
+
+        let started_at = Instant::now();
+        let result = handle_user_pass_auth(
+            &mut server,
+            "127.0.0.1:4242".parse().unwrap(),
+            &user_pass_settings(),
+            config,
+            &MetricsRegistry::default(),
+            &mut HandshakeTranscript::default(),
+            &mut HandshakeBudget::default(),
+        )
+        .await;
+        let took = started_at.elapsed();
+
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [1, u8::from(result.is_err())]);
+
+        (result.is_ok(), took)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn delays_failed_auth_response() {
+        let config = Config {
+            auth_failure_delay: Duration::from_millis(100),
+            ..Config::default()
+        };
+
+        let (accepted, took) =
+            time_user_pass_auth(&user_pass_auth("alice", "wrong"), &config).await;
+        assert!(!accepted);
+        assert!(took >= Duration::from_millis(100));
+        assert!(took < Duration::from_millis(110));
+
+        let (accepted, took) = time_user_pass_auth(&user_pass_auth("mallory", "x"), &config).await;
+        assert!(!accepted);
+        assert!(took >= Duration::from_millis(100));
+        assert!(took < Duration::from_millis(110));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn answers_successful_auth_right_away() {
+        let config = Config {
+            auth_failure_delay: Duration::from_millis(100),
+            ..Config::default()
+        };
+
+        let (accepted, took) =
+            time_user_pass_auth(&user_pass_auth("alice", "secret"), &config).await;
+
+        assert!(accepted);
+        assert!(took < Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn reads_maximal_domain_request_without_consuming_what_follows() {
+        let domain = "a".repeat(255);
+        let mut bytes = domain_request(RequestCommand::Connect, &domain, 443);
+        bytes.extend_from_slice(b"GET /");
+        let mut stream = &bytes[..];
+
+        let request = read_client_request(&mut stream, &mut HandshakeBudget::default())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            request.destination_addr,
+            DestinationAddress::DomainName(domain)
+        );
+        assert_eq!(request.destination_port, 443);
+        assert_eq!(stream, b"GET /");
+    }
+
+    #[tokio::test]
+    async fn reads_hello_split_into_single_bytes() {
+        let (mut client, mut server) = io::duplex(1);
+        tokio::spawn(async move {
+            client
+                .write_all(&[SOCKS_VERSION, 2, AuthMethod::NoAuth as u8, 0x80])
+                .await
+                .unwrap();
+        });
+
+        let hello = read_client_hello(&mut server, &mut HandshakeBudget::default())
+            .await
+            .unwrap();
+
+        assert_eq!(hello.methods, vec![AuthMethod::NoAuth]);
+        assert_eq!(hello.unknown_methods, vec![0x80]);
+    }
+
+    #[tokio::test]
+    async fn reads_user_pass_auth_split_into_single_bytes() {
+        let (mut client, mut server) = io::duplex(1);
+        tokio::spawn(async move {
+            let mut bytes = user_pass_auth("alice", "secret");
+            bytes.extend_from_slice(b"rest");
+            client.write_all(&bytes).await.unwrap();
+        });
+
+        let raw_packet = read_user_pass_auth(&mut server).await.unwrap();
+        let auth = ClientUserPassAuth::new(&raw_packet).unwrap();
+
+        assert_eq!(auth.username, "alice");
+        assert_eq!(auth.password, "secret");
+        assert_eq!(auth.len, raw_packet.len());
+        let mut rest = [0; 4];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b"rest");
+    }
+
+    #[tokio::test]
+    async fn leaves_pipelined_request_after_hello() {
+        let mut bytes = vec![SOCKS_VERSION, 1, AuthMethod::NoAuth as u8];
+        bytes.extend_from_slice(&request(
+            RequestCommand::Connect,
+            "127.0.0.1:80".parse().unwrap(),
+        ));
+        let mut stream = &bytes[..];
+        let mut budget = HandshakeBudget::default();
+
+        read_client_hello(&mut stream, &mut budget).await.unwrap();
+        let request = read_client_request(&mut stream, &mut budget).await.unwrap();
+
+        assert_eq!(request.destination_port, 80);
+        assert!(stream.is_empty());
+        assert_eq!(budget.packets, 2);
+        assert_eq!(budget.bytes, bytes.len());
+    }
+
+    #[tokio::test]
+    async fn recognizes_http_request_from_method_alone() {
+        let mut stream: &[u8] = b"OPTIONS ";
+
+        let result = read_client_hello(&mut stream, &mut HandshakeBudget::default()).await;
+
+        assert!(matches!(
+            result,
+            Err(ClientHelloError::UnexpectedHttpRequest)
+        ));
+    }
+
+    #[test]
+    fn handshake_budget_rejects_extra_packets() {
+        let mut budget = HandshakeBudget::default();
+        for _ in 0..MAX_HANDSHAKE_PACKETS {
+            budget.spend(3).unwrap();
+        }
+
+        assert!(matches!(
+            budget.spend(3),
+            Err(HandshakeBudgetError::TooManyPackets(MAX_HANDSHAKE_PACKETS))
+        ));
+    }
+
+    #[test]
+    fn handshake_budget_rejects_extra_bytes() {
+        let mut budget = HandshakeBudget::default();
+        budget.spend(MAX_HANDSHAKE_BYTES).unwrap();
+
+        assert!(matches!(
+            budget.spend(1),
+            Err(HandshakeBudgetError::TooManyBytes(MAX_HANDSHAKE_BYTES))
+        ));
+    }
+
+    #[tokio::test]
+    async fn serves_fully_pipelined_handshake() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = SocksServer::with_config(user_pass_settings(), Config::default())
+            .unwrap()
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let mut bytes = vec![SOCKS_VERSION, 1, AuthMethod::UserPassword as u8];
+        bytes.extend_from_slice(&user_pass_auth("alice", "secret"));
+        bytes.extend_from_slice(&request(
+            RequestCommand::Connect,
+            destination.local_addr().unwrap(),
+        ));
+        bytes.extend_from_slice(b"ping");
+        client.write_all(&bytes).await.unwrap();
+
+        let mut answers = [0; 4];
+        client.read_exact(&mut answers).await.unwrap();
+        assert_eq!(
+            answers,
+            [SOCKS_VERSION, AuthMethod::UserPassword as u8, 1, 0]
+        );
+        let (reply, _) = read_reply(&mut client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        let mut received = [0; 4];
+        remote_conn.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn closes_connection_sending_extra_hellos() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        let hello = [SOCKS_VERSION, 1, AuthMethod::NoAuth as u8];
+        client.write_all(&hello.repeat(3)).await.unwrap();
+
+        // The second hello is read as a request, which the server turns down.
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+        assert_eq!(server_hello, [SOCKS_VERSION, AuthMethod::NoAuth as u8]);
+        let (reply, _) = read_reply(&mut client).await;
+        assert_ne!(reply, Reply::Succeeded as u8);
+        assert!(
+            time::timeout(Duration::from_millis(100), destination.accept())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_garbage_hello_and_closes_without_answering() {
+        let handle = spawn_server(Config {
+            log_malformed_hellos: false,
+            ..Config::default()
+        })
+        .await;
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        // A SOCKS4 CONNECT header.
+        client.write_all(&[4, 1]).await.unwrap();
+
+        assert!(read_until_closed(&mut client).await.is_empty());
+        assert_eq!(handle.metrics().malformed_hellos, 1);
+    }
+
+    #[tokio::test]
+    async fn reads_no_auth_request_without_an_auth_stage() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let state = server_state(Config::default());
+        let (mut client, mut server) = io::duplex(1024);
+        // Sent before the server hello is in, so the request is the very next thing after the
+        // hello. An auth read would take it for a user/password packet of the wrong version.
+        let mut bytes = vec![SOCKS_VERSION, 1, AuthMethod::NoAuth as u8];
+        bytes.extend_from_slice(&request(
+            RequestCommand::Connect,
+            destination.local_addr().unwrap(),
+        ));
+        client.write_all(&bytes).await.unwrap();
+
+        let mut record = connection_record();
+        let negotiated = negotiate(
+            &mut server,
+            "127.0.0.1:4242".parse().unwrap(),
+            "127.0.0.1:1080".parse().unwrap(),
+            &state,
+            &mut record,
+        )
+        .await;
+
+        assert!(matches!(negotiated, Some(Negotiated::Relay(_))));
+        let mut answers = [0; 2 + 10];
+        client.read_exact(&mut answers).await.unwrap();
+        assert_eq!(answers[..2], [SOCKS_VERSION, AuthMethod::NoAuth as u8]);
+        assert_eq!(answers[3], Reply::Succeeded as u8);
+        assert!(record.timings.auth.is_none());
+        assert!(record.stats.handshake.username.is_none());
+    }
+
+    #[derive(Debug, Default)]
+    struct RecordingSink(Mutex<Vec<MetricEvent>>);
+
+    impl MetricsSink for RecordingSink {
+        fn record(&self, event: MetricEvent) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn pushes_metric_events_of_a_connection() {
+        let sink = Arc::new(RecordingSink::default());
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config {
+            metrics_sink: Some(sink.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        let (mut client, reply, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        remote_conn.read_exact(&mut received).await.unwrap();
+        remote_conn.write_all(b"pong!").await.unwrap();
+        let mut received = [0; 5];
+        client.read_exact(&mut received).await.unwrap();
+        drop(client);
+        drop(remote_conn);
+
+        time::timeout(Duration::from_secs(5), async {
+            while !sink
+                .0
+                .lock()
+                .unwrap()
+                .contains(&MetricEvent::ConnectionClosed)
+            {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection was not closed");
+        assert_eq!(
+            *sink.0.lock().unwrap(),
+            [
+                MetricEvent::ConnectionAccepted,
+                MetricEvent::BytesRelayed {
+                    client_to_remote: true,
+                    bytes: 4,
+                },
+                MetricEvent::BytesRelayed {
+                    client_to_remote: false,
+                    bytes: 5,
+                },
+                MetricEvent::ConnectionClosed,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn pushes_denied_requests() {
+        let sink = Arc::new(RecordingSink::default());
+        let handle = spawn_server(Config {
+            metrics_sink: Some(sink.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        let (_client, reply, _) = socks_request(
+            handle.local_addr(),
+            RequestCommand::Bind,
+            "127.0.0.1:80".parse().unwrap(),
+        )
+        .await;
+
+        assert_eq!(reply, Reply::CmdNotSupported as u8);
+        assert!(sink
+            .0
+            .lock()
+            .unwrap()
+            .contains(&MetricEvent::RequestDenied {
+                reply: Reply::CmdNotSupported,
+            }));
+    }
+
+    #[tokio::test]
+    async fn survives_client_gone_before_error_reply() {
+        let state = server_state(Config::default());
+        let (client, mut server) = io::duplex(64);
+        drop(client);
+
+        let reply =
+            handle_client_request_error(&mut server, ClientRequestError::ErrUnknownCommand, &state)
+                .await;
+        assert_eq!(reply, Reply::CmdNotSupported);
+
+        let reply =
+            handle_server_reply_error(&mut server, ServerReplyError::BindTimeout, &state).await;
+        assert_eq!(reply, Reply::TTLExpired);
+    }
+
+    // Hands out connections opened ahead of time, and counts the ones given back.
+    #[derive(Debug, Default)]
+    struct PoolingConnector {
+        warm: Mutex<HashMap<SocketAddr, Vec<TcpStream>>>,
+        released: AtomicU64,
+    }
+
+    impl PoolingConnector {
+        async fn warm_up(&self, addr: SocketAddr) {
+            let conn = TcpStream::connect(addr).await.unwrap();
+            self.warm
+                .lock()
+                .unwrap()
+                .entry(addr)
+                .or_default()
+                .push(conn);
+        }
+    }
+
+    impl Connector for PoolingConnector {
+        fn connect(&self, addr: SocketAddr) -> ConnectFuture<'_> {
+            let warm = self
+                .warm
+                .lock()
+                .unwrap()
+                .get_mut(&addr)
+                .and_then(|conns| conns.pop());
+            Box::pin(async move {
+                match warm {
+                    Some(conn) => Ok(conn),
+                    None => TcpStream::connect(addr).await,
+                }
+            })
+        }
+
+        fn release(&self, _addr: SocketAddr) {
+            self.released.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn relays_over_connection_from_connector_pool() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let connector = Arc::new(PoolingConnector::default());
+        connector.warm_up(destination_addr).await;
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        let handle = spawn_server(Config {
+            connector: Some(Arc::clone(&connector) as Arc<dyn Connector>),
+            ..Config::default()
+        })
+        .await;
+
+        let (mut client, reply, _) = socks_connect(handle.local_addr(), destination_addr).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert!(connector.warm.lock().unwrap()[&destination_addr].is_empty());
+        // Data reaches the connection warmed up before the request was made.
+        client.write_all(b"ping").await.unwrap();
+        let mut ping = [0; 4];
+        remote_conn.read_exact(&mut ping).await.unwrap();
+        assert_eq!(&ping, b"ping");
+        assert_eq!(connector.released.load(Ordering::Relaxed), 0);
+
+        drop(client);
+        drop(remote_conn);
+        wait_for_active_connections(&handle, 0).await;
+        assert_eq!(connector.released.load(Ordering::Relaxed), 1);
+    }
+
+    // Resolves every name to the same addresses, in order.
+    #[derive(Debug)]
+    struct StaticResolver(Vec<IpAddr>);
+
+    impl Resolver for StaticResolver {
+        fn resolve<'a>(&'a self, _host: &'a str, port: u16) -> ResolveFuture<'a> {
+            let addrs = self.0.iter().map(|&ip| SocketAddr::new(ip, port)).collect();
+            Box::pin(future::ready(Ok(addrs)))
+        }
+    }
+
+    #[tokio::test]
+    async fn never_connects_to_blocked_resolved_address() {
+        let allowed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = allowed.local_addr().unwrap().port();
+        let blocked = TcpListener::bind(("127.0.0.2", port)).await.unwrap();
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(vec![
+                Ipv4Addr::new(127, 0, 0, 2).into(),
+                Ipv4Addr::LOCALHOST.into(),
+            ])),
+            access_control: Some(AccessControl::parse("deny 127.0.0.2")),
+            ..Config::default()
+        })
+        .await;
+
+        let request = domain_request(RequestCommand::Connect, "rebind.example", port);
+        let (_client, reply, _) = send_request(handle.local_addr(), &request).await;
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+        allowed.accept().await.unwrap();
+        let blocked_accept = time::timeout(Duration::from_millis(100), blocked.accept()).await;
+        assert!(blocked_accept.is_err(), "connected to the blocked address");
+    }
+
+    #[tokio::test]
+    async fn only_tries_max_resolved_addrs() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = destination.local_addr().unwrap().port();
+        // Nothing listens on the first two, so only the third address would work.
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(vec![
+                Ipv4Addr::new(127, 0, 0, 3).into(),
+                Ipv4Addr::new(127, 0, 0, 4).into(),
+                Ipv4Addr::LOCALHOST.into(),
+            ])),
+            max_resolved_addrs: 2,
+            ..Config::default()
+        })
+        .await;
+
+        let request = domain_request(RequestCommand::Connect, "many.example", port);
+        let (_client, reply, _) = send_request(handle.local_addr(), &request).await;
+
+        assert_eq!(reply, Reply::ConnRefused as u8);
+    }
+
+    #[tokio::test]
+    async fn records_reset_from_destination() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+
+        let (mut client, _, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        let (remote_conn, _) = destination.accept().await.unwrap();
+        SockRef::from(&remote_conn)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(remote_conn);
+        // The reset is passed on to the client as one.
+        let mut received = Vec::new();
+        let result = client.read_to_end(&mut received).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+
+        let stats = loop {
+            if let ConnectionEvent::Closed { stats, .. } = events.recv().await.unwrap() {
+                break stats;
+            }
+        };
+        let reset = CloseReason::Error(io::ErrorKind::ConnectionReset);
+        assert_eq!(stats.close_reason, Some(reset));
+        assert_eq!(
+            stats.directions.unwrap().remote_to_client.end,
+            DirectionEnd::Error(io::ErrorKind::ConnectionReset)
+        );
+        assert_eq!(handle.metrics().relay_errors["ConnectionReset"], 1);
+    }
+
+    #[tokio::test]
+    async fn advertises_public_udp_address_while_binding_locally() {
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let public_ip: IpAddr = "198.51.100.7".parse().unwrap();
+        let handle = spawn_server(Config {
+            allow_udp_associate: true,
+            udp_public_addr: Some(public_ip),
+            ..Config::default()
+        })
+        .await;
+
+        let (_control, reply, advertised_addr) = socks_request(
+            handle.local_addr(),
+            RequestCommand::UdpAssociate,
+            "0.0.0.0:0".parse().unwrap(),
+        )
+        .await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(advertised_addr.ip(), public_ip);
+
+        // The relay listens on the advertised port, on the address the client connected to.
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), advertised_addr.port());
+        let datagram = UdpHeader::wrap(destination.local_addr().unwrap(), b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+        let mut buf = [0; 64];
+        let (n, _) = time::timeout(Duration::from_secs(5), destination.recv_from(&mut buf))
+            .await
+            .expect("datagram was not relayed")
+            .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+    }
+
+    #[tokio::test]
+    async fn advertises_each_associations_own_port_with_public_address() {
+        let public_ip: IpAddr = "198.51.100.7".parse().unwrap();
+        let handle = spawn_server(Config {
+            allow_udp_associate: true,
+            udp_public_addr: Some(public_ip),
+            ..Config::default()
+        })
+        .await;
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let mut advertised_ports = Vec::new();
+        let mut controls = Vec::new();
+        for payload in [b"one", b"two"] {
+            let (control, reply, advertised_addr) = socks_request(
+                handle.local_addr(),
+                RequestCommand::UdpAssociate,
+                "0.0.0.0:0".parse().unwrap(),
+            )
+            .await;
+            assert_eq!(reply, Reply::Succeeded as u8);
+            assert_eq!(advertised_addr.ip(), public_ip);
+            controls.push(control);
+            advertised_ports.push(advertised_addr.port());
+
+            // Each association listens locally on exactly the port it advertised.
+            let relay_addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), advertised_addr.port());
+            let datagram = UdpHeader::wrap(destination.local_addr().unwrap(), payload);
+            client.send_to(&datagram, relay_addr).await.unwrap();
+            assert_eq!(recv_datagram(&destination).await, payload);
+        }
+        assert_ne!(advertised_ports[0], advertised_ports[1]);
+    }
+
+    // A UDP header naming `domain` as the destination, in front of `payload`.
+    fn domain_datagram(domain: &str, port: u16, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0, 0, 0, AddressType::DomainName as u8, domain.len() as u8];
+        datagram.extend_from_slice(domain.as_bytes());
+        datagram.extend_from_slice(&port.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    // Opens a UDP association on a server started with `config`, returning the control
+    // connection, a client socket and the address to send datagrams to.
+    async fn udp_associate(config: Config) -> (ServerHandle, TcpStream, UdpSocket, SocketAddr) {
+        let handle = spawn_server(Config {
+            allow_udp_associate: true,
+            ..config
+        })
+        .await;
+        let (control, reply, relay_addr) = socks_request(
+            handle.local_addr(),
+            RequestCommand::UdpAssociate,
+            "0.0.0.0:0".parse().unwrap(),
+        )
+        .await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        (handle, control, client, relay_addr)
+    }
+
+    async fn recv_datagram(socket: &UdpSocket) -> Vec<u8> {
+        let mut buf = [0; 64];
+        let (n, _) = time::timeout(Duration::from_secs(5), socket.recv_from(&mut buf))
+            .await
+            .expect("datagram was not relayed")
+            .unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[tokio::test]
+    async fn relays_datagrams_while_a_name_resolves() {
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_handle, _control, client, relay_addr) = udp_associate(Config {
+            resolver: Arc::new(HangingResolver),
+            ..Config::default()
+        })
+        .await;
+
+        let slow = domain_datagram("slow.example", 53, b"lost");
+        client.send_to(&slow, relay_addr).await.unwrap();
+        let datagram = UdpHeader::wrap(destination.local_addr().unwrap(), b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        assert_eq!(recv_datagram(&destination).await, b"ping");
+    }
+
+    #[tokio::test]
+    async fn relays_datagram_to_resolved_name() {
+        let destination = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (_handle, _control, client, relay_addr) = udp_associate(Config {
+            resolver: Arc::new(StaticResolver(vec![Ipv4Addr::LOCALHOST.into()])),
+            ..Config::default()
+        })
+        .await;
+
+        let port = destination.local_addr().unwrap().port();
+        let datagram = domain_datagram("example.com", port, b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+
+        assert_eq!(recv_datagram(&destination).await, b"ping");
+    }
+
+    // Sends a datagram to a destination bound on `destination_ip` through an association and
+    // has it answer, returning the destination's address along with the datagram the client
+    // got back.
+    async fn relay_datagram_and_reply(destination_ip: &str) -> (SocketAddr, Vec<u8>) {
+        let destination = UdpSocket::bind((destination_ip, 0)).await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let (_handle, _control, client, relay_addr) = udp_associate(Config::default()).await;
+
+        let datagram = UdpHeader::wrap(destination_addr, b"ping");
+        client.send_to(&datagram, relay_addr).await.unwrap();
+        let mut buf = [0; 64];
+        let (n, outbound_addr) =
+            time::timeout(Duration::from_secs(5), destination.recv_from(&mut buf))
+                .await
+                .expect("datagram was not relayed")
+                .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        destination.send_to(b"pong", outbound_addr).await.unwrap();
+
+        (destination_addr, recv_datagram(&client).await)
+    }
+
+    #[tokio::test]
+    async fn wraps_reply_from_ipv4_source_in_udp_header() {
+        let (source, datagram) = relay_datagram_and_reply("127.0.0.1").await;
+
+        let mut expected = vec![0, 0, 0, AddressType::Ipv4 as u8, 127, 0, 0, 1];
+        expected.extend_from_slice(&source.port().to_be_bytes());
+        expected.extend_from_slice(b"pong");
+        assert_eq!(datagram, expected);
+    }
+
+    #[tokio::test]
+    async fn wraps_reply_from_ipv6_source_in_udp_header() {
+        let (source, datagram) = relay_datagram_and_reply("::1").await;
+
+        let mut expected = vec![0, 0, 0, AddressType::Ipv6 as u8];
+        expected.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        expected.extend_from_slice(&source.port().to_be_bytes());
+        expected.extend_from_slice(b"pong");
+        assert_eq!(datagram, expected);
+    }
+
+    #[tokio::test]
+    async fn sends_configured_reply_version() {
+        let mut written = Vec::new();
+        let state = server_state(Config::default());
+        handle_client_request_error(&mut written, ClientRequestError::ErrUnknownCommand, &state)
+            .await;
+        assert_eq!(written[..2], [SOCKS_VERSION, Reply::CmdNotSupported as u8]);
+
+        let mut written = Vec::new();
+        let state = server_state(Config {
+            reply_version: 0,
+            ..Config::default()
+        });
+        handle_client_request_error(&mut written, ClientRequestError::ErrUnknownCommand, &state)
+            .await;
+        assert_eq!(written[..2], [0, Reply::CmdNotSupported as u8]);
+    }
+
+    #[tokio::test]
+    async fn spells_out_method_mismatch() {
+        let client_hello = ClientHello::new(&[SOCKS_VERSION, 2, 0, 0x80]).unwrap();
+        let (mut client, mut server) = io::duplex(64);
+
+        let result = send_server_hello(&mut server, client_hello, &user_pass_settings()).await;
+
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+        assert_eq!(
+            server_hello,
+            [SOCKS_VERSION, AuthMethod::NoAcceptableMethod as u8]
+        );
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "no authentication method is acceptable: client offered [NoAuth, 0x80], server \
+             accepts [UserPassword]"
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_to_connect_to_itself() {
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(vec![Ipv4Addr::LOCALHOST.into()])),
+            ..Config::default()
+        })
+        .await;
+        let server = handle.local_addr();
+
+        let (_client, reply, _) = socks_connect(server, server).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+
+        let request = domain_request(RequestCommand::Connect, "proxy.example", server.port());
+        let (_client, reply, _) = send_request(server, &request).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+    }
+
+    #[test]
+    fn treats_local_addresses_as_own_for_unspecified_listener() {
+        let state = server_state(Config::default());
+        state
+            .listen_addrs
+            .lock()
+            .unwrap()
+            .push("0.0.0.0:1080".parse().unwrap());
+        let client_local_addr = "192.0.2.1:1080".parse().unwrap();
+
+        for own in [
+            "127.0.0.1:1080",
+            "0.0.0.0:1080",
+            "192.0.2.1:1080",
+            "[::1]:1080",
+        ] {
+            assert!(state.is_own_listen_addr(own.parse().unwrap(), client_local_addr));
+        }
+        for other in ["127.0.0.1:1081", "192.0.2.2:1080", "198.51.100.1:1080"] {
+            assert!(!state.is_own_listen_addr(other.parse().unwrap(), client_local_addr));
+        }
+    }
+
+    async fn wait_for_queued_connections(handle: &ServerHandle, count: u64) {
+        time::timeout(Duration::from_secs(5), async {
+            while handle.metrics().queued_connections < count {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connections were not queued");
+    }
+
+    // Whether the server answers the hello already sent on `client` within `wait`.
+    async fn hello_answered(client: &mut TcpStream, wait: Duration) -> bool {
+        let mut server_hello = [0; 2];
+        time::timeout(wait, client.read_exact(&mut server_hello))
+            .await
+            .is_ok_and(|read| read.is_ok())
+    }
+
+    #[tokio::test]
+    async fn serves_queued_connections_in_arrival_order() {
+        let handle = spawn_server(Config {
+            max_connections: Some(1),
+            ..Config::default()
+        })
+        .await;
+        let hello = [SOCKS_VERSION, 1, AuthMethod::NoAuth as u8];
+
+        let first = say_hello(handle.local_addr(), &[]).await;
+        let mut second = TcpStream::connect(handle.local_addr()).await.unwrap();
+        second.write_all(&hello).await.unwrap();
+        wait_for_queued_connections(&handle, 1).await;
+        let mut third = TcpStream::connect(handle.local_addr()).await.unwrap();
+        third.write_all(&hello).await.unwrap();
+        wait_for_queued_connections(&handle, 2).await;
+
+        drop(first);
+        assert!(hello_answered(&mut second, Duration::from_secs(5)).await);
+        assert!(!hello_answered(&mut third, Duration::from_millis(100)).await);
+
+        drop(second);
+        assert!(hello_answered(&mut third, Duration::from_secs(5)).await);
+    }
+
+    #[tokio::test]
+    async fn fails_connection_queued_for_too_long() {
+        let handle = spawn_server(Config {
+            max_connections: Some(1),
+            queue_wait_timeout: Duration::from_millis(50),
+            ..Config::default()
+        })
+        .await;
+
+        let _first = say_hello(handle.local_addr(), &[]).await;
+        let mut second = TcpStream::connect(handle.local_addr()).await.unwrap();
+
+        let (reply, _) = read_reply(&mut second).await;
+        assert_eq!(reply, Reply::SocksServerFail as u8);
+        assert_eq!(handle.metrics().queued_connections, 0);
+    }
+
+    #[tokio::test]
+    async fn controls_spawned_server_through_handle_clones() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+        let observer = handle.clone();
+
+        let (client, reply, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (remote_conn, _) = destination.accept().await.unwrap();
+        assert_eq!(observer.metrics().total_connections, 1);
+        assert_eq!(observer.active_connections(), 1);
+
+        observer.shutdown();
+        drop(client);
+        drop(remote_conn);
+        time::timeout(Duration::from_secs(5), handle.stopped())
+            .await
+            .expect("server did not stop");
+
+        assert_eq!(handle.active_connections(), 0);
+        assert!(TcpStream::connect(handle.local_addr()).await.is_err());
+    }
+
+    // Reads a request whose bytes arrive one at a time, as from a very slow client.
+    async fn read_request_byte_by_byte(bytes: Vec<u8>) -> ClientRequest {
+        let (mut client, mut server) = io::duplex(1);
+        tokio::spawn(async move { client.write_all(&bytes).await });
+
+        read_client_request(&mut server, &mut HandshakeBudget::default())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_requests_split_into_single_bytes() {
+        for destination in ["192.0.2.1:80", "[2001:db8::1]:443"] {
+            let destination: SocketAddr = destination.parse().unwrap();
+            let request =
+                read_request_byte_by_byte(request(RequestCommand::Connect, destination)).await;
+            let addr = match request.destination_addr {
+                DestinationAddress::Ipv4(v4_addr) => IpAddr::V4(v4_addr),
+                DestinationAddress::Ipv6(v6_addr) => IpAddr::V6(v6_addr),
+                DestinationAddress::DomainName(_) => panic!("expected an IP address"),
+            };
+            assert_eq!(SocketAddr::new(addr, request.destination_port), destination);
+        }
+
+        let request =
+            read_request_byte_by_byte(domain_request(RequestCommand::Connect, "example.com", 443))
+                .await;
+        assert_eq!(
+            request.destination_addr,
+            DestinationAddress::DomainName("example.com".to_string())
+        );
+        assert_eq!(request.destination_port, 443);
+    }
+
+    #[tokio::test]
+    async fn limits_connections_per_destination() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = target.local_addr().unwrap();
+        let handle = spawn_server(Config {
+            max_connections_per_destination: Some(1),
+            resolver: Arc::new(StaticResolver(vec![destination.ip()])),
+            ..Config::default()
+        })
+        .await;
+        let server = handle.local_addr();
+
+        let (_first, reply, _) = socks_connect(server, destination).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (_second, reply, _) = socks_connect(server, destination).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+
+        // Domain requests count against the address they resolve to.
+        let request = domain_request(
+            RequestCommand::Connect,
+            "target.example",
+            destination.port(),
+        );
+        let (_third, reply, _) = send_request(server, &request).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+    }
+
+    // Connects to 127.0.0.1, refuses 127.0.0.2 after `refusal_delay` and never finishes
+    // connecting to any other address, recording each attempt as it starts and fails.
+    #[derive(Debug, Default)]
+    struct ScriptedConnector {
+        refusal_delay: Duration,
+        attempts: Mutex<Vec<String>>,
+    }
+
+    impl ScriptedConnector {
+        fn record(&self, event: String) {
+            self.attempts.lock().unwrap().push(event);
+        }
+    }
+
+    impl Connector for ScriptedConnector {
+        fn connect(&self, addr: SocketAddr) -> ConnectFuture<'_> {
+            Box::pin(async move {
+                self.record(format!("start {}", addr.ip()));
+                if addr.ip() == Ipv4Addr::LOCALHOST {
+                    TcpStream::connect(addr).await
+                } else if addr.ip() == Ipv4Addr::new(127, 0, 0, 2) {
+                    time::sleep(self.refusal_delay).await;
+                    self.record(format!("fail {}", addr.ip()));
+                    Err(io::ErrorKind::ConnectionRefused.into())
+                } else {
+                    future::pending().await
+                }
+            })
+        }
+    }
+
+    // Connects to a domain resolving to `addrs`, of which only 127.0.0.1 accepts connections,
+    // returning the reply code and the connection attempts made.
+    async fn connect_through_scripted_connector(
+        addrs: &[Ipv4Addr],
+        refusal_delay: Duration,
+        connect_attempt_delay: Option<Duration>,
+    ) -> (u8, Vec<String>) {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let connector = Arc::new(ScriptedConnector {
+            refusal_delay,
+            ..ScriptedConnector::default()
+        });
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(addrs.iter().map(|&a| a.into()).collect())),
+            connector: Some(connector.clone()),
+            connect_attempt_delay,
+            ..Config::default()
+        })
+        .await;
+
+        let request = domain_request(
+            RequestCommand::Connect,
+            "target.example",
+            target.local_addr().unwrap().port(),
+        );
+        let (_client, reply, _) = time::timeout(
+            Duration::from_secs(5),
+            send_request(handle.local_addr(), &request),
+        )
+        .await
+        .expect("no reply to the request");
+        let attempts = connector.attempts.lock().unwrap().clone();
+
+        (reply, attempts)
+    }
+
+    #[tokio::test]
+    async fn tries_resolved_addresses_one_after_the_other() {
+        let (reply, attempts) = connect_through_scripted_connector(
+            &[Ipv4Addr::new(127, 0, 0, 2), Ipv4Addr::LOCALHOST],
+            Duration::from_millis(100),
+            None,
+        )
+        .await;
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(
+            attempts,
+            ["start 127.0.0.2", "fail 127.0.0.2", "start 127.0.0.1"]
+        );
+    }
+
+    #[tokio::test]
+    async fn staggers_attempts_past_an_address_that_hangs() {
+        let (reply, attempts) = connect_through_scripted_connector(
+            &[
+                Ipv4Addr::new(127, 0, 0, 3),
+                Ipv4Addr::new(127, 0, 0, 2),
+                Ipv4Addr::LOCALHOST,
+            ],
+            Duration::ZERO,
+            Some(Duration::from_millis(50)),
+        )
+        .await;
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+        // The failed attempt starts the next one right away, without waiting out the delay.
+        assert_eq!(
+            attempts,
+            [
+                "start 127.0.0.3",
+                "start 127.0.0.2",
+                "fail 127.0.0.2",
+                "start 127.0.0.1"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_address_a_domain_request_connected_to() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(vec![target_addr.ip()])),
+            ..Config::default()
+        })
+        .await;
+        let mut events = handle.subscribe();
+
+        let request = domain_request(
+            RequestCommand::Connect,
+            "target.example",
+            target_addr.port(),
+        );
+        let (_client, reply, _) = send_request(handle.local_addr(), &request).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+
+        loop {
+            if let ConnectionEvent::RelayStarted {
+                destination_addr,
+                destination_port,
+                remote_addr,
+                ..
+            } = events.recv().await.unwrap()
+            {
+                assert_eq!(
+                    destination_addr,
+                    DestinationAddress::DomainName("target.example".to_string())
+                );
+                assert_eq!(destination_port, target_addr.port());
+                assert_eq!(remote_addr, target_addr);
+                break;
+            }
+        }
+    }
+
+    async fn closed_stats(events: &mut broadcast::Receiver<ConnectionEvent>) -> ConnectionStats {
+        loop {
+            if let ConnectionEvent::Closed { stats, .. } = events.recv().await.unwrap() {
+                return stats;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn records_command_of_every_request_read() {
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+
+        let client = say_hello(handle.local_addr(), &[]).await;
+        drop(client);
+        assert_eq!(closed_stats(&mut events).await.command, None);
+
+        let unreachable = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination = unreachable.local_addr().unwrap();
+        drop(unreachable);
+        let (_client, reply, _) = socks_connect(handle.local_addr(), destination).await;
+        assert_eq!(reply, Reply::ConnRefused as u8);
+        assert_eq!(
+            closed_stats(&mut events).await.command,
+            Some(RequestCommand::Connect)
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_health_check_without_connecting_out() {
+        let connector = Arc::new(ScriptedConnector::default());
+        let handle = spawn_server(Config {
+            health_check_domain: Some("ping.socks.local".to_string()),
+            resolver: Arc::new(HangingResolver),
+            connector: Some(connector.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        let request = domain_request(RequestCommand::Connect, "PING.socks.local", 0);
+        let (mut client, reply, bound_addr) = send_request(handle.local_addr(), &request).await;
+
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(bound_addr, SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)));
+        assert!(read_until_closed(&mut client).await.is_empty());
+        assert!(connector.attempts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_exactly_the_methods_nmethods_announces() {
+        let mut fewer_announced: &[u8] = &[SOCKS_VERSION, 1, 0, 2];
+        let hello = read_client_hello(&mut fewer_announced, &mut HandshakeBudget::default())
+            .await
+            .unwrap();
+        assert_eq!(hello.methods, vec![AuthMethod::NoAuth]);
+        assert_eq!(fewer_announced, [2]);
+
+        let mut more_announced: &[u8] = &[SOCKS_VERSION, 3, 0, 2];
+        let result = read_client_hello(&mut more_announced, &mut HandshakeBudget::default()).await;
+        assert!(matches!(
+            result,
+            Err(ClientHelloError::IoError(e)) if e.kind() == io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[tokio::test]
+    async fn reports_growing_relay_progress() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+        let (mut client, _, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        let id = loop {
+            if let ConnectionEvent::RelayStarted { id, .. } = events.recv().await.unwrap() {
+                break id;
+            }
+        };
+
+        let mut progress = time::timeout(Duration::from_secs(5), async {
+            loop {
+                match handle.relay_progress(id) {
+                    Some(progress) => break progress,
+                    None => time::sleep(Duration::from_millis(10)).await,
+                }
+            }
+        })
+        .await
+        .expect("relay did not start");
+        // Progress is only sent every so often, so keep data flowing until it has been twice.
+        let mut reported = Vec::new();
+        time::timeout(Duration::from_secs(5), async {
+            while reported.len() < 2 {
+                client.write_all(&[0; 10]).await.unwrap();
+                remote_conn.read_exact(&mut [0; 10]).await.unwrap();
+                let sent = progress.borrow_and_update().bytes_client_to_remote;
+                if sent > 0 && reported.last() != Some(&sent) {
+                    reported.push(sent);
+                }
+                time::sleep(Duration::from_millis(30)).await;
+            }
+        })
+        .await
+        .expect("relay progress was not reported");
+        assert!(reported[0] < reported[1]);
+
+        // The last update, sent as the relay ends, has the final byte counts.
+        drop(client);
+        drop(remote_conn);
+        let stats = closed_stats(&mut events).await;
+        assert_eq!(
+            *progress.borrow(),
+            RelayProgress {
+                bytes_client_to_remote: stats.bytes_client_to_remote,
+                bytes_remote_to_client: 0,
+            }
+        );
+        assert!(handle.relay_progress(id).is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_unspecified_destinations_without_connecting() {
+        let connector = Arc::new(ScriptedConnector::default());
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(vec![Ipv6Addr::UNSPECIFIED.into()])),
+            connector: Some(connector.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        for destination in ["0.0.0.0:80", "[::]:80", "[::ffff:0.0.0.0]:80"] {
+            let (_client, reply, _) =
+                socks_connect(handle.local_addr(), destination.parse().unwrap()).await;
+            assert_eq!(reply, Reply::ConnNotAllowed as u8, "{}", destination);
+        }
+        let request = domain_request(RequestCommand::Connect, "nowhere.example", 80);
+        let (_client, reply, _) = send_request(handle.local_addr(), &request).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+
+        assert!(connector.attempts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connects_to_unspecified_destination_when_allowed() {
+        let connector = Arc::new(ScriptedConnector::default());
+        let handle = spawn_server(Config {
+            allow_unspecified_destinations: true,
+            connector: Some(connector.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        // The scripted connector never finishes connecting there, so no reply comes.
+        let server = handle.local_addr();
+        let _request =
+            tokio::spawn(async move { socks_connect(server, "0.0.0.0:80".parse().unwrap()).await });
+        time::timeout(Duration::from_secs(5), async {
+            while connector.attempts.lock().unwrap().is_empty() {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("no connection attempt");
+        assert_eq!(*connector.attempts.lock().unwrap(), ["start 0.0.0.0"]);
+    }
+
+    #[tokio::test]
+    async fn passes_client_reset_on_to_destination() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+
+        let (client, _, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        SockRef::from(&client)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(client);
+
+        let mut received = Vec::new();
+        let result = time::timeout(
+            Duration::from_secs(5),
+            remote_conn.read_to_end(&mut received),
+        )
+        .await
+        .expect("destination connection was not closed");
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn samples_one_in_every_rate_accepted_connections() {
+        let state = server_state(Config::default());
+
+        let sampled = (0..100)
+            .filter(|_| state.sample_accepted_connection(10))
+            .count();
+        assert_eq!(sampled, 10);
+        assert!((0..5).all(|_| state.sample_accepted_connection(0)));
+        assert!((0..5).all(|_| state.sample_accepted_connection(1)));
+    }
+
+    #[tokio::test]
+    async fn counts_every_connection_when_sampling_accept_log() {
+        let handle = spawn_server(Config {
+            accept_log_sample_rate: 10,
+            ..Config::default()
+        })
+        .await;
+
+        for _ in 0..20 {
+            say_hello(handle.local_addr(), &[]).await;
+        }
+
+        assert_eq!(handle.metrics().total_connections, 20);
+    }
+
+    #[test]
+    fn rejects_contradictory_configs() {
+        let invalid = |config: Config| SocksServer::with_config(no_auth(), config).err();
+
+        assert!(matches!(
+            invalid(Config {
+                max_connections_per_ip: Some(0),
+                ..Config::default()
+            }),
+            Some(ConfigError::ZeroLimit("max_connections_per_ip"))
+        ));
+        assert!(matches!(
+            invalid(Config {
+                max_connections: Some(10),
+                max_connections_per_ip: Some(20),
+                ..Config::default()
+            }),
+            Some(ConfigError::PerIpLimitAboveTotal {
+                per_ip: 20,
+                total: 10
+            })
+        ));
+        assert!(matches!(
+            invalid(Config {
+                relay_chunk_size: 0,
+                ..Config::default()
+            }),
+            Some(ConfigError::ZeroRelayChunkSize)
+        ));
+        #[allow(clippy::reversed_empty_ranges)]
+        let empty_range = 2000..=1000;
+        assert!(matches!(
+            invalid(Config {
+                outbound_source_port_range: Some(empty_range),
+                ..Config::default()
+            }),
+            Some(ConfigError::EmptySourcePortRange(_))
+        ));
+        assert!(matches!(
+            invalid(Config {
+                connector: Some(Arc::new(SlowConnector {
+                    delay: Duration::ZERO
+                })),
+                outbound_bind_pool: vec![Ipv4Addr::LOCALHOST.into()],
+                ..Config::default()
+            }),
+            Some(ConfigError::IgnoredByConnector("outbound_bind_pool"))
+        ));
+        assert!(matches!(
+            invalid(Config {
+                check_tls_server_name: true,
+                ..Config::default()
+            }),
+            Some(ConfigError::MissingPrerequisite {
+                setting: "check_tls_server_name",
+                requires: "access_control"
+            })
+        ));
+
+        assert!(Config::default().validate().is_ok());
+    }
+
+    #[tokio::test]
+    async fn closes_connections_not_redirected_in_transparent_mode() {
+        let connector = Arc::new(ScriptedConnector::default());
+        let handle = spawn_server(Config {
+            transparent: true,
+            connector: Some(connector.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        // Without a redirect, there is either no original destination to read or it's the
+        // server itself. Either way the connection is closed without connecting anywhere.
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        assert!(read_until_closed(&mut client).await.is_empty());
+        assert!(connector.attempts.lock().unwrap().is_empty());
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[tokio::test]
+    async fn reads_own_address_or_nothing_as_original_destination_of_direct_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_conn, _) = listener.accept().await.unwrap();
+
+        if let Ok(addr) = original_destination(&server_conn) {
+            assert_eq!(addr, listener.local_addr().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_connect_to_port_zero_but_not_bind() {
+        let connector = Arc::new(ScriptedConnector::default());
+        let handle = spawn_server(Config {
+            allow_bind: true,
+            resolver: Arc::new(StaticResolver(vec![Ipv4Addr::LOCALHOST.into()])),
+            connector: Some(connector.clone()),
+            ..Config::default()
+        })
+        .await;
+        let server = handle.local_addr();
+
+        let (_client, reply, _) = socks_connect(server, "127.0.0.1:0".parse().unwrap()).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+        let request = domain_request(RequestCommand::Connect, "target.example", 0);
+        let (_client, reply, _) = send_request(server, &request).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+        assert!(connector.attempts.lock().unwrap().is_empty());
+
+        // For BIND, port 0 means the peer may connect from any port.
+        let (_client, reply, _) =
+            socks_request(server, RequestCommand::Bind, "127.0.0.1:0".parse().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+    }
+
+    #[tokio::test]
+    async fn counts_requests_per_address_type() {
+        let connector = Arc::new(ScriptedConnector::default());
+        let handle = spawn_server(Config {
+            resolver: Arc::new(StaticResolver(vec![Ipv4Addr::new(127, 0, 0, 2).into()])),
+            connector: Some(connector),
+            ..Config::default()
+        })
+        .await;
+        let server = handle.local_addr();
+
+        socks_connect(server, "127.0.0.2:80".parse().unwrap()).await;
+        socks_connect(server, "[::1]:0".parse().unwrap()).await;
+        socks_connect(server, "[::2]:0".parse().unwrap()).await;
+        send_request(
+            server,
+            &domain_request(RequestCommand::Connect, "target.example", 80),
+        )
+        .await;
+
+        assert_eq!(
+            handle.metrics().requests_per_address_type,
+            BTreeMap::from([
+                (AddressType::Ipv4, 1),
+                (AddressType::DomainName, 1),
+                (AddressType::Ipv6, 2),
+            ])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn times_out_waiting_for_credentials() {
+        let (_client, mut server) = io::duplex(1024);
+        let config = Config::default();
+
+        let started_at = Instant::now();
+        let result = handle_user_pass_auth(
+            &mut server,
+            "127.0.0.1:4242".parse().unwrap(),
+            &user_pass_settings(),
+            &config,
+            &MetricsRegistry::default(),
+            &mut HandshakeTranscript::default(),
+            &mut HandshakeBudget::default(),
+        )
+        .await;
+
+        assert!(matches!(
+            result,
+            Err(UserPassAuthError::Timeout(timeout)) if timeout == config.auth_timeout
+        ));
+        assert_eq!(started_at.elapsed(), config.auth_timeout);
+    }
+
+    #[tokio::test]
+    async fn closes_connection_never_sending_credentials() {
+        let handle = SocksServer::with_config(
+            user_pass_settings(),
+            Config {
+                auth_timeout: Duration::from_millis(100),
+                ..Config::default()
+            },
+        )
+        .unwrap()
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client
+            .write_all(&[SOCKS_VERSION, 1, AuthMethod::UserPassword as u8])
+            .await
+            .unwrap();
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+        assert_eq!(
+            server_hello,
+            [SOCKS_VERSION, AuthMethod::UserPassword as u8]
+        );
+
+        assert!(read_until_closed(&mut client).await.is_empty());
+    }
+
+    // Accepts a single login, or fails every check when it has none, counting its checks.
+    #[derive(Debug)]
+    struct TestAuthenticator {
+        login: Option<(&'static str, &'static str)>,
+        checks: AtomicU64,
+    }
+
+    impl TestAuthenticator {
+        fn new(login: Option<(&'static str, &'static str)>) -> Arc<Self> {
+            Arc::new(TestAuthenticator {
+                login,
+                checks: AtomicU64::new(0),
+            })
+        }
+
+        fn checks(&self) -> u64 {
+            self.checks.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Authenticator for TestAuthenticator {
+        fn authenticate<'a>(
+            &'a self,
+            username: &'a str,
+            password: &'a str,
+        ) -> AuthenticateFuture<'a> {
+            self.checks.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                match self.login {
+                    Some(login) => Ok(login == (username, password)),
+                    None => Err(io::Error::other("credential store unreachable")),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn tries_authenticators_in_order_until_one_accepts() {
+        let failing = TestAuthenticator::new(None);
+        let old_store = TestAuthenticator::new(Some(("carol", "old")));
+        let new_store = TestAuthenticator::new(Some(("bob", "hunter2")));
+        let last = TestAuthenticator::new(Some(("bob", "hunter2")));
+        let config = Config {
+            authenticators: vec![
+                failing.clone(),
+                old_store.clone(),
+                new_store.clone(),
+                last.clone(),
+            ],
+            ..Config::default()
+        };
+        let client_addr = "127.0.0.1:4242".parse().unwrap();
+        let auth_settings = user_pass_settings();
+        let check = |username, password| {
+            check_credentials(username, password, client_addr, &auth_settings, &config)
+        };
+
+        assert!(check("bob", "hunter2").await);
+        let checks = [&failing, &old_store, &new_store, &last].map(|a| a.checks());
+        assert_eq!(checks, [1, 1, 1, 0]);
+
+        // Static logins never reach the authenticators.
+        assert!(check("alice", "secret").await);
+        assert!(!check("bob", "wrong").await);
+        let checks = [&failing, &old_store, &new_store, &last].map(|a| a.checks());
+        assert_eq!(checks, [2, 2, 2, 1]);
+    }
+
+    // Fails every resolution, in the way the name's first label says.
+    #[derive(Debug)]
+    struct FailingResolver;
+
+    impl Resolver for FailingResolver {
+        fn resolve<'a>(&'a self, host: &'a str, _port: u16) -> ResolveFuture<'a> {
+            Box::pin(async move {
+                Err(match host.split('.').next() {
+                    Some("nxdomain") => ResolveError::NxDomain,
+                    Some("timeout") => ResolveError::Timeout,
+                    _ => ResolveError::Failed(io::Error::other("SERVFAIL")),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn answers_each_resolver_failure_with_its_own_reply() {
+        let handle = spawn_server(Config {
+            resolver: Arc::new(FailingResolver),
+            ..Config::default()
+        })
+        .await;
+
+        for (domain, expected) in [
+            ("nxdomain.example", Reply::HostUnreachable),
+            ("timeout.example", Reply::TTLExpired),
+            ("servfail.example", Reply::SocksServerFail),
+        ] {
+            let request = domain_request(RequestCommand::Connect, domain, 80);
+            let (_client, reply, _) = send_request(handle.local_addr(), &request).await;
+            assert_eq!(reply, expected as u8, "{}", domain);
+        }
+    }
+
+    // Authenticates with username/password over a new connection, returning whether the
+    // server accepted the credentials.
+    async fn log_in(server: SocketAddr, username: &str, password: &str) -> bool {
+        let mut client = TcpStream::connect(server).await.unwrap();
+        client
+            .write_all(&[SOCKS_VERSION, 1, AuthMethod::UserPassword as u8])
+            .await
+            .unwrap();
+        let mut server_hello = [0; 2];
+        client.read_exact(&mut server_hello).await.unwrap();
+        assert_eq!(
+            server_hello,
+            [SOCKS_VERSION, AuthMethod::UserPassword as u8]
+        );
+        client
+            .write_all(&user_pass_auth(username, password))
+            .await
+            .unwrap();
+        let mut response = [0; 2];
+        client.read_exact(&mut response).await.unwrap();
+
+        response == [1, 0]
+    }
+
+    #[tokio::test]
+    async fn authenticates_new_connections_against_reloaded_credentials() {
+        let handle = SocksServer::with_config(user_pass_settings(), Config::default())
+            .unwrap()
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = handle.local_addr();
+        assert!(log_in(server, "alice", "secret").await);
+        assert!(!log_in(server, "bob", "hunter2").await);
+
+        let reloaded = AuthSettings {
+            method: AuthMethod::UserPassword,
+            params: Some(AuthParams {
+                logins: HashMap::from([("bob".to_string(), "hunter2".to_string())]),
+            }),
+        };
+        handle.reload(reloaded, Config::default()).unwrap();
+
+        assert!(log_in(server, "bob", "hunter2").await);
+        assert!(!log_in(server, "alice", "secret").await);
+    }
+
+    #[tokio::test]
+    async fn records_how_each_direction_ended() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+
+        let (mut client, _, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        remote_conn.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        SockRef::from(&remote_conn)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+        drop(remote_conn);
+
+        let directions = closed_stats(&mut events).await.directions.unwrap();
+        assert_eq!(
+            directions,
+            DirectionalStats {
+                client_to_remote: DirectionStats {
+                    bytes: 5,
+                    end: DirectionEnd::Eof,
+                },
+                remote_to_client: DirectionStats {
+                    bytes: 0,
+                    end: DirectionEnd::Error(io::ErrorKind::ConnectionReset),
+                },
+                ended_first: RelayDirection::ClientToRemote,
+            }
+        );
+        assert_eq!(
+            directions.to_string(),
+            "client to remote: 5 bytes, EOF; remote to client: 0 bytes, connection reset; \
+             client to remote ended first"
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_connections_over_handshake_limit() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config {
+            max_handshakes_in_progress: Some(2),
+            ..Config::default()
+        })
+        .await;
+        let server = handle.local_addr();
+
+        // Two clients that take their time to send a hello use up the limit.
+        let mut slow_client = TcpStream::connect(server).await.unwrap();
+        let _other_slow_client = TcpStream::connect(server).await.unwrap();
+        time::timeout(Duration::from_secs(5), async {
+            while handle.metrics().handshakes_in_progress < 2 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("clients did not start negotiating");
+        let mut dropped = TcpStream::connect(server).await.unwrap();
+        assert!(read_until_closed(&mut dropped).await.is_empty());
+
+        // Established relays don't count against the limit.
+        slow_client
+            .write_all(&[SOCKS_VERSION, 1, AuthMethod::NoAuth as u8])
+            .await
+            .unwrap();
+        slow_client.read_exact(&mut [0; 2]).await.unwrap();
+        slow_client
+            .write_all(&request(
+                RequestCommand::Connect,
+                destination.local_addr().unwrap(),
+            ))
+            .await
+            .unwrap();
+        let (reply, _) = read_reply(&mut slow_client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        say_hello(server, &[]).await;
+        assert_eq!(handle.active_connections(), 3);
+    }
+
+    #[tokio::test]
+    async fn refuses_destination_resetting_right_after_accept() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let handle = spawn_server(Config {
+            destination_liveness_check: Some(Duration::from_millis(200)),
+            ..Config::default()
+        })
+        .await;
+
+        tokio::spawn(async move {
+            let (remote_conn, _) = destination.accept().await.unwrap();
+            SockRef::from(&remote_conn)
+                .set_linger(Some(Duration::ZERO))
+                .unwrap();
+            drop(remote_conn);
+
+            // The next one greets the client straight away instead.
+            let (mut remote_conn, _) = destination.accept().await.unwrap();
+            remote_conn.write_all(b"220 ready\r\n").await.unwrap();
+            remote_conn
+        });
+
+        let (_client, reply, _) = socks_connect(handle.local_addr(), destination_addr).await;
+        assert_eq!(reply, Reply::ConnRefused as u8);
+
+        let (mut client, reply, _) = socks_connect(handle.local_addr(), destination_addr).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let mut greeting = [0; 11];
+        client.read_exact(&mut greeting).await.unwrap();
+        assert_eq!(&greeting, b"220 ready\r\n");
+    }
+
+    #[tokio::test]
+    async fn records_transcript_of_full_handshake() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let handle = SocksServer::with_config(user_pass_settings(), Config::default())
+            .unwrap()
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let mut events = handle.subscribe();
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client
+            .write_all(&[SOCKS_VERSION, 3, 0, AuthMethod::UserPassword as u8, 0x80])
+            .await
+            .unwrap();
+        client.read_exact(&mut [0; 2]).await.unwrap();
+        client
+            .write_all(&user_pass_auth("alice", "secret"))
+            .await
+            .unwrap();
+        client.read_exact(&mut [0; 2]).await.unwrap();
+        client
+            .write_all(&request(RequestCommand::Connect, destination_addr))
+            .await
+            .unwrap();
+        let (reply, _) = read_reply(&mut client).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (remote_conn, _) = destination.accept().await.unwrap();
+        drop(client);
+        drop(remote_conn);
+
+        assert_eq!(
+            closed_stats(&mut events).await.handshake,
+            HandshakeTranscript {
+                version: Some(SOCKS_VERSION),
+                offered_methods: vec![AuthMethod::NoAuth, AuthMethod::UserPassword],
+                unknown_methods: vec![0x80],
+                selected_method: Some(AuthMethod::UserPassword),
+                username: Some("alice".to_string()),
+                authenticated: Some(true),
+                destination_addr: Some(DestinationAddress::Ipv4(Ipv4Addr::LOCALHOST)),
+                destination_port: Some(destination_addr.port()),
+                reply: Some(Reply::Succeeded),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_bound_address_once_ready() {
+        let server = Arc::new(SocksServer::new(no_auth()));
+        let (ready, bound) = tokio::sync::oneshot::channel();
+        let listening = Arc::clone(&server);
+        tokio::spawn(async move {
+            listening
+                .listen_with_ready("127.0.0.1", 0, |addr| ready.send(addr).unwrap())
+                .await
+        });
+
+        let bound_addr = bound.await.unwrap();
+        assert_eq!(bound_addr.ip(), Ipv4Addr::LOCALHOST);
+        assert_ne!(bound_addr.port(), 0);
+        say_hello(bound_addr, &[]).await;
+
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let mut called = false;
+        let result = server
+            .listen_with_ready("127.0.0.1", port, |_| called = true)
+            .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        assert!(!called);
+    }
+
+    #[tokio::test]
+    async fn closes_connection_on_non_utf8_credentials_and_keeps_serving() {
+        let handle = SocksServer::with_config(user_pass_settings(), Config::default())
+            .unwrap()
+            .spawn("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let server = handle.local_addr();
+
+        for auth in [
+            [1, 2, 0xc3, 0x28, 2, b'p', b'w'],
+            [1, 2, b'a', b'b', 2, 0xff, 0xfe],
+        ] {
+            let mut client = TcpStream::connect(server).await.unwrap();
+            client
+                .write_all(&[SOCKS_VERSION, 1, AuthMethod::UserPassword as u8])
+                .await
+                .unwrap();
+            let mut server_hello = [0; 2];
+            client.read_exact(&mut server_hello).await.unwrap();
+            client.write_all(&auth).await.unwrap();
+            assert!(read_until_closed(&mut client).await.is_empty());
+        }
+
+        assert!(log_in(server, "alice", "secret").await);
+    }
+
+    #[derive(Debug, Default)]
+    struct ToggledGate(AtomicBool);
+
+    impl AcceptGate for ToggledGate {
+        fn should_accept(&self) -> bool {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    // Waits until the server's accepting has been paused, or resumed, by its accept gate.
+    async fn wait_for_accepting_paused(handle: &ServerHandle, paused: bool) {
+        time::timeout(Duration::from_secs(5), async {
+            while handle.metrics().accepting_paused != paused {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn pauses_and_resumes_accepting_as_the_gate_says() {
+        let gate = Arc::new(ToggledGate::default());
+        let handle = spawn_server(Config {
+            accept_gate: Some(gate.clone()),
+            accept_gate_interval: Duration::from_millis(10),
+            ..Config::default()
+        })
+        .await;
+        wait_for_accepting_paused(&handle, true).await;
+
+        let mut client = TcpStream::connect(handle.local_addr()).await.unwrap();
+        client.write_all(&[SOCKS_VERSION, 1, 0]).await.unwrap();
+        let mut server_hello = [0; 2];
+        assert!(time::timeout(
+            Duration::from_millis(200),
+            client.read_exact(&mut server_hello)
+        )
+        .await
+        .is_err());
+
+        gate.0.store(true, Ordering::Relaxed);
+        wait_for_accepting_paused(&handle, false).await;
+        time::timeout(Duration::from_secs(5), client.read_exact(&mut server_hello))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(server_hello, [SOCKS_VERSION, 0]);
+        assert!(handle
+            .metrics()
+            .to_openmetrics()
+            .contains("socks_accepting_paused 0"));
+    }
+
+    // Logs in as `username` and asks the server to connect to `destination`, returning the
+    // request's reply.
+    async fn connect_as(
+        server: SocketAddr,
+        username: &str,
+        password: &str,
+        destination: SocketAddr,
+    ) -> (TcpStream, u8) {
+        let mut client = TcpStream::connect(server).await.unwrap();
+        let mut bytes = vec![SOCKS_VERSION, 1, AuthMethod::UserPassword as u8];
+        bytes.extend_from_slice(&user_pass_auth(username, password));
+        bytes.extend_from_slice(&request(RequestCommand::Connect, destination));
+        client.write_all(&bytes).await.unwrap();
+
+        let mut answers = [0; 4];
+        client.read_exact(&mut answers).await.unwrap();
+        assert_eq!(
+            answers,
+            [SOCKS_VERSION, AuthMethod::UserPassword as u8, 1, 0]
+        );
+        let (reply, _) = read_reply(&mut client).await;
+
+        (client, reply)
+    }
+
+    #[tokio::test]
+    async fn counts_all_of_a_users_connections_against_one_quota() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        let handle = SocksServer::with_config(
+            user_pass_settings(),
+            Config {
+                user_byte_quota: Some(UserByteQuota {
+                    max_bytes: 100,
+                    window: Duration::from_secs(24 * 60 * 60),
+                    cut_off_relays: false,
+                }),
+                ..Config::default()
+            },
+        )
+        .unwrap()
+        .spawn("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+        let server = handle.local_addr();
+
+        // Each connection stays under the quota on its own, but not together with the other.
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let (mut client, reply) = connect_as(server, "alice", "secret", destination_addr).await;
+            assert_eq!(reply, Reply::Succeeded as u8);
+            let (mut remote_conn, _) = destination.accept().await.unwrap();
+            client.write_all(&[7; 60]).await.unwrap();
+            remote_conn.read_exact(&mut [0; 60]).await.unwrap();
+            connections.push((client, remote_conn));
+        }
+
+        let (_client, reply) = connect_as(server, "alice", "secret", destination_addr).await;
+        assert_eq!(reply, Reply::ConnNotAllowed as u8);
+    }
+
+    #[tokio::test]
+    async fn counts_successful_connects_slower_than_threshold() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+
+        for (threshold, slow_connects) in
+            [(Duration::from_millis(50), 1), (Duration::from_secs(5), 0)]
+        {
+            let handle = spawn_server(Config {
+                connector: Some(Arc::new(SlowConnector {
+                    delay: Duration::from_millis(100),
+                })),
+                slow_connect_threshold: Some(threshold),
+                ..Config::default()
+            })
+            .await;
+
+            let (_client, reply, _) = socks_connect(handle.local_addr(), destination_addr).await;
+            assert_eq!(reply, Reply::Succeeded as u8);
+            destination.accept().await.unwrap();
+            assert_eq!(handle.metrics().slow_connects, slow_connects);
+        }
+    }
+
+    // A TLS ClientHello, in a single record, asking for `server_name`.
+    fn client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let mut server_name_list = vec![0];
+        server_name_list.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        server_name_list.extend_from_slice(name);
+        let mut extension = (server_name_list.len() as u16).to_be_bytes().to_vec();
+        extension.extend_from_slice(&server_name_list);
+        let mut extensions = vec![0, 0];
+        extensions.extend_from_slice(&(extension.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&extension);
+
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0x5a; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+        let mut handshake = vec![1];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![22, 3, 1];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[derive(Debug)]
+    struct RecordingInspector {
+        allow: bool,
+        seen: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl Inspector for RecordingInspector {
+        fn inspect(&self, _client_addr: SocketAddr, _remote_addr: SocketAddr, data: &[u8]) -> bool {
+            self.seen.lock().unwrap().push(data.to_vec());
+            self.allow
+        }
+    }
+
+    // Relays a ClientHello past an inspector answering `allow`, returning what it was shown
+    // along with the client's connection and what the destination got.
+    async fn relay_client_hello_past_inspector(allow: bool) -> (Vec<Vec<u8>>, TcpStream, Vec<u8>) {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let inspector = Arc::new(RecordingInspector {
+            allow,
+            seen: Mutex::new(Vec::new()),
+        });
+        let handle = spawn_server(Config {
+            initial_peek_bytes: 2048,
+            inspector: Some(inspector.clone()),
+            ..Config::default()
+        })
+        .await;
+
+        let (mut client, reply, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        client
+            .write_all(&client_hello("example.com"))
+            .await
+            .unwrap();
+        client.shutdown().await.unwrap();
+
+        let mut delivered = Vec::new();
+        time::timeout(
+            Duration::from_secs(5),
+            remote_conn.read_to_end(&mut delivered),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        let seen = inspector.seen.lock().unwrap().clone();
+
+        (seen, client, delivered)
+    }
+
+    #[tokio::test]
+    async fn shows_client_hello_to_inspector_and_relays_it_intact() {
+        let hello = client_hello("example.com");
+
+        let (seen, _, delivered) = relay_client_hello_past_inspector(true).await;
+
+        assert_eq!(delivered, hello);
+        assert_eq!(seen, [hello]);
+    }
+
+    #[tokio::test]
+    async fn closes_connection_the_inspector_turns_down() {
+        let (seen, mut client, delivered) = relay_client_hello_past_inspector(false).await;
+
+        assert_eq!(seen.len(), 1);
+        assert!(delivered.is_empty());
+        // The ClientHello was only peeked at, so closing with it unread may reset the client.
+        assert!(matches!(client.read(&mut [0; 1]).await, Ok(0) | Err(_)));
+    }
+
+    // Sends `data` in `parts`, a little apart, through a server checking TLS server names
+    // against a rule denying blocked.example, and returns what reached the destination.
+    async fn relay_past_server_name_check(data: &[u8], parts: usize) -> Vec<u8> {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config {
+            access_control: Some(AccessControl::parse("deny blocked.example")),
+            check_tls_server_name: true,
+            initial_peek_bytes: 2048,
+            ..Config::default()
+        })
+        .await;
+
+        let (mut client, reply, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        for part in data.chunks(data.len().div_ceil(parts)) {
+            client.write_all(part).await.unwrap();
+            time::sleep(Duration::from_millis(20)).await;
+        }
+        client.shutdown().await.unwrap();
+
+        let mut delivered = Vec::new();
+        time::timeout(
+            Duration::from_secs(5),
+            remote_conn.read_to_end(&mut delivered),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        delivered
+    }
+
+    #[tokio::test]
+    async fn closes_connection_asking_tls_for_blocked_server_name() {
+        assert!(
+            relay_past_server_name_check(&client_hello("blocked.example"), 1)
+                .await
+                .is_empty()
+        );
+        assert!(
+            relay_past_server_name_check(&client_hello("www.blocked.example"), 2)
+                .await
+                .is_empty()
+        );
+    }
+
+    #[tokio::test]
+    async fn relays_allowed_server_names_and_other_traffic() {
+        let hello = client_hello("allowed.example");
+        assert_eq!(relay_past_server_name_check(&hello, 1).await, hello);
+
+        let request = b"GET / HTTP/1.1\r\nHost: blocked.example\r\n\r\n";
+        assert_eq!(relay_past_server_name_check(request, 1).await, request);
+    }
+
+    #[tokio::test]
+    async fn reports_accepted_auth_methods_as_reloaded() {
+        let server = SocksServer::with_config(user_pass_settings(), Config::default()).unwrap();
+        assert_eq!(server.accepted_auth_methods(), [AuthMethod::UserPassword]);
+
+        let handle = server.spawn("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        assert_eq!(handle.accepted_auth_methods(), [AuthMethod::UserPassword]);
+
+        handle.reload(no_auth(), Config::default()).unwrap();
+        assert_eq!(handle.accepted_auth_methods(), [AuthMethod::NoAuth]);
+    }
+
+    // Opens a relay through a server with `idle_policy` and leaves it idle for 300ms,
+    // returning the server's connection events along with both ends of the relay.
+    async fn idle_relay(
+        idle_policy: IdlePolicy,
+    ) -> (
+        ServerHandle,
+        broadcast::Receiver<ConnectionEvent>,
+        TcpStream,
+        TcpStream,
+    ) {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config {
+            idle_policy: Some(idle_policy),
+            ..Config::default()
+        })
+        .await;
+        let events = handle.subscribe();
+
+        let (client, reply, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (remote_conn, _) = destination.accept().await.unwrap();
+        time::sleep(Duration::from_millis(300)).await;
+
+        (handle, events, client, remote_conn)
+    }
+
+    #[tokio::test]
+    async fn closes_idle_relay_under_close_policy() {
+        let (_handle, mut events, mut client, _remote_conn) =
+            idle_relay(IdlePolicy::Close(Duration::from_millis(100))).await;
+
+        assert!(read_until_closed(&mut client).await.is_empty());
+        assert_eq!(
+            closed_stats(&mut events).await.close_reason,
+            Some(CloseReason::IdleTimeout)
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_idle_but_alive_relay_under_keepalive_policy() {
+        let (_handle, _events, mut client, mut remote_conn) =
+            idle_relay(IdlePolicy::KeepAlive(KeepaliveSettings {
+                idle: Duration::from_secs(60),
+                interval: Duration::from_secs(10),
+                retries: 3,
+            }))
+            .await;
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        remote_conn.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+    }
+
+    #[tokio::test]
+    async fn fails_to_listen_on_invalid_ip() {
+        let server = SocksServer::with_config(no_auth(), Config::default()).unwrap();
+
+        let error = server.listen("not.an.ip", 0).await.unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(error.to_string(), "`not.an.ip` is not a valid IP address");
+    }
+
+    #[tokio::test]
+    async fn holds_data_of_paused_connection_until_resumed() {
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let handle = spawn_server(Config::default()).await;
+        let mut events = handle.subscribe();
+        let (mut client, _, _) =
+            socks_connect(handle.local_addr(), destination.local_addr().unwrap()).await;
+        let (mut remote_conn, _) = destination.accept().await.unwrap();
+        let id = loop {
+            if let ConnectionEvent::RelayStarted { id, .. } = events.recv().await.unwrap() {
+                break id;
+            }
+        };
+        client.write_all(b"before").await.unwrap();
+        let mut received = [0; 6];
+        remote_conn.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"before");
+
+        assert!(handle.pause_connection(id));
+        // Gives the relay a moment to notice before there's anything to read.
+        time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"during").await.unwrap();
+        assert!(time::timeout(
+            Duration::from_millis(200),
+            remote_conn.read_exact(&mut received)
+        )
+        .await
+        .is_err());
+
+        assert!(handle.resume_connection(id));
+        time::timeout(
+            Duration::from_secs(5),
+            remote_conn.read_exact(&mut received),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(&received, b"during");
+    }
+
+    #[tokio::test]
+    async fn refuses_to_pause_unknown_connection() {
+        let handle = spawn_server(Config::default()).await;
+
+        assert!(!handle.pause_connection(42));
+        assert!(!handle.resume_connection(42));
+    }
+
+    #[cfg(feature = "tls")]
+    #[tokio::test]
+    async fn relays_plaintext_client_inside_tls_to_destination() {
+        use tokio_rustls::rustls::pki_types::PrivatePkcs8KeyDer;
+        use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+        use tokio_rustls::TlsAcceptor;
+
+        let certified = rcgen::generate_simple_self_signed(["127.0.0.1".to_string()]).unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![certified.cert.der().clone()],
+                PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der()).into(),
+            )
+            .unwrap();
+        let destination = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let destination_addr = destination.local_addr().unwrap();
+        // Terminates TLS and answers each "ping" with a "pong".
+        let destination_task = tokio::spawn(async move {
+            let (conn, _) = destination.accept().await.unwrap();
+            let mut tls = TlsAcceptor::from(Arc::new(server_config))
+                .accept(conn)
+                .await
+                .unwrap();
+            let mut received = [0; 4];
+            tls.read_exact(&mut received).await.unwrap();
+            assert_eq!(&received, b"ping");
+            tls.write_all(b"pong").await.unwrap();
+            tls.flush().await.unwrap();
+        });
+        let handle = spawn_server(Config {
+            outbound_tls: Some(OutboundTls {
+                client_config: Arc::new(
+                    ClientConfig::builder()
+                        .with_root_certificates(roots)
+                        .with_no_client_auth(),
+                ),
+                destinations: vec![AccessTarget::All],
+            }),
+            ..Config::default()
+        })
+        .await;
+
+        let (mut client, reply, _) = socks_connect(handle.local_addr(), destination_addr).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        time::timeout(Duration::from_secs(5), client.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&received, b"pong");
+        destination_task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_binds_over_the_pending_limit_until_one_completes() {
+        let handle = spawn_server(Config {
+            allow_bind: true,
+            max_pending_binds: Some(2),
+            ..Config::default()
+        })
+        .await;
+        let bind = || {
+            socks_request(
+                handle.local_addr(),
+                RequestCommand::Bind,
+                "127.0.0.1:0".parse().unwrap(),
+            )
+        };
+
+        let (mut first, reply, listen_addr) = bind().await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        let (_second, reply, _) = bind().await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(handle.metrics().pending_binds, 2);
+        let (_refused, reply, _) = bind().await;
+        assert_eq!(reply, Reply::SocksServerFail as u8);
+
+        let _peer = TcpStream::connect(listen_addr).await.unwrap();
+        let (reply, _) = read_reply(&mut first).await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+        assert_eq!(handle.metrics().pending_binds, 1);
+        let (_third, reply, _) = bind().await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+    }
+
+    #[tokio::test]
+    async fn refuses_associations_over_the_limit_until_one_closes() {
+        let (handle, control, _client, _relay_addr) = udp_associate(Config {
+            max_pending_associations: Some(1),
+            ..Config::default()
+        })
+        .await;
+        let associate = || {
+            socks_request(
+                handle.local_addr(),
+                RequestCommand::UdpAssociate,
+                "0.0.0.0:0".parse().unwrap(),
+            )
+        };
+
+        let (_refused, reply, _) = associate().await;
+        assert_eq!(reply, Reply::SocksServerFail as u8);
+
+        drop(control);
+        time::timeout(Duration::from_secs(5), async {
+            while handle.metrics().pending_associations > 0 {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("association was not closed");
+        let (_control, reply, _) = associate().await;
+        assert_eq!(reply, Reply::Succeeded as u8);
+    }
 }
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+// Counts live connections per key (e.g. client IP). Keys are removed as soon as their count
+// drops to zero, so the map only ever holds keys that currently have connections.
+#[derive(Debug)]
+pub(crate) struct ConnectionCounter<K> {
+    counts: Mutex<HashMap<K, usize>>,
+}
+
+impl<K: Eq + Hash + Clone> ConnectionCounter<K> {
+    pub fn new() -> Self {
+        Self {
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns `None` when `key` already has `limit` live connections.
+    pub fn try_acquire(self: &Arc<Self>, key: K, limit: usize) -> Option<ConnectionPermit<K>> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(key.clone()).or_insert(0);
+        if *count >= limit {
+            if *count == 0 {
+                counts.remove(&key);
+            }
+            return None;
+        }
+        *count += 1;
+
+        Some(ConnectionPermit {
+            counter: Arc::clone(self),
+            key,
+        })
+    }
+}
+
+// Releases its slot in the counter when dropped.
+#[derive(Debug)]
+pub(crate) struct ConnectionPermit<K: Eq + Hash> {
+    counter: Arc<ConnectionCounter<K>>,
+    key: K,
+}
+
+impl<K: Eq + Hash> Drop for ConnectionPermit<K> {
+    fn drop(&mut self) {
+        let mut counts = self.counter.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.key);
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use socks_server::AuthMethod;
 use socks_server::AuthSettings;
 use socks_server::SocksServer;
@@ -5,11 +7,33 @@ use socks_server::SocksServer;
 const IP: &str = "0.0.0.0";
 const PORT: u16 = 1080;
 
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
 #[tokio::main]
 async fn main() {
-    let server = SocksServer::new(AuthSettings {
+    let server = Arc::new(SocksServer::new(AuthSettings {
         method: AuthMethod::NoAuth,
         params: None,
+    }));
+
+    let signal_server = Arc::clone(&server);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        signal_server.shutdown();
     });
 
     if let Err(e) = server.listen(IP, PORT).await {
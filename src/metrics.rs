@@ -0,0 +1,621 @@
+use std::collections::BTreeMap;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::{AddressType, Reply};
+
+/// Snapshot of the server's counters at the time it was taken.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub total_connections: u64,
+    pub active_connections: u64,
+    /// Connections waiting for a slot under `Config::max_connections`.
+    pub queued_connections: u64,
+    /// Connections accepted but not relaying yet, i.e. still negotiating or waiting for a slot.
+    pub handshakes_in_progress: u64,
+    /// Connections relaying data.
+    pub active_relays: u64,
+    pub bytes_client_to_remote: u64,
+    pub bytes_remote_to_client: u64,
+    pub auth_failures: u64,
+    /// Connections whose first packet could not be parsed as a client hello. A spike usually
+    /// means someone is scanning the port or a client is misconfigured.
+    pub malformed_hellos: u64,
+    /// Outbound connects that succeeded but took longer than `Config::slow_connect_threshold`.
+    pub slow_connects: u64,
+    /// Domain names being resolved right now.
+    pub resolutions_in_flight: u64,
+    /// BIND requests waiting for their peer to connect.
+    pub pending_binds: u64,
+    /// UDP associations currently open.
+    pub pending_associations: u64,
+    /// Whether `Config::accept_gate` currently has the server not accepting new connections.
+    pub accepting_paused: bool,
+    /// Number of requests per destination port.
+    pub requests_per_port: BTreeMap<u16, u64>,
+    /// Number of requests per destination address type, e.g. to tell how many clients send
+    /// domain names rather than resolving them themselves.
+    pub requests_per_address_type: BTreeMap<AddressType, u64>,
+    /// Number of replies sent to clients per reply code.
+    pub replies: BTreeMap<Reply, u64>,
+    /// Highest number of relay buffers in use at once, when the buffer pool is enabled.
+    pub relay_buffers_high_water_mark: u64,
+    /// Bytes relays have read but not written out yet, across all connections.
+    pub buffered_bytes: u64,
+    /// Highest value `buffered_bytes` reached, e.g. to size `Config::max_buffered_bytes`.
+    pub buffered_bytes_high_water_mark: u64,
+    /// Number of relays that ended because of an IO error, per error kind (as in
+    /// `io::ErrorKind`'s `Debug` output, e.g. `ConnectionReset`).
+    pub relay_errors: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    /// Renders the snapshot in the OpenMetrics text exposition format, which Prometheus also
+    /// understands, so it can be served as is from any HTTP endpoint.
+    pub fn to_openmetrics(&self) -> String {
+        let mut output = String::new();
+
+        write_family(
+            &mut output,
+            "socks_connections",
+            "counter",
+            "Accepted connections.",
+        );
+        let _ = writeln!(output, "socks_connections_total {}", self.total_connections);
+
+        write_family(
+            &mut output,
+            "socks_active_connections",
+            "gauge",
+            "Connections currently being served.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_active_connections {}",
+            self.active_connections
+        );
+
+        write_family(
+            &mut output,
+            "socks_queued_connections",
+            "gauge",
+            "Connections waiting for a free connection slot.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_queued_connections {}",
+            self.queued_connections
+        );
+
+        write_family(
+            &mut output,
+            "socks_handshakes_in_progress",
+            "gauge",
+            "Connections accepted but not relaying yet.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_handshakes_in_progress {}",
+            self.handshakes_in_progress
+        );
+
+        write_family(
+            &mut output,
+            "socks_active_relays",
+            "gauge",
+            "Connections relaying data.",
+        );
+        let _ = writeln!(output, "socks_active_relays {}", self.active_relays);
+
+        write_family(
+            &mut output,
+            "socks_relayed_bytes",
+            "counter",
+            "Relayed bytes.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_relayed_bytes_total{{direction=\"client_to_remote\"}} {}",
+            self.bytes_client_to_remote
+        );
+        let _ = writeln!(
+            output,
+            "socks_relayed_bytes_total{{direction=\"remote_to_client\"}} {}",
+            self.bytes_remote_to_client
+        );
+
+        write_family(
+            &mut output,
+            "socks_auth_failures",
+            "counter",
+            "Rejected username/password authentications.",
+        );
+        let _ = writeln!(output, "socks_auth_failures_total {}", self.auth_failures);
+
+        write_family(
+            &mut output,
+            "socks_malformed_hellos",
+            "counter",
+            "Connections whose client hello could not be parsed.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_malformed_hellos_total {}",
+            self.malformed_hellos
+        );
+
+        write_family(
+            &mut output,
+            "socks_slow_connects",
+            "counter",
+            "Outbound connects that succeeded but took longer than the slow connect threshold.",
+        );
+        let _ = writeln!(output, "socks_slow_connects_total {}", self.slow_connects);
+
+        write_family(
+            &mut output,
+            "socks_resolutions_in_flight",
+            "gauge",
+            "Domain names currently being resolved.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_resolutions_in_flight {}",
+            self.resolutions_in_flight
+        );
+
+        write_family(
+            &mut output,
+            "socks_pending_binds",
+            "gauge",
+            "BIND requests waiting for their peer to connect.",
+        );
+        let _ = writeln!(output, "socks_pending_binds {}", self.pending_binds);
+
+        write_family(
+            &mut output,
+            "socks_pending_associations",
+            "gauge",
+            "UDP associations currently open.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_pending_associations {}",
+            self.pending_associations
+        );
+
+        write_family(
+            &mut output,
+            "socks_accepting_paused",
+            "gauge",
+            "Whether the accept gate has paused accepting new connections (1) or not (0).",
+        );
+        let _ = writeln!(
+            output,
+            "socks_accepting_paused {}",
+            u8::from(self.accepting_paused)
+        );
+
+        write_family(
+            &mut output,
+            "socks_requests",
+            "counter",
+            "Client requests by destination port.",
+        );
+        for (port, count) in &self.requests_per_port {
+            let _ = writeln!(
+                output,
+                "socks_requests_total{{port=\"{}\"}} {}",
+                port, count
+            );
+        }
+
+        write_family(
+            &mut output,
+            "socks_requests_by_address_type",
+            "counter",
+            "Client requests by destination address type.",
+        );
+        for (address_type, count) in &self.requests_per_address_type {
+            let _ = writeln!(
+                output,
+                "socks_requests_by_address_type_total{{address_type=\"{}\"}} {}",
+                address_type.label(),
+                count
+            );
+        }
+
+        write_family(
+            &mut output,
+            "socks_replies",
+            "counter",
+            "Replies sent by code.",
+        );
+        for (reply, count) in &self.replies {
+            let _ = writeln!(
+                output,
+                "socks_replies_total{{reply=\"{}\"}} {}",
+                reply.label(),
+                count
+            );
+        }
+
+        write_family(
+            &mut output,
+            "socks_relay_buffers_high_water_mark",
+            "gauge",
+            "Highest number of pooled relay buffers in use at once.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_relay_buffers_high_water_mark {}",
+            self.relay_buffers_high_water_mark
+        );
+
+        write_family(
+            &mut output,
+            "socks_buffered_bytes",
+            "gauge",
+            "Bytes read by relays but not written out yet.",
+        );
+        let _ = writeln!(output, "socks_buffered_bytes {}", self.buffered_bytes);
+
+        write_family(
+            &mut output,
+            "socks_buffered_bytes_high_water_mark",
+            "gauge",
+            "Highest number of bytes buffered by relays at once.",
+        );
+        let _ = writeln!(
+            output,
+            "socks_buffered_bytes_high_water_mark {}",
+            self.buffered_bytes_high_water_mark
+        );
+
+        write_family(
+            &mut output,
+            "socks_relay_errors",
+            "counter",
+            "Relays ended by an IO error, by error kind.",
+        );
+        for (kind, count) in &self.relay_errors {
+            let _ = writeln!(
+                output,
+                "socks_relay_errors_total{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+
+        output.push_str("# EOF\n");
+
+        output
+    }
+}
+
+/// Something worth counting that just happened, as passed to `MetricsSink::record`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricEvent {
+    ConnectionAccepted,
+    ConnectionClosed,
+    /// Some bytes were relayed in one direction. Sent for every chunk written, so sinks will
+    /// usually want to add these up rather than forward each one.
+    BytesRelayed {
+        client_to_remote: bool,
+        bytes: u64,
+    },
+    AuthFailed,
+    /// A request was answered with a failure reply.
+    RequestDenied {
+        reply: Reply,
+    },
+}
+
+/// Receives metric events as they happen, for pushing them to systems such as StatsD, in
+/// addition to the counters behind `SocksServer::metrics`. `record` is called inline on
+/// connection tasks, so it should hand the event off rather than block.
+pub trait MetricsSink: fmt::Debug + Send + Sync {
+    fn record(&self, event: MetricEvent);
+}
+
+fn write_family(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+}
+
+// Live counters shared by all connections of a server.
+#[derive(Debug, Default)]
+pub(crate) struct MetricsRegistry {
+    total_connections: AtomicU64,
+    active_connections: AtomicU64,
+    queued_connections: AtomicU64,
+    handshakes_in_progress: AtomicU64,
+    active_relays: AtomicU64,
+    bytes_client_to_remote: AtomicU64,
+    bytes_remote_to_client: AtomicU64,
+    auth_failures: AtomicU64,
+    malformed_hellos: AtomicU64,
+    slow_connects: AtomicU64,
+    resolutions_in_flight: AtomicU64,
+    pending_binds: AtomicU64,
+    pending_associations: AtomicU64,
+    accepting_paused: AtomicBool,
+    requests_per_port: Mutex<BTreeMap<u16, u64>>,
+    requests_per_address_type: Mutex<BTreeMap<AddressType, u64>>,
+    replies: Mutex<BTreeMap<Reply, u64>>,
+    relay_errors: Mutex<BTreeMap<String, u64>>,
+    sink: Option<Arc<dyn MetricsSink>>,
+}
+
+impl MetricsRegistry {
+    pub fn new(sink: Option<Arc<dyn MetricsSink>>) -> Self {
+        Self {
+            sink,
+            ..Self::default()
+        }
+    }
+
+    fn push(&self, event: MetricEvent) {
+        if let Some(sink) = &self.sink {
+            sink.record(event);
+        }
+    }
+
+    pub fn connection_opened(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        self.push(MetricEvent::ConnectionAccepted);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+        self.push(MetricEvent::ConnectionClosed);
+    }
+
+    pub fn connection_queued(&self) {
+        self.queued_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_dequeued(&self) {
+        self.queued_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_started(&self) {
+        self.handshakes_in_progress.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn handshake_finished(&self) {
+        self.handshakes_in_progress.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn relay_started(&self) {
+        self.active_relays.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn relay_finished(&self) {
+        self.active_relays.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_relayed(&self, client_to_remote: bool, n: u64) {
+        let counter = if client_to_remote {
+            &self.bytes_client_to_remote
+        } else {
+            &self.bytes_remote_to_client
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+        self.push(MetricEvent::BytesRelayed {
+            client_to_remote,
+            bytes: n,
+        });
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+        self.push(MetricEvent::AuthFailed);
+    }
+
+    pub fn malformed_hello(&self) {
+        self.malformed_hellos.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_connect(&self) {
+        self.slow_connects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn resolution_started(&self) -> InFlightResolution<'_> {
+        self.resolutions_in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightResolution(&self.resolutions_in_flight)
+    }
+
+    pub fn bind_started(&self) -> PendingBind<'_> {
+        self.pending_binds.fetch_add(1, Ordering::Relaxed);
+        PendingBind(&self.pending_binds)
+    }
+
+    pub fn association_opened(&self) -> OpenAssociation<'_> {
+        self.pending_associations.fetch_add(1, Ordering::Relaxed);
+        OpenAssociation(&self.pending_associations)
+    }
+
+    pub fn set_accepting_paused(&self, paused: bool) {
+        self.accepting_paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn request_received(&self, port: u16, address_type: AddressType) {
+        *self
+            .requests_per_port
+            .lock()
+            .unwrap()
+            .entry(port)
+            .or_default() += 1;
+        *self
+            .requests_per_address_type
+            .lock()
+            .unwrap()
+            .entry(address_type)
+            .or_default() += 1;
+    }
+
+    pub fn reply_sent(&self, reply: Reply) {
+        *self.replies.lock().unwrap().entry(reply).or_default() += 1;
+        if reply != Reply::Succeeded {
+            self.push(MetricEvent::RequestDenied { reply });
+        }
+    }
+
+    pub fn relay_failed(&self, kind: io::ErrorKind) {
+        *self
+            .relay_errors
+            .lock()
+            .unwrap()
+            .entry(format!("{:?}", kind))
+            .or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> Metrics {
+        Metrics {
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            queued_connections: self.queued_connections.load(Ordering::Relaxed),
+            handshakes_in_progress: self.handshakes_in_progress.load(Ordering::Relaxed),
+            active_relays: self.active_relays.load(Ordering::Relaxed),
+            bytes_client_to_remote: self.bytes_client_to_remote.load(Ordering::Relaxed),
+            bytes_remote_to_client: self.bytes_remote_to_client.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            malformed_hellos: self.malformed_hellos.load(Ordering::Relaxed),
+            slow_connects: self.slow_connects.load(Ordering::Relaxed),
+            resolutions_in_flight: self.resolutions_in_flight.load(Ordering::Relaxed),
+            pending_binds: self.pending_binds.load(Ordering::Relaxed),
+            pending_associations: self.pending_associations.load(Ordering::Relaxed),
+            accepting_paused: self.accepting_paused.load(Ordering::Relaxed),
+            requests_per_port: self.requests_per_port.lock().unwrap().clone(),
+            requests_per_address_type: self.requests_per_address_type.lock().unwrap().clone(),
+            replies: self.replies.lock().unwrap().clone(),
+            relay_buffers_high_water_mark: 0,
+            buffered_bytes: 0,
+            buffered_bytes_high_water_mark: 0,
+            relay_errors: self.relay_errors.lock().unwrap().clone(),
+        }
+    }
+}
+
+// Counts a resolution as in flight until dropped, so lookups abandoned on timeout are not
+// counted forever.
+pub(crate) struct InFlightResolution<'a>(&'a AtomicU64);
+
+impl Drop for InFlightResolution<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Counts a BIND request as pending until dropped, however it ends.
+pub(crate) struct PendingBind<'a>(&'a AtomicU64);
+
+impl Drop for PendingBind<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Counts a UDP association as open until dropped.
+pub(crate) struct OpenAssociation<'a>(&'a AtomicU64);
+
+impl Drop for OpenAssociation<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Checks the parts of the OpenMetrics text format the exporter relies on: every sample
+    // belongs to the family declared right before it, with the `_total` suffix for counters,
+    // labels are quoted, values are numbers, and `# EOF` ends the output.
+    fn assert_valid_openmetrics(output: &str) {
+        let mut lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.pop(), Some("# EOF"));
+        assert!(output.ends_with("# EOF\n"));
+
+        let mut family: Option<(&str, &str)> = None;
+        for line in lines {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').unwrap();
+                assert!(matches!(kind, "counter" | "gauge"), "{}", line);
+                family = Some((name, kind));
+            } else if let Some(rest) = line.strip_prefix("# HELP ") {
+                let (name, help) = rest.split_once(' ').unwrap();
+                assert_eq!(Some(name), family.map(|(name, _)| name), "{}", line);
+                assert!(!help.is_empty());
+            } else {
+                let (name, kind) = family.expect("sample before any family");
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                value.parse::<f64>().unwrap();
+                let (metric, labels) = match series.split_once('{') {
+                    Some((metric, labels)) => (metric, labels.strip_suffix('}').unwrap()),
+                    None => (series, ""),
+                };
+                let expected = match kind {
+                    "counter" => format!("{}_total", name),
+                    _ => name.to_string(),
+                };
+                assert_eq!(metric, expected, "{}", line);
+                for label in labels.split(',').filter(|label| !label.is_empty()) {
+                    let (key, value) = label.split_once('=').unwrap();
+                    assert!(key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'));
+                    assert!(value.len() >= 2 && value.starts_with('"') && value.ends_with('"'));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn renders_valid_openmetrics() {
+        let registry = MetricsRegistry::new(None);
+        registry.connection_opened();
+        registry.connection_opened();
+        registry.connection_closed();
+        registry.bytes_relayed(true, 100);
+        registry.bytes_relayed(false, 2048);
+        registry.auth_failed();
+        registry.request_received(443, AddressType::DomainName);
+        registry.request_received(443, AddressType::Ipv4);
+        registry.request_received(80, AddressType::Ipv4);
+        registry.reply_sent(Reply::Succeeded);
+        registry.reply_sent(Reply::ConnRefused);
+        registry.relay_failed(io::ErrorKind::ConnectionReset);
+
+        let output = registry.snapshot().to_openmetrics();
+
+        assert_valid_openmetrics(&output);
+        for sample in [
+            "socks_connections_total 2",
+            "socks_active_connections 1",
+            "socks_relayed_bytes_total{direction=\"client_to_remote\"} 100",
+            "socks_relayed_bytes_total{direction=\"remote_to_client\"} 2048",
+            "socks_auth_failures_total 1",
+            "socks_requests_total{port=\"80\"} 1",
+            "socks_requests_total{port=\"443\"} 2",
+            "socks_requests_by_address_type_total{address_type=\"domain_name\"} 1",
+            "socks_requests_by_address_type_total{address_type=\"ipv4\"} 2",
+            "socks_replies_total{reply=\"succeeded\"} 1",
+            "socks_replies_total{reply=\"connection_refused\"} 1",
+            "socks_relay_errors_total{kind=\"ConnectionReset\"} 1",
+        ] {
+            assert!(output.lines().any(|line| line == sample), "{}", sample);
+        }
+    }
+
+    #[test]
+    fn renders_valid_openmetrics_without_any_traffic() {
+        let output = Metrics::default().to_openmetrics();
+
+        assert_valid_openmetrics(&output);
+        assert!(output
+            .lines()
+            .any(|line| line == "socks_connections_total 0"));
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+const MAX_ENTRIES: usize = 1024;
+
+// Remembers destinations that recently failed to connect, so that requests for them fail
+// straight away instead of waiting on the same failure again.
+#[derive(Debug)]
+pub(crate) struct ConnectFailureCache {
+    ttl: Duration,
+    failures: Mutex<HashMap<SocketAddr, (Instant, io::ErrorKind)>>,
+}
+
+impl ConnectFailureCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Only failures that say something about the destination itself are worth caching.
+    pub fn is_cacheable(kind: io::ErrorKind) -> bool {
+        matches!(
+            kind,
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::TimedOut
+        )
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<io::ErrorKind> {
+        let mut failures = self.failures.lock().unwrap();
+        match failures.get(addr) {
+            Some(&(expires_at, kind)) if expires_at > Instant::now() => Some(kind),
+            Some(_) => {
+                failures.remove(addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, addr: SocketAddr, kind: io::ErrorKind) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() >= MAX_ENTRIES && !failures.contains_key(&addr) {
+            failures.retain(|_, (expires_at, _)| *expires_at > now);
+        }
+        if failures.len() >= MAX_ENTRIES && !failures.contains_key(&addr) {
+            let soonest_to_expire = failures
+                .iter()
+                .min_by_key(|(_, (expires_at, _))| *expires_at)
+                .map(|(addr, _)| *addr);
+            if let Some(soonest_to_expire) = soonest_to_expire {
+                failures.remove(&soonest_to_expire);
+            }
+        }
+
+        failures.insert(addr, (now + self.ttl, kind));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn remembers_failure_until_ttl_expires() {
+        let cache = ConnectFailureCache::new(Duration::from_secs(10));
+        let addr = "203.0.113.7:80".parse().unwrap();
+        cache.insert(addr, io::ErrorKind::ConnectionRefused);
+
+        time::advance(Duration::from_secs(9)).await;
+        assert_eq!(cache.get(&addr), Some(io::ErrorKind::ConnectionRefused));
+        assert_eq!(cache.get(&"203.0.113.7:81".parse().unwrap()), None);
+
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(cache.get(&addr), None);
+        assert!(cache.failures.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evicts_soonest_to_expire_when_full() {
+        let cache = ConnectFailureCache::new(Duration::from_secs(10));
+        let addr = |port| SocketAddr::from(([203, 0, 113, 7], port));
+        for port in 0..MAX_ENTRIES as u16 {
+            cache.insert(addr(port), io::ErrorKind::TimedOut);
+            time::advance(Duration::from_millis(1)).await;
+        }
+
+        cache.insert(addr(u16::MAX), io::ErrorKind::TimedOut);
+
+        assert_eq!(cache.failures.lock().unwrap().len(), MAX_ENTRIES);
+        assert_eq!(cache.get(&addr(0)), None);
+        assert_eq!(cache.get(&addr(1)), Some(io::ErrorKind::TimedOut));
+        assert_eq!(cache.get(&addr(u16::MAX)), Some(io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn caches_only_failures_about_the_destination() {
+        assert!(ConnectFailureCache::is_cacheable(
+            io::ErrorKind::ConnectionRefused
+        ));
+        assert!(ConnectFailureCache::is_cacheable(io::ErrorKind::TimedOut));
+        assert!(!ConnectFailureCache::is_cacheable(
+            io::ErrorKind::AddrNotAvailable
+        ));
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How an outbound bind address is picked from `Config::outbound_bind_pool`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutboundBindStrategy {
+    /// Cycle through the addresses in order, one connection after another.
+    #[default]
+    RoundRobin,
+    /// Always use the same address for a given destination IP.
+    HashByDestination,
+    Random,
+}
+
+// Local addresses outbound connections are spread across. Only addresses of the destination's
+// family are candidates, since a socket can't bind to one family and connect to the other.
+#[derive(Debug)]
+pub(crate) struct OutboundBindPool {
+    addrs: Vec<IpAddr>,
+    strategy: OutboundBindStrategy,
+    next: AtomicUsize,
+    random_state: RandomState,
+}
+
+impl OutboundBindPool {
+    pub fn new(addrs: Vec<IpAddr>, strategy: OutboundBindStrategy) -> Self {
+        Self {
+            addrs,
+            strategy,
+            next: AtomicUsize::new(0),
+            random_state: RandomState::new(),
+        }
+    }
+
+    // Returns `None` when the pool has no address of the destination's family.
+    pub fn select(&self, destination: SocketAddr) -> Option<IpAddr> {
+        let candidates: Vec<IpAddr> = self
+            .addrs
+            .iter()
+            .copied()
+            .filter(|addr| addr.is_ipv4() == destination.is_ipv4())
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let index = match self.strategy {
+            OutboundBindStrategy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            // `DefaultHasher::new` always uses the same keys, so the mapping is stable for
+            // the lifetime of the server.
+            OutboundBindStrategy::HashByDestination => {
+                let mut hasher = DefaultHasher::new();
+                destination.ip().hash(&mut hasher);
+                hasher.finish() as usize
+            }
+            OutboundBindStrategy::Random => {
+                self.random_state
+                    .hash_one(self.next.fetch_add(1, Ordering::Relaxed)) as usize
+            }
+        };
+
+        Some(candidates[index % candidates.len()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(strategy: OutboundBindStrategy) -> OutboundBindPool {
+        OutboundBindPool::new(
+            vec![
+                "192.0.2.1".parse().unwrap(),
+                "2001:db8::1".parse().unwrap(),
+                "192.0.2.2".parse().unwrap(),
+            ],
+            strategy,
+        )
+    }
+
+    #[test]
+    fn cycles_through_addresses_round_robin() {
+        let pool = pool(OutboundBindStrategy::RoundRobin);
+        let destination = "203.0.113.7:80".parse().unwrap();
+
+        let picks: Vec<IpAddr> = (0..4).map(|_| pool.select(destination).unwrap()).collect();
+
+        let first: IpAddr = "192.0.2.1".parse().unwrap();
+        let second: IpAddr = "192.0.2.2".parse().unwrap();
+        assert_eq!(picks, [first, second, first, second]);
+    }
+
+    #[test]
+    fn only_picks_addresses_of_the_destination_family() {
+        let pool = pool(OutboundBindStrategy::Random);
+
+        for _ in 0..8 {
+            assert!(pool
+                .select("203.0.113.7:80".parse().unwrap())
+                .unwrap()
+                .is_ipv4());
+            assert_eq!(
+                pool.select("[2001:db8::7]:80".parse().unwrap()),
+                Some("2001:db8::1".parse().unwrap())
+            );
+        }
+        let ipv4_only = OutboundBindPool::new(
+            vec!["192.0.2.1".parse().unwrap()],
+            OutboundBindStrategy::RoundRobin,
+        );
+        assert_eq!(ipv4_only.select("[2001:db8::7]:80".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn hashes_each_destination_to_the_same_address() {
+        let pool = pool(OutboundBindStrategy::HashByDestination);
+
+        for port in [80, 443, 8080] {
+            let destination = SocketAddr::from(([203, 0, 113, 7], port));
+            assert_eq!(
+                pool.select(destination),
+                pool.select("203.0.113.7:80".parse().unwrap())
+            );
+        }
+    }
+}
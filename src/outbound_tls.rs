@@ -0,0 +1,48 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::TlsConnector;
+
+use crate::AccessTarget;
+
+/// TLS the server originates to some destinations, so that clients speaking plaintext reach
+/// destinations that only accept TLS. What the client sends is relayed inside the TLS session,
+/// and what the destination sends back is relayed to the client decrypted.
+#[derive(Debug, Clone)]
+pub struct OutboundTls {
+    /// TLS client settings, including the root certificates destinations are verified against.
+    pub client_config: Arc<ClientConfig>,
+    /// Destinations to originate TLS to; matching any one of them is enough. The server name
+    /// sent and verified is the requested domain name, or the IP connected to for requests
+    /// made by address.
+    pub destinations: Vec<AccessTarget>,
+}
+
+impl OutboundTls {
+    pub(crate) fn applies_to(&self, domain: Option<&str>, addr: SocketAddr) -> bool {
+        self.destinations
+            .iter()
+            .any(|target| target.matches(domain, addr))
+    }
+
+    pub(crate) async fn connect(
+        &self,
+        domain: Option<&str>,
+        conn: TcpStream,
+    ) -> io::Result<TlsStream<TcpStream>> {
+        let server_name = match domain {
+            Some(domain) => ServerName::try_from(domain.to_string())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            None => ServerName::IpAddress(conn.peer_addr()?.ip().into()),
+        };
+
+        TlsConnector::from(Arc::clone(&self.client_config))
+            .connect(server_name, conn)
+            .await
+    }
+}
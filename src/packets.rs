@@ -1,8 +1,9 @@
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-const SOCKS_VERSION: u8 = 5;
+pub(crate) const SOCKS_VERSION: u8 = 5;
 const RESERVED: u8 = 0;
-const USER_PASSWORD_AUTH_VERSION: u8 = 1;
+pub(crate) const USER_PASSWORD_AUTH_VERSION: u8 = 1;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthMethod {
@@ -26,13 +27,25 @@ impl TryFrom<u8> for AuthMethod {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Type of the destination address in a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum AddressType {
     Ipv4 = 1,
     DomainName = 3,
     Ipv6 = 4,
 }
 
+impl AddressType {
+    /// Short snake_case name of the address type, e.g. for metric labels.
+    pub fn label(&self) -> &'static str {
+        match self {
+            AddressType::Ipv4 => "ipv4",
+            AddressType::DomainName => "domain_name",
+            AddressType::Ipv6 => "ipv6",
+        }
+    }
+}
+
 impl TryFrom<u8> for AddressType {
     type Error = ();
 
@@ -46,17 +59,56 @@ impl TryFrom<u8> for AddressType {
     }
 }
 
-#[derive(Debug)]
+/// Address of a request's destination, as sent by the client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DestinationAddress {
     Ipv4(Ipv4Addr),
     Ipv6(Ipv6Addr),
     DomainName(String),
 }
 
+impl DestinationAddress {
+    pub fn address_type(&self) -> AddressType {
+        match self {
+            DestinationAddress::Ipv4(_) => AddressType::Ipv4,
+            DestinationAddress::Ipv6(_) => AddressType::Ipv6,
+            DestinationAddress::DomainName(_) => AddressType::DomainName,
+        }
+    }
+
+    // Appends the address followed by `port`, as replies and UDP headers carry them, i.e.
+    // with domain names prefixed by their length. The address type isn't included.
+    pub(crate) fn write(&self, port: u16, packet: &mut Vec<u8>) {
+        match self {
+            DestinationAddress::Ipv4(v4_addr) => packet.extend_from_slice(&v4_addr.octets()),
+            DestinationAddress::Ipv6(v6_addr) => packet.extend_from_slice(&v6_addr.octets()),
+            DestinationAddress::DomainName(domain) => {
+                packet.push(domain.len() as u8);
+                packet.extend_from_slice(domain.as_bytes());
+            }
+        }
+        packet.extend_from_slice(&port.to_be_bytes());
+    }
+}
+
+// IPv6 addresses are wrapped in brackets so that `{addr}:{port}` is unambiguous.
+impl fmt::Display for DestinationAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DestinationAddress::Ipv4(v4_addr) => write!(f, "{}", v4_addr),
+            DestinationAddress::Ipv6(v6_addr) => write!(f, "[{}]", v6_addr),
+            DestinationAddress::DomainName(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
 pub mod client_hello;
 pub mod client_request;
 pub mod client_user_pass_auth;
 pub mod errors;
+pub mod proxy_header;
 pub mod server_hello;
 pub mod server_reply;
 pub mod server_user_pass_response;
+pub mod tls_client_hello;
+pub mod udp_relay;
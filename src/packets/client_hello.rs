@@ -1,10 +1,37 @@
 use super::errors::ClientHelloError;
 use super::{AuthMethod, SOCKS_VERSION};
 
+// First byte of a TLS record carrying a handshake message, such as a ClientHello.
+const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"HEAD ",
+    b"PUT ",
+    b"DELETE ",
+    b"CONNECT ",
+    b"OPTIONS ",
+    b"TRACE ",
+    b"PATCH ",
+];
+
 #[derive(Debug)]
 pub struct ClientHello {
     pub version: u8,
     pub methods: Vec<AuthMethod>,
+    // Method bytes offered that don't correspond to any `AuthMethod`, e.g. private methods.
+    pub unknown_methods: Vec<u8>,
+    // Number of method bytes dropped because they repeat one offered earlier in the packet.
+    pub duplicate_methods: usize,
+    // Number of bytes the packet takes up on the wire.
+    pub len: usize,
+}
+
+// Whether `raw_packet` starts like an HTTP method but is too short yet to tell which one.
+pub fn is_partial_http_method(raw_packet: &[u8]) -> bool {
+    HTTP_METHODS
+        .iter()
+        .any(|method| method.len() > raw_packet.len() && method.starts_with(raw_packet))
 }
 
 impl ClientHello {
@@ -15,27 +42,134 @@ impl ClientHello {
     // | 1  |    1     | 1 to 255 |
     // +----+----------+----------+
     pub fn new(raw_packet: &[u8]) -> Result<Self, ClientHelloError> {
-        if raw_packet.len() < 3 {
-            return Err(ClientHelloError::MalformedPacket);
+        if let Some(&version) = raw_packet.first() {
+            if version != SOCKS_VERSION {
+                // Point out the usual suspects of a client configured for the wrong protocol.
+                if version == TLS_HANDSHAKE_RECORD {
+                    return Err(ClientHelloError::UnexpectedTlsHandshake);
+                }
+                if HTTP_METHODS
+                    .iter()
+                    .any(|method| raw_packet.starts_with(method))
+                {
+                    return Err(ClientHelloError::UnexpectedHttpRequest);
+                }
+
+                return Err(ClientHelloError::UnexpectedProtocolVersion(version));
+            }
         }
 
-        let version = raw_packet[0];
-        if version != SOCKS_VERSION {
-            return Err(ClientHelloError::UnexpectedProtocolVersion(version));
+        if raw_packet.len() < 3 {
+            return Err(ClientHelloError::MalformedPacket {
+                offset: raw_packet.len(),
+                reason: format!(
+                    "packet is {} bytes long, shorter than the 3-byte minimum",
+                    raw_packet.len()
+                ),
+            });
         }
+        let version = raw_packet[0];
 
-        let n_methods = raw_packet[1];
+        let n_methods = raw_packet[1] as usize;
         if n_methods == 0 {
-            return Err(ClientHelloError::MalformedPacket);
+            return Err(ClientHelloError::MalformedPacket {
+                offset: 1,
+                reason: "NMETHODS is 0".to_string(),
+            });
+        }
+
+        // Only the methods NMETHODS announces belong to the hello. Anything after them is the
+        // next packet of a client that didn't wait for the answer, and is left to that stage.
+        let methods_present = raw_packet.len() - 2;
+        if methods_present < n_methods {
+            return Err(ClientHelloError::MalformedPacket {
+                offset: 2,
+                reason: format!(
+                    "NMETHODS claims {} but only {} bytes present",
+                    n_methods, methods_present
+                ),
+            });
         }
 
-        let mut methods = Vec::with_capacity(n_methods as usize);
-        for &method in &raw_packet[2..] {
-            if let Ok(method) = AuthMethod::try_from(method) {
-                methods.push(method);
+        // Only the first occurrence of each method is kept, so that the offered methods form a
+        // set in the order the client listed them.
+        let mut methods = Vec::with_capacity(n_methods);
+        let mut unknown_methods = Vec::new();
+        let mut duplicate_methods = 0;
+        for (i, &method) in raw_packet[2..n_methods + 2].iter().enumerate() {
+            if raw_packet[2..i + 2].contains(&method) {
+                duplicate_methods += 1;
+                continue;
+            }
+            match AuthMethod::try_from(method) {
+                Ok(method) => methods.push(method),
+                Err(_) => unknown_methods.push(method),
             }
         }
 
-        Ok(Self { version, methods })
+        Ok(Self {
+            version,
+            methods,
+            unknown_methods,
+            duplicate_methods,
+            len: n_methods + 2,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn malformed(raw_packet: &[u8]) -> (usize, String) {
+        match ClientHello::new(raw_packet) {
+            Err(ClientHelloError::MalformedPacket { offset, reason }) => (offset, reason),
+            other => panic!("expected a malformed packet error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_offset_of_missing_methods() {
+        assert_eq!(
+            malformed(&[5, 5, 0, 2]),
+            (2, "NMETHODS claims 5 but only 2 bytes present".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_only_announced_methods() {
+        let hello = ClientHello::new(&[5, 1, 0, 5, 1, 0, 1]).unwrap();
+
+        assert_eq!(hello.methods, vec![AuthMethod::NoAuth]);
+        assert_eq!(hello.len, 3);
+    }
+
+    #[test]
+    fn reports_offset_of_zero_nmethods() {
+        assert_eq!(malformed(&[5, 0, 0]), (1, "NMETHODS is 0".to_string()));
+    }
+
+    #[test]
+    fn reports_offset_of_short_packet() {
+        assert_eq!(
+            malformed(&[5, 1]),
+            (
+                2,
+                "packet is 2 bytes long, shorter than the 3-byte minimum".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn drops_repeated_methods() {
+        let hello = ClientHello::new(&[5, 6, 2, 0, 2, 0x80, 0x80, 0]).unwrap();
+
+        assert_eq!(
+            hello.methods,
+            vec![AuthMethod::UserPassword, AuthMethod::NoAuth]
+        );
+        assert_eq!(hello.unknown_methods, vec![0x80]);
+        assert_eq!(hello.duplicate_methods, 3);
+        assert_eq!(hello.len, 8);
     }
 }
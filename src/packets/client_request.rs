@@ -3,7 +3,8 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 use super::errors::ClientRequestError;
 use super::{AddressType, DestinationAddress, SOCKS_VERSION};
 
-#[derive(Debug, PartialEq, Eq)]
+/// Command of a client request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RequestCommand {
     Connect = 1,
     Bind,
@@ -25,10 +26,13 @@ impl TryFrom<u8> for RequestCommand {
 
 #[derive(Debug)]
 pub struct ClientRequest {
+    #[allow(unused)]
     pub version: u8,
     pub command: RequestCommand,
     pub destination_addr: DestinationAddress,
     pub destination_port: u16,
+    // Number of bytes the packet takes up on the wire.
+    pub len: usize,
 }
 
 impl ClientRequest {
@@ -39,8 +43,16 @@ impl ClientRequest {
     // | 1  |  1  | X'00' |  1   | Variable |    2     |
     // +----+-----+-------+------+----------+----------+
     pub fn new(raw_packet: &[u8]) -> Result<Self, ClientRequestError> {
-        if raw_packet.len() < 10 {
-            return Err(ClientRequestError::MalformedPacket);
+        // Everything up to ATYP plus the first byte of DST.ADDR, which for domain names holds
+        // their length. The full length is only known once that byte has been read.
+        if raw_packet.len() < 5 {
+            return Err(ClientRequestError::MalformedPacket {
+                offset: raw_packet.len(),
+                reason: format!(
+                    "packet is {} bytes long, shorter than the 5-byte minimum",
+                    raw_packet.len()
+                ),
+            });
         }
 
         let version = raw_packet[0];
@@ -48,14 +60,10 @@ impl ClientRequest {
             return Err(ClientRequestError::UnexpectedProtocolVersion(version));
         }
 
+        // Whether the command is supported is decided by the caller, so that requests for
+        // unsupported commands are still parsed in full and can be logged with their destination.
         let command = raw_packet[1];
         let command = if let Ok(cmd) = RequestCommand::try_from(command) {
-            if cmd == RequestCommand::Bind {
-                return Err(ClientRequestError::ErrUnsupportedBindCommand);
-            } else if cmd == RequestCommand::UdpAssociate {
-                return Err(ClientRequestError::ErrUnsupportedUDPAssociateCommand);
-            }
-
             cmd
         } else {
             return Err(ClientRequestError::ErrUnknownCommand);
@@ -71,6 +79,37 @@ impl ClientRequest {
             return Err(ClientRequestError::ErrUnknownAddressType);
         };
 
+        // DST.ADDR starts at offset 4 and must be followed by the 2-byte DST.PORT.
+        let (address_len, address_description) = match address_type {
+            AddressType::Ipv4 => (4, "IPv4 address".to_string()),
+            AddressType::Ipv6 => (16, "IPv6 address".to_string()),
+            AddressType::DomainName => {
+                let domain_name_len = raw_packet[4] as usize;
+                if domain_name_len == 0 {
+                    return Err(ClientRequestError::MalformedPacket {
+                        offset: 4,
+                        reason: "domain name is empty".to_string(),
+                    });
+                }
+                (
+                    domain_name_len + 1,
+                    format!("domain name of {} bytes", domain_name_len),
+                )
+            }
+        };
+        let bytes_present = raw_packet.len() - 4;
+        if bytes_present < address_len + 2 {
+            return Err(ClientRequestError::MalformedPacket {
+                offset: 4,
+                reason: format!(
+                    "{} and port need {} bytes but only {} bytes present",
+                    address_description,
+                    address_len + 2,
+                    bytes_present
+                ),
+            });
+        }
+
         let destination_addr = match address_type {
             AddressType::Ipv4 => {
                 let mut octets = [0; 4];
@@ -87,20 +126,192 @@ impl ClientRequest {
             AddressType::DomainName => {
                 let domain_name_len = raw_packet[4] as usize;
 
-                let domain =
-                    String::from_utf8(raw_packet[5..domain_name_len + 5].to_vec()).unwrap();
+                let domain = String::from_utf8(raw_packet[5..domain_name_len + 5].to_vec())
+                    .map_err(|e| ClientRequestError::MalformedPacket {
+                        offset: 5 + e.utf8_error().valid_up_to(),
+                        reason: "domain name is not valid UTF-8".to_string(),
+                    })?;
 
                 DestinationAddress::DomainName(domain)
             }
         };
 
-        let destination_port: Vec<u8> = raw_packet.iter().rev().cloned().take(2).rev().collect();
+        let port_offset = 4 + address_len;
+        let destination_port =
+            u16::from_be_bytes([raw_packet[port_offset], raw_packet[port_offset + 1]]);
 
         Ok(Self {
             version,
             command,
             destination_addr,
-            destination_port: u16::from_be_bytes(destination_port.try_into().unwrap()),
+            destination_port,
+            len: port_offset + 2,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bind_destination() {
+        let request = ClientRequest::new(&[5, 2, 0, 1, 203, 0, 113, 7, 0x1f, 0x90]).unwrap();
+
+        assert_eq!(request.command, RequestCommand::Bind);
+        assert_eq!(
+            request.destination_addr,
+            DestinationAddress::Ipv4(Ipv4Addr::new(203, 0, 113, 7))
+        );
+        assert_eq!(request.destination_port, 8080);
+    }
+
+    #[test]
+    fn parses_bind_domain_destination() {
+        let mut packet = vec![5, 2, 0, 3, 11];
+        packet.extend_from_slice(b"example.com");
+        packet.extend_from_slice(&21u16.to_be_bytes());
+
+        let request = ClientRequest::new(&packet).unwrap();
+
+        assert_eq!(request.command, RequestCommand::Bind);
+        assert_eq!(
+            request.destination_addr,
+            DestinationAddress::DomainName("example.com".to_string())
+        );
+        assert_eq!(request.destination_port, 21);
+    }
+
+    #[test]
+    fn parses_udp_associate_destination() {
+        let mut packet = vec![5, 3, 0, 4];
+        packet.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        packet.extend_from_slice(&5353u16.to_be_bytes());
+
+        let request = ClientRequest::new(&packet).unwrap();
+
+        assert_eq!(request.command, RequestCommand::UdpAssociate);
+        assert_eq!(
+            request.destination_addr,
+            DestinationAddress::Ipv6(Ipv6Addr::LOCALHOST)
+        );
+        assert_eq!(request.destination_port, 5353);
+    }
+
+    #[test]
+    fn rejects_unknown_command() {
+        assert!(matches!(
+            ClientRequest::new(&[5, 9, 0, 1, 127, 0, 0, 1, 0, 80]),
+            Err(ClientRequestError::ErrUnknownCommand)
+        ));
+    }
+
+    fn malformed(raw_packet: &[u8]) -> (usize, String) {
+        match ClientRequest::new(raw_packet) {
+            Err(ClientRequestError::MalformedPacket { offset, reason }) => (offset, reason),
+            other => panic!("expected a malformed packet error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_offset_of_truncated_ipv6_address() {
+        assert_eq!(
+            malformed(&[5, 1, 0, 4, 0x20, 0x01, 0x0d, 0xb8]),
+            (
+                4,
+                "IPv6 address and port need 18 bytes but only 4 bytes present".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn reports_offset_of_invalid_utf8_domain() {
+        let mut packet = vec![5, 1, 0, 3, 4];
+        packet.extend_from_slice(b"ab\xffc");
+        packet.extend_from_slice(&80u16.to_be_bytes());
+
+        assert_eq!(
+            malformed(&packet),
+            (7, "domain name is not valid UTF-8".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_offset_of_short_packet() {
+        assert_eq!(
+            malformed(&[5, 1, 0]),
+            (
+                3,
+                "packet is 3 bytes long, shorter than the 5-byte minimum".to_string()
+            )
+        );
+    }
+
+    fn domain_packet(domain: &[u8], port: &[u8]) -> Vec<u8> {
+        let mut packet = vec![5, 1, 0, 3, domain.len() as u8];
+        packet.extend_from_slice(domain);
+        packet.extend_from_slice(port);
+        packet
+    }
+
+    #[test]
+    fn parses_maximal_domain_name_and_port() {
+        let domain = "a".repeat(255);
+
+        let request = ClientRequest::new(&domain_packet(domain.as_bytes(), &[0xff, 0xfe])).unwrap();
+
+        assert_eq!(
+            request.destination_addr,
+            DestinationAddress::DomainName(domain)
+        );
+        assert_eq!(request.destination_port, 0xfffe);
+    }
+
+    #[test]
+    fn rejects_empty_domain_name() {
+        assert_eq!(
+            malformed(&domain_packet(b"", &[0, 80])),
+            (4, "domain name is empty".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_maximal_domain_name_truncated_inside_address() {
+        let mut packet = domain_packet(&[b'a'; 255], &[0, 80]);
+        packet.truncate(5 + 200);
+
+        assert_eq!(
+            malformed(&packet),
+            (
+                4,
+                "domain name of 255 bytes and port need 258 bytes but only 201 bytes present"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_maximal_domain_name_truncated_inside_port() {
+        let packet = domain_packet(&[b'a'; 255], &[0]);
+
+        assert_eq!(
+            malformed(&packet),
+            (
+                4,
+                "domain name of 255 bytes and port need 258 bytes but only 257 bytes present"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_ipv4_request_truncated_inside_port() {
+        assert_eq!(
+            malformed(&[5, 1, 0, 1, 127, 0, 0, 1, 0]),
+            (
+                4,
+                "IPv4 address and port need 6 bytes but only 5 bytes present".to_string()
+            )
+        );
+    }
+}
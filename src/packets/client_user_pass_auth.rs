@@ -4,9 +4,12 @@ use super::{errors::UserPassAuthError, USER_PASSWORD_AUTH_VERSION};
 
 #[derive(Debug)]
 pub struct ClientUserPassAuth {
+    #[allow(unused)]
     pub version: u8,
     pub username: String,
     pub password: String,
+    // Number of bytes the packet takes up on the wire.
+    pub len: usize,
 }
 
 impl ClientUserPassAuth {
@@ -18,7 +21,13 @@ impl ClientUserPassAuth {
     // +----+------+----------+------+----------+
     pub fn new(raw_packet: &[u8]) -> Result<Self, UserPassAuthError> {
         if raw_packet.len() < 5 {
-            return Err(UserPassAuthError::MalformedPacket);
+            return Err(UserPassAuthError::MalformedPacket {
+                offset: raw_packet.len(),
+                reason: format!(
+                    "packet is {} bytes long, shorter than the 5-byte minimum",
+                    raw_packet.len()
+                ),
+            });
         }
 
         let version = raw_packet[0];
@@ -27,20 +36,101 @@ impl ClientUserPassAuth {
         }
 
         let username_len = raw_packet[1] as usize;
+        // The username must be followed by at least the PLEN byte.
+        if raw_packet.len() < username_len + 3 {
+            return Err(UserPassAuthError::MalformedPacket {
+                offset: 2,
+                reason: format!(
+                    "ULEN claims {} but only {} bytes present",
+                    username_len,
+                    raw_packet.len() - 2
+                ),
+            });
+        }
         let username = str::from_utf8(&raw_packet[2..username_len + 2])
-            .unwrap()
+            .map_err(|e| UserPassAuthError::MalformedPacket {
+                offset: 2 + e.valid_up_to(),
+                reason: "username is not valid UTF-8".to_string(),
+            })?
             .to_string();
 
         let password_len = raw_packet[username_len + 2] as usize;
-        let password =
-            str::from_utf8(&raw_packet[username_len + 3..password_len + username_len + 3])
-                .unwrap()
-                .to_string();
+        let password_offset = username_len + 3;
+        if raw_packet.len() < password_offset + password_len {
+            return Err(UserPassAuthError::MalformedPacket {
+                offset: password_offset,
+                reason: format!(
+                    "PLEN claims {} but only {} bytes present",
+                    password_len,
+                    raw_packet.len() - password_offset
+                ),
+            });
+        }
+        let password = str::from_utf8(&raw_packet[password_offset..password_offset + password_len])
+            .map_err(|e| UserPassAuthError::MalformedPacket {
+                offset: password_offset + e.valid_up_to(),
+                reason: "password is not valid UTF-8".to_string(),
+            })?
+            .to_string();
 
         Ok(Self {
             version,
             username,
             password,
+            len: password_offset + password_len,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn malformed(raw_packet: &[u8]) -> (usize, String) {
+        match ClientUserPassAuth::new(raw_packet) {
+            Err(UserPassAuthError::MalformedPacket { offset, reason }) => (offset, reason),
+            other => panic!("expected a malformed packet error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reports_offset_of_truncated_username() {
+        assert_eq!(
+            malformed(&[1, 10, b'a', b'l', b'i']),
+            (2, "ULEN claims 10 but only 3 bytes present".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_offset_of_truncated_password() {
+        assert_eq!(
+            malformed(&[1, 1, b'a', 8, b'p', b'w']),
+            (4, "PLEN claims 8 but only 2 bytes present".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_offset_of_invalid_utf8_username() {
+        assert_eq!(
+            malformed(&[1, 3, b'a', 0xff, b'b', 2, b'p', b'w']),
+            (3, "username is not valid UTF-8".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_offset_of_invalid_utf8_password() {
+        assert_eq!(
+            malformed(&[1, 1, b'a', 3, b'p', 0xc3, 0x28]),
+            (5, "password is not valid UTF-8".to_string())
+        );
+    }
+
+    #[test]
+    fn parses_credentials() {
+        let auth = ClientUserPassAuth::new(&[1, 3, b'b', b'o', b'b', 2, b'p', b'w']).unwrap();
+
+        assert_eq!(auth.username, "bob");
+        assert_eq!(auth.password, "pw");
+        assert_eq!(auth.len, 8);
+    }
+}
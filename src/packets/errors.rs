@@ -1,32 +1,49 @@
 use std::io;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::time::Duration;
 use thiserror::Error;
 
 use super::{SOCKS_VERSION, USER_PASSWORD_AUTH_VERSION};
 
 #[derive(Debug, Error)]
 pub enum ClientHelloError {
-    #[error("malformed client hello packet")]
-    MalformedPacket,
+    #[error("malformed client hello packet at offset {offset}: {reason}")]
+    MalformedPacket { offset: usize, reason: String },
     #[error("expected protocol version to be {}, but received {0}", SOCKS_VERSION)]
     UnexpectedProtocolVersion(u8),
+    #[error("received what looks like a TLS ClientHello on the SOCKS port")]
+    UnexpectedTlsHandshake,
+    #[error("received what looks like an HTTP request on the SOCKS port")]
+    UnexpectedHttpRequest,
+    #[error(transparent)]
+    HandshakeBudget(#[from] HandshakeBudgetError),
     #[error("failed IO operation: {0}")]
     IoError(#[from] io::Error),
 }
 
+#[derive(Debug, Error)]
+pub enum HandshakeBudgetError {
+    #[error("client sent more than {0} handshake packets")]
+    TooManyPackets(usize),
+    #[error("client sent more than {0} bytes of handshake packets")]
+    TooManyBytes(usize),
+}
+
 #[derive(Debug, Error)]
 pub enum ServerHelloError {
-    #[error("no authentication method is acceptable")]
-    NoAcceptableAuth,
-    #[error("user/pass authentication failed: {0}")]
-    AuthError(#[from] UserPassAuthError),
+    #[error(
+        "no authentication method is acceptable: client offered [{offered}], server accepts [{accepted}]"
+    )]
+    NoAcceptableAuth { offered: String, accepted: String },
     #[error("failed IO operation: {0}")]
     IoError(#[from] io::Error),
 }
 
 #[derive(Debug, Error)]
 pub enum UserPassAuthError {
-    #[error("malformed client user/password auth packet")]
-    MalformedPacket,
+    #[error("malformed client user/password auth packet at offset {offset}: {reason}")]
+    MalformedPacket { offset: usize, reason: String },
     #[error(
         "expected user/password auth version to be {}, but received {0}",
         USER_PASSWORD_AUTH_VERSION
@@ -34,14 +51,18 @@ pub enum UserPassAuthError {
     UnexpectedUserPassAuthVersion(u8),
     #[error("user and password did not match")]
     FailedAuth,
+    #[error("no credentials received within {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    HandshakeBudget(#[from] HandshakeBudgetError),
     #[error("failed IO operation: {0}")]
     IoError(#[from] io::Error),
 }
 
 #[derive(Debug, Error)]
 pub enum ClientRequestError {
-    #[error("malformed client request packet")]
-    MalformedPacket,
+    #[error("malformed client request packet at offset {offset}: {reason}")]
+    MalformedPacket { offset: usize, reason: String },
     #[error("expected protocol version to be {}, but received {0}", SOCKS_VERSION)]
     UnexpectedProtocolVersion(u8),
     #[error("unsupported BIND command")]
@@ -52,12 +73,109 @@ pub enum ClientRequestError {
     ErrUnknownCommand,
     #[error("unknown address type")]
     ErrUnknownAddressType,
+    #[error(transparent)]
+    HandshakeBudget(#[from] HandshakeBudgetError),
     #[error("failed IO operation: {0}")]
     IoError(#[from] io::Error),
 }
 
 #[derive(Debug, Error)]
 pub enum ServerReplyError {
+    #[error("no free source port in the configured outbound range")]
+    SourcePortsExhausted,
+    #[error("{0}")]
+    Resolve(#[from] ResolveError),
+    #[error("destination {0} is one of the server's own listen addresses")]
+    ConnectionToSelf(SocketAddr),
+    #[error("access control rules deny connecting to {0}")]
+    ConnectionNotAllowed(SocketAddr),
+    #[error("too many connections to {0} already")]
+    DestinationLimitReached(SocketAddr),
+    #[error("destination {0} is the unspecified address")]
+    UnspecifiedDestination(SocketAddr),
+    #[error("user {0} used up their byte quota")]
+    UserQuotaExceeded(String),
+    #[error("destination port is 0")]
+    ZeroDestinationPort,
+    #[error("destination {0} closed the connection right after accepting it")]
+    DestinationHungUp(SocketAddr),
+    #[error("too many BIND requests waiting for their peer already")]
+    BindLimitReached,
+    #[error("no peer connected to the BIND listener in time")]
+    BindTimeout,
+    #[error("{0} is not the peer the BIND request expects")]
+    BindPeerMismatch(SocketAddr),
+    #[error("too many UDP associations open already")]
+    AssociationLimitReached,
+    #[cfg(feature = "tls")]
+    #[error("TLS handshake with {0} failed: {1}")]
+    TlsHandshake(SocketAddr, io::Error),
+    #[error("failed IO operation: {0}")]
+    IoError(#[from] io::Error),
+}
+
+// Why a datagram from a client can't be relayed. Such datagrams are dropped.
+#[derive(Debug, Error)]
+pub enum UdpHeaderError {
+    #[error("datagram of {0} bytes is too short for its header")]
+    Truncated(usize),
+    #[error("fragmented datagrams are not supported (FRAG {0})")]
+    Fragmented(u8),
+    #[error("unknown address type {0}")]
+    UnknownAddressType(u8),
+    #[error("domain name is not valid UTF-8")]
+    InvalidDomainName,
+}
+
+/// Why resolving a domain name destination failed, as returned by `Resolver::resolve`.
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    /// The domain name does not exist (NXDOMAIN). Answered with a host unreachable reply.
+    #[error("domain name does not exist")]
+    NxDomain,
+    /// No answer came in time. Answered with a TTL expired reply, which is also what requests
+    /// get when `Config::resolve_timeout` runs out.
+    #[error("resolving the destination timed out")]
+    Timeout,
+    /// Any other failure, such as SERVFAIL. Answered with a general server failure reply.
+    #[error("resolving the destination failed: {0}")]
+    Failed(io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum ProxyHeaderError {
+    #[error("expected a PROXY protocol header, but none was received")]
+    MissingHeader,
+    #[error("malformed PROXY protocol header")]
+    MalformedPacket,
+    #[error("unsupported PROXY protocol version {0}")]
+    UnsupportedVersion(u8),
+    #[error("no PROXY protocol header received within {0:?}")]
+    Timeout(Duration),
     #[error("failed IO operation: {0}")]
     IoError(#[from] io::Error),
 }
+
+/// Problem found by `Config::validate`.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("`{0}` is 0, which would refuse every connection")]
+    ZeroLimit(&'static str),
+    #[error("`max_connections_per_ip` ({per_ip}) is above `max_connections` ({total})")]
+    PerIpLimitAboveTotal { per_ip: usize, total: usize },
+    #[error("`relay_chunk_size` must not be 0")]
+    ZeroRelayChunkSize,
+    #[error("`accept_gate_interval` must not be 0")]
+    ZeroAcceptGateInterval,
+    #[error("`max_resolved_addrs` is 0, so no domain name could ever be connected to")]
+    ZeroResolvedAddrs,
+    #[error("`outbound_source_port_range` {0:?} contains no port")]
+    EmptySourcePortRange(RangeInclusive<u16>),
+    #[error("`{0}` is set but has no effect when a connector is configured")]
+    IgnoredByConnector(&'static str),
+    #[error("`{setting}` has no effect unless `{requires}` is set")]
+    MissingPrerequisite {
+        setting: &'static str,
+        requires: &'static str,
+    },
+}
@@ -0,0 +1,213 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str;
+
+use super::errors::ProxyHeaderError;
+
+pub const PROXY_V1_PREFIX: &[u8] = b"PROXY ";
+pub const PROXY_V1_MAX_LEN: usize = 107;
+pub const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+#[derive(Debug)]
+pub struct ProxyHeader {
+    pub version: u8,
+    // `None` when the header does not carry an address (v1 `UNKNOWN`, v2 `LOCAL`
+    // or an unspecified address family), in which case the socket address is kept.
+    pub source_addr: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    pub fn new(raw_packet: &[u8]) -> Result<Self, ProxyHeaderError> {
+        if raw_packet.starts_with(&PROXY_V2_SIGNATURE) {
+            Self::parse_v2(raw_packet)
+        } else if raw_packet.starts_with(PROXY_V1_PREFIX) {
+            Self::parse_v1(raw_packet)
+        } else {
+            Err(ProxyHeaderError::MissingHeader)
+        }
+    }
+
+    // Raw packet is a single CRLF-terminated line of at most 107 bytes:
+    // PROXY <TCP4|TCP6|UNKNOWN> <SRC ADDR> <DST ADDR> <SRC PORT> <DST PORT>\r\n
+    fn parse_v1(raw_packet: &[u8]) -> Result<Self, ProxyHeaderError> {
+        if raw_packet.len() > PROXY_V1_MAX_LEN || !raw_packet.ends_with(b"\r\n") {
+            return Err(ProxyHeaderError::MalformedPacket);
+        }
+
+        let line = str::from_utf8(&raw_packet[..raw_packet.len() - 2])
+            .map_err(|_| ProxyHeaderError::MalformedPacket)?;
+        let fields: Vec<&str> = line.split(' ').collect();
+
+        let source_addr = match fields.get(1) {
+            Some(&"UNKNOWN") => None,
+            Some(&"TCP4") | Some(&"TCP6") if fields.len() == 6 => {
+                let ip = fields[2]
+                    .parse::<IpAddr>()
+                    .map_err(|_| ProxyHeaderError::MalformedPacket)?;
+                let port = fields[4]
+                    .parse::<u16>()
+                    .map_err(|_| ProxyHeaderError::MalformedPacket)?;
+
+                Some(SocketAddr::new(ip, port))
+            }
+            _ => return Err(ProxyHeaderError::MalformedPacket),
+        };
+
+        Ok(Self {
+            version: 1,
+            source_addr,
+        })
+    }
+
+    // Raw packet has the following structure:
+    // +-----------+---------+-----+-----+-----------+
+    // | SIGNATURE | VER/CMD | FAM | LEN | ADDRESSES |
+    // +-----------+---------+-----+-----+-----------+
+    // |    12     |    1    |  1  |  2  |    LEN    |
+    // +-----------+---------+-----+-----+-----------+
+    fn parse_v2(raw_packet: &[u8]) -> Result<Self, ProxyHeaderError> {
+        if raw_packet.len() < 16 {
+            return Err(ProxyHeaderError::MalformedPacket);
+        }
+
+        let version = raw_packet[12] >> 4;
+        if version != 2 {
+            return Err(ProxyHeaderError::UnsupportedVersion(version));
+        }
+
+        let addresses_len = u16::from_be_bytes([raw_packet[14], raw_packet[15]]) as usize;
+        let addresses = &raw_packet[16..];
+        if addresses.len() != addresses_len {
+            return Err(ProxyHeaderError::MalformedPacket);
+        }
+
+        let command = raw_packet[12] & 0x0F;
+        let family = raw_packet[13] >> 4;
+
+        // LOCAL connections (health checks from the proxy itself) carry no address.
+        if command == 0 {
+            return Ok(Self {
+                version,
+                source_addr: None,
+            });
+        }
+
+        let source_addr = match family {
+            // AF_INET: src addr (4), dst addr (4), src port (2), dst port (2)
+            1 => {
+                if addresses.len() < 12 {
+                    return Err(ProxyHeaderError::MalformedPacket);
+                }
+
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&addresses[0..4]);
+                let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+
+                Some(SocketAddr::from((Ipv4Addr::from(octets), port)))
+            }
+            // AF_INET6: src addr (16), dst addr (16), src port (2), dst port (2)
+            2 => {
+                if addresses.len() < 36 {
+                    return Err(ProxyHeaderError::MalformedPacket);
+                }
+
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addresses[0..16]);
+                let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+
+                Some(SocketAddr::from((Ipv6Addr::from(octets), port)))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            version,
+            source_addr,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2_packet(ver_cmd: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut packet = PROXY_V2_SIGNATURE.to_vec();
+        packet.extend_from_slice(&[ver_cmd, family]);
+        packet.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        packet.extend_from_slice(addresses);
+        packet
+    }
+
+    #[test]
+    fn parses_v1_tcp6_source() {
+        let header = ProxyHeader::new(b"PROXY TCP6 2001:db8::1 2001:db8::2 4242 1080\r\n").unwrap();
+
+        assert_eq!(header.version, 1);
+        assert_eq!(
+            header.source_addr,
+            Some("[2001:db8::1]:4242".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn v1_unknown_keeps_socket_address() {
+        let header = ProxyHeader::new(b"PROXY UNKNOWN\r\n").unwrap();
+
+        assert_eq!(header.source_addr, None);
+    }
+
+    #[test]
+    fn rejects_malformed_v1() {
+        for packet in [
+            &b"PROXY TCP4 203.0.113.7 10.0.0.1 4242 1080"[..],
+            b"PROXY TCP4 not-an-ip 10.0.0.1 4242 1080\r\n",
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 70000 1080\r\n",
+            b"PROXY UDP4 203.0.113.7 10.0.0.1 4242 1080\r\n",
+        ] {
+            assert!(matches!(
+                ProxyHeader::new(packet),
+                Err(ProxyHeaderError::MalformedPacket)
+            ));
+        }
+    }
+
+    #[test]
+    fn parses_v2_tcp6_source() {
+        let mut addresses = Vec::new();
+        addresses.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&"2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        addresses.extend_from_slice(&4242u16.to_be_bytes());
+        addresses.extend_from_slice(&1080u16.to_be_bytes());
+
+        let header = ProxyHeader::new(&v2_packet(0x21, 0x21, &addresses)).unwrap();
+
+        assert_eq!(header.version, 2);
+        assert_eq!(
+            header.source_addr,
+            Some("[2001:db8::1]:4242".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn v2_local_keeps_socket_address() {
+        let header = ProxyHeader::new(&v2_packet(0x20, 0x00, &[])).unwrap();
+
+        assert_eq!(header.source_addr, None);
+    }
+
+    #[test]
+    fn rejects_unsupported_v2_version() {
+        assert!(matches!(
+            ProxyHeader::new(&v2_packet(0x11, 0x11, &[0; 12])),
+            Err(ProxyHeaderError::UnsupportedVersion(1))
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_header() {
+        assert!(matches!(
+            ProxyHeader::new(b"\x05\x01\x00"),
+            Err(ProxyHeaderError::MissingHeader)
+        ));
+    }
+}
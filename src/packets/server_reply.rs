@@ -2,23 +2,36 @@ use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
 use super::{AddressType, DestinationAddress, RESERVED, SOCKS_VERSION};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Reply {
     Succeeded = 0,
     SocksServerFail,
-    #[allow(unused)]
     ConnNotAllowed,
-    #[allow(unused)]
     NetUnreachable,
-    #[allow(unused)]
     HostUnreachable,
     ConnRefused,
-    #[allow(unused)]
     TTLExpired,
     CmdNotSupported,
     AddrTypeNotSupported,
 }
 
+impl Reply {
+    /// Short snake_case name of the reply, e.g. for metric labels.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Reply::Succeeded => "succeeded",
+            Reply::SocksServerFail => "general_failure",
+            Reply::ConnNotAllowed => "connection_not_allowed",
+            Reply::NetUnreachable => "network_unreachable",
+            Reply::HostUnreachable => "host_unreachable",
+            Reply::ConnRefused => "connection_refused",
+            Reply::TTLExpired => "ttl_expired",
+            Reply::CmdNotSupported => "command_not_supported",
+            Reply::AddrTypeNotSupported => "address_type_not_supported",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ServerReply {
     pub version: u8,
@@ -70,20 +83,59 @@ impl ServerReply {
             self.reserved,
             self.address_type as u8,
         ];
-        let port = u16::to_be_bytes(self.bound_port);
+        self.bound_address.write(self.bound_port, &mut packet);
+
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
 
-        match &self.bound_address {
-            DestinationAddress::Ipv4(v4_addr) => {
-                packet.extend_from_slice(v4_addr.octets().as_slice());
-                packet.extend_from_slice(port.as_slice());
+    use super::*;
+
+    // Reads back the bound address of a reply, checking the fields before it on the way.
+    fn parse_bound_addr(raw_packet: &[u8]) -> SocketAddr {
+        assert_eq!(
+            raw_packet[..3],
+            [SOCKS_VERSION, Reply::Succeeded as u8, RESERVED]
+        );
+        let (ip, port_offset) = match AddressType::try_from(raw_packet[3]) {
+            Ok(AddressType::Ipv4) => {
+                let octets: [u8; 4] = raw_packet[4..8].try_into().unwrap();
+                (IpAddr::from(octets), 8)
             }
-            DestinationAddress::Ipv6(v6_addr) => {
-                packet.extend_from_slice(v6_addr.octets().as_slice());
-                packet.extend_from_slice(port.as_slice());
+            Ok(AddressType::Ipv6) => {
+                let octets: [u8; 16] = raw_packet[4..20].try_into().unwrap();
+                (IpAddr::from(octets), 20)
             }
-            _ => {}
+            _ => panic!("unexpected bound address type {}", raw_packet[3]),
         };
+        assert_eq!(raw_packet.len(), port_offset + 2);
+        let port = u16::from_be_bytes([raw_packet[port_offset], raw_packet[port_offset + 1]]);
 
-        packet
+        SocketAddr::new(ip, port)
+    }
+
+    #[test]
+    fn round_trips_bound_address() {
+        let ips = [
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::BROADCAST),
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0xff00, 0x42)),
+        ];
+        for ip in ips {
+            for port in [0, 1, 256, 1080, 65535] {
+                let addr = SocketAddr::new(ip, port);
+
+                let reply = ServerReply::new_successful_reply(addr);
+
+                assert_eq!(parse_bound_addr(&reply.as_bytes()), addr);
+            }
+        }
     }
 }
@@ -0,0 +1,224 @@
+use std::str;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const SERVER_NAME_TYPE_HOST_NAME: u8 = 0;
+
+// What the first bytes of a connection say about the TLS server name (SNI) the client asks
+// for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ServerName {
+    // The bytes don't start a TLS ClientHello.
+    NotTls,
+    // A ClientHello, but not all of it has arrived yet.
+    Incomplete,
+    // A ClientHello without a usable server_name extension.
+    Missing,
+    Name(String),
+}
+
+// Finds the server name in a ClientHello, which may be split over several TLS records. Only
+// the bits needed to get to the server_name extension are looked at.
+pub fn parse_server_name(data: &[u8]) -> ServerName {
+    // Record layer: +------+---------+--------+----------+
+    //               | TYPE | VERSION | LENGTH | FRAGMENT |
+    //               +------+---------+--------+----------+
+    //               |  1   |    2    |   2    | variable |
+    //               +------+---------+--------+----------+
+    match data {
+        [CONTENT_TYPE_HANDSHAKE, 3, ..] | [CONTENT_TYPE_HANDSHAKE] => {}
+        _ => return ServerName::NotTls,
+    }
+
+    let mut handshake = Vec::new();
+    let mut records = data;
+    loop {
+        if records.len() < 5 {
+            return ServerName::Incomplete;
+        }
+        if records[0] != CONTENT_TYPE_HANDSHAKE {
+            return ServerName::Missing;
+        }
+        let fragment_len = u16::from_be_bytes([records[3], records[4]]) as usize;
+        let Some(fragment) = records.get(5..5 + fragment_len) else {
+            return ServerName::Incomplete;
+        };
+        handshake.extend_from_slice(fragment);
+        records = &records[5 + fragment_len..];
+
+        // Handshake message: TYPE (1), LENGTH (3), BODY.
+        if handshake.len() < 4 {
+            continue;
+        }
+        if handshake[0] != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return ServerName::NotTls;
+        }
+        let body_len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if let Some(body) = handshake.get(4..4 + body_len) {
+            return client_hello_server_name(body).map_or(ServerName::Missing, ServerName::Name);
+        }
+    }
+}
+
+// ClientHello body: VERSION (2), RANDOM (32), SESSION_ID (1 + n), CIPHER_SUITES (2 + n),
+// COMPRESSION_METHODS (1 + n), EXTENSIONS (2 + n), each extension being TYPE (2), LENGTH (2)
+// and DATA.
+fn client_hello_server_name(body: &[u8]) -> Option<String> {
+    let mut reader = Reader(body);
+    reader.take(2 + 32)?;
+    let session_id_len = reader.u8()? as usize;
+    reader.take(session_id_len)?;
+    let cipher_suites_len = reader.u16()? as usize;
+    reader.take(cipher_suites_len)?;
+    let compression_methods_len = reader.u8()? as usize;
+    reader.take(compression_methods_len)?;
+
+    let extensions_len = reader.u16()? as usize;
+    let mut extensions = Reader(reader.take(extensions_len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let extension_len = extensions.u16()? as usize;
+        let extension = extensions.take(extension_len)?;
+        if extension_type == EXTENSION_SERVER_NAME {
+            return server_name_extension_host(extension);
+        }
+    }
+
+    None
+}
+
+// server_name extension: LIST_LENGTH (2), then entries of NAME_TYPE (1), LENGTH (2) and NAME.
+fn server_name_extension_host(extension: &[u8]) -> Option<String> {
+    let mut reader = Reader(extension);
+    let list_len = reader.u16()? as usize;
+    let mut entries = Reader(reader.take(list_len)?);
+    while !entries.0.is_empty() {
+        let name_type = entries.u8()?;
+        let name_len = entries.u16()? as usize;
+        let name = entries.take(name_len)?;
+        if name_type == SERVER_NAME_TYPE_HOST_NAME {
+            return str::from_utf8(name).ok().map(str::to_string);
+        }
+    }
+
+    None
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+
+        Some(taken)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server_name_extension(name: &str) -> Vec<u8> {
+        let mut entry = vec![SERVER_NAME_TYPE_HOST_NAME];
+        entry.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        entry.extend_from_slice(name.as_bytes());
+        let mut extension = EXTENSION_SERVER_NAME.to_be_bytes().to_vec();
+        extension.extend_from_slice(&(entry.len() as u16 + 2).to_be_bytes());
+        extension.extend_from_slice(&(entry.len() as u16).to_be_bytes());
+        extension.extend_from_slice(&entry);
+        extension
+    }
+
+    // A ClientHello handshake message carrying `extensions`.
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = vec![3, 3];
+        body.extend_from_slice(&[0x5a; 32]);
+        body.extend_from_slice(&[0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+        handshake
+    }
+
+    // Wraps `handshake` in records carrying at most `max_fragment` bytes each.
+    fn records(handshake: &[u8], max_fragment: usize) -> Vec<u8> {
+        let mut records = Vec::new();
+        for fragment in handshake.chunks(max_fragment) {
+            records.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 3, 1]);
+            records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+            records.extend_from_slice(fragment);
+        }
+        records
+    }
+
+    #[test]
+    fn finds_server_name_after_other_extensions() {
+        let mut extensions = vec![0, 23, 0, 0];
+        extensions.extend_from_slice(&server_name_extension("example.com"));
+        let data = records(&client_hello(&extensions), 1 << 14);
+
+        assert_eq!(
+            parse_server_name(&data),
+            ServerName::Name("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn finds_server_name_split_over_records() {
+        let data = records(&client_hello(&server_name_extension("example.com")), 10);
+
+        assert_eq!(
+            parse_server_name(&data),
+            ServerName::Name("example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn waits_for_rest_of_client_hello() {
+        let data = records(
+            &client_hello(&server_name_extension("example.com")),
+            1 << 14,
+        );
+
+        for len in [1, 4, 5, data.len() - 1] {
+            assert_eq!(parse_server_name(&data[..len]), ServerName::Incomplete);
+        }
+    }
+
+    #[test]
+    fn reports_client_hello_without_server_name() {
+        let data = records(&client_hello(&[0, 23, 0, 0]), 1 << 14);
+
+        assert_eq!(parse_server_name(&data), ServerName::Missing);
+    }
+
+    #[test]
+    fn skips_traffic_that_is_not_tls() {
+        assert_eq!(parse_server_name(b"GET / HTTP/1.1\r\n"), ServerName::NotTls);
+        assert_eq!(
+            parse_server_name(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            ServerName::NotTls
+        );
+        // A handshake record that isn't a ClientHello.
+        assert_eq!(
+            parse_server_name(&records(&[2, 0, 0, 0], 1 << 14)),
+            ServerName::NotTls
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use super::errors::UdpHeaderError;
+use super::{AddressType, DestinationAddress, RESERVED};
+
+// Header in front of every datagram relayed over a UDP association. On datagrams from the
+// client it names where to send the payload, on datagrams to the client where it came from.
+#[derive(Debug)]
+pub struct UdpHeader {
+    pub destination_addr: DestinationAddress,
+    pub destination_port: u16,
+}
+
+impl UdpHeader {
+    // Raw datagram has the following structure:
+    // +-----+------+------+----------+----------+----------+
+    // | RSV | FRAG | ATYP | DST.ADDR | DST.PORT |   DATA   |
+    // +-----+------+------+----------+----------+----------+
+    // |  2  |  1   |  1   | Variable |    2     | Variable |
+    // +-----+------+------+----------+----------+----------+
+    // Fragmentation isn't supported, so only datagrams with FRAG set to 0 are accepted.
+    // Returns the header along with the payload.
+    pub fn parse(datagram: &[u8]) -> Result<(Self, &[u8]), UdpHeaderError> {
+        // Everything up to ATYP plus the first byte of DST.ADDR, which for domain names holds
+        // their length.
+        if datagram.len() < 5 {
+            return Err(UdpHeaderError::Truncated(datagram.len()));
+        }
+
+        let fragment = datagram[2];
+        if fragment != 0 {
+            return Err(UdpHeaderError::Fragmented(fragment));
+        }
+
+        let address_type = AddressType::try_from(datagram[3])
+            .map_err(|_| UdpHeaderError::UnknownAddressType(datagram[3]))?;
+        let address_len = match address_type {
+            AddressType::Ipv4 => 4,
+            AddressType::Ipv6 => 16,
+            AddressType::DomainName => datagram[4] as usize + 1,
+        };
+        let port_offset = 4 + address_len;
+        let Some(port) = datagram.get(port_offset..port_offset + 2) else {
+            return Err(UdpHeaderError::Truncated(datagram.len()));
+        };
+
+        let destination_addr = match address_type {
+            AddressType::Ipv4 => {
+                let mut octets = [0; 4];
+                octets.copy_from_slice(&datagram[4..8]);
+
+                DestinationAddress::Ipv4(Ipv4Addr::from(octets))
+            }
+            AddressType::Ipv6 => {
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&datagram[4..20]);
+
+                DestinationAddress::Ipv6(Ipv6Addr::from(octets))
+            }
+            AddressType::DomainName => String::from_utf8(datagram[5..port_offset].to_vec())
+                .map(DestinationAddress::DomainName)
+                .map_err(|_| UdpHeaderError::InvalidDomainName)?,
+        };
+
+        let header = Self {
+            destination_addr,
+            destination_port: u16::from_be_bytes([port[0], port[1]]),
+        };
+
+        Ok((header, &datagram[port_offset + 2..]))
+    }
+
+    // Puts the header for a datagram received from `source` in front of its `payload`, ready to
+    // be sent on to the client.
+    pub fn wrap(source: SocketAddr, payload: &[u8]) -> Vec<u8> {
+        let source_addr = match source.ip() {
+            IpAddr::V4(v4_addr) => DestinationAddress::Ipv4(v4_addr),
+            IpAddr::V6(v6_addr) => DestinationAddress::Ipv6(v6_addr),
+        };
+
+        let mut datagram = vec![RESERVED, RESERVED, 0, source_addr.address_type() as u8];
+        source_addr.write(source.port(), &mut datagram);
+        datagram.extend_from_slice(payload);
+
+        datagram
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(source: SocketAddr) {
+        let datagram = UdpHeader::wrap(source, b"payload");
+
+        let (header, payload) = UdpHeader::parse(&datagram).unwrap();
+        let expected_addr = match source.ip() {
+            IpAddr::V4(v4_addr) => DestinationAddress::Ipv4(v4_addr),
+            IpAddr::V6(v6_addr) => DestinationAddress::Ipv6(v6_addr),
+        };
+        assert_eq!(header.destination_addr, expected_addr);
+        assert_eq!(header.destination_port, source.port());
+        assert_eq!(payload, b"payload");
+    }
+
+    #[test]
+    fn round_trips_ipv4_source() {
+        round_trip("192.0.2.7:5353".parse().unwrap());
+    }
+
+    #[test]
+    fn round_trips_ipv6_source() {
+        round_trip("[2001:db8::7]:443".parse().unwrap());
+    }
+
+    #[test]
+    fn wraps_with_zero_reserved_and_fragment_bytes() {
+        let datagram = UdpHeader::wrap("192.0.2.7:53".parse().unwrap(), b"x");
+
+        assert_eq!(
+            datagram,
+            [0, 0, 0, AddressType::Ipv4 as u8, 192, 0, 2, 7, 0, 53, b'x']
+        );
+    }
+
+    #[test]
+    fn rejects_fragmented_datagrams() {
+        let mut datagram = UdpHeader::wrap("192.0.2.7:53".parse().unwrap(), b"x");
+        datagram[2] = 1;
+
+        assert!(matches!(
+            UdpHeader::parse(&datagram),
+            Err(UdpHeaderError::Fragmented(1))
+        ));
+    }
+}
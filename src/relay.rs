@@ -0,0 +1,1511 @@
+use std::fmt;
+use std::future::{self, Future};
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::{watch, Notify};
+use tokio::task;
+use tokio::time::{self, Instant};
+
+use crate::buffer_budget::BufferBudget;
+use crate::buffer_pool::{BufferPool, RelayBuffer};
+use crate::metrics::MetricsRegistry;
+use crate::user_quota::UserAccount;
+use crate::{set_keepalive, IdlePolicy, RemoteConn, ServerState};
+
+pub(crate) const DEFAULT_RELAY_CHUNK_SIZE: usize = 8 * 1024;
+// Minimum time between two progress updates of a relay, so that watching progress doesn't cost
+// a wakeup per chunk.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+// Window over which `Config::min_transfer_rate` is measured.
+const TRANSFER_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Why the relay of a connection ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// Both directions reached EOF or failed on their own.
+    Completed,
+    /// `Config::max_bytes_per_connection` was reached.
+    QuotaExceeded,
+    /// The authenticated user reached `Config::user_byte_quota`, whose `cut_off_relays` is set.
+    UserQuotaExceeded,
+    /// Neither side sent anything within `Config::first_byte_timeout`.
+    FirstByteTimeout,
+    /// No data moved in either direction for the time set by `IdlePolicy::Close`.
+    IdleTimeout,
+    /// Data was written out slower than `Config::min_transfer_rate`.
+    SlowLoris,
+    /// A write made no progress at all for `Config::write_stall_timeout`.
+    WriteStalled,
+    /// `Config::inspector` turned the connection down after looking at the client's first bytes.
+    Rejected,
+    /// `Config::access_control` denies the server name the client asked for in its TLS
+    /// ClientHello, see `Config::check_tls_server_name`.
+    ServerNameNotAllowed,
+    /// The relay was still running `Config::shutdown_grace` after the server started shutting
+    /// down.
+    Shutdown,
+    /// Reading from or writing to one of the sides failed, e.g. because the peer reset the
+    /// connection. When both directions fail, the client to remote one is reported.
+    Error(io::ErrorKind),
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Completed => write!(f, "relay completed"),
+            CloseReason::QuotaExceeded => write!(f, "relay byte quota exceeded"),
+            CloseReason::UserQuotaExceeded => write!(f, "user byte quota exceeded"),
+            CloseReason::IdleTimeout => write!(f, "relay idle for too long"),
+            CloseReason::FirstByteTimeout => {
+                write!(f, "no data relayed before the first-byte timeout")
+            }
+            CloseReason::SlowLoris => write!(f, "relay below the minimum transfer rate"),
+            CloseReason::WriteStalled => write!(f, "relay write made no progress"),
+            CloseReason::Rejected => write!(f, "rejected after inspecting the client's data"),
+            CloseReason::ServerNameNotAllowed => {
+                write!(f, "TLS server name denied by access control rules")
+            }
+            CloseReason::Shutdown => write!(f, "closed by server shutdown"),
+            CloseReason::Error(kind) => write!(f, "relay failed: {}", kind),
+        }
+    }
+}
+
+/// How a relay ended and how much it moved in each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RelayOutcome {
+    pub reason: CloseReason,
+    pub bytes_client_to_remote: u64,
+    pub bytes_remote_to_client: u64,
+    pub directions: DirectionalStats,
+}
+
+/// One of the two directions data flows in during a relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayDirection {
+    ClientToRemote,
+    RemoteToClient,
+}
+
+impl fmt::Display for RelayDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayDirection::ClientToRemote => write!(f, "client to remote"),
+            RelayDirection::RemoteToClient => write!(f, "remote to client"),
+        }
+    }
+}
+
+/// How one direction of a relay ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DirectionEnd {
+    /// The side being read from closed its write side.
+    Eof,
+    /// Reading from one side or writing to the other failed.
+    Error(io::ErrorKind),
+    /// The whole relay was stopped while the direction was still open, see `CloseReason`.
+    Stopped,
+}
+
+impl fmt::Display for DirectionEnd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DirectionEnd::Eof => write!(f, "EOF"),
+            DirectionEnd::Error(kind) => write!(f, "{}", kind),
+            DirectionEnd::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Bytes relayed in one direction and how that direction ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectionStats {
+    pub bytes: u64,
+    pub end: DirectionEnd,
+}
+
+/// How each direction of a relay went, to tell one-sided problems apart, e.g. uploads stalling
+/// while downloads flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirectionalStats {
+    pub client_to_remote: DirectionStats,
+    pub remote_to_client: DirectionStats,
+    pub ended_first: RelayDirection,
+}
+
+// Renders e.g. "client to remote: 5 bytes, EOF; remote to client: 0 bytes, connection reset;
+// client to remote ended first".
+impl fmt::Display for DirectionalStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} bytes, {}; {}: {} bytes, {}; {} ended first",
+            RelayDirection::ClientToRemote,
+            self.client_to_remote.bytes,
+            self.client_to_remote.end,
+            RelayDirection::RemoteToClient,
+            self.remote_to_client.bytes,
+            self.remote_to_client.end,
+            self.ended_first
+        )
+    }
+}
+
+/// How relayed data is written out, see `Config::relay_mode`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RelayMode {
+    /// Whatever is read is written out in one go.
+    #[default]
+    Stream,
+    /// Each line is written and flushed on its own as soon as it is read, with Nagle's
+    /// algorithm disabled on both sockets, for interactive line-oriented protocols (SMTP,
+    /// IRC, ...). Chunks are still never held back waiting for a newline.
+    LineBuffered,
+}
+
+/// Bytes a relay has moved so far in each direction, see `SocksServer::relay_progress`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RelayProgress {
+    pub bytes_client_to_remote: u64,
+    pub bytes_remote_to_client: u64,
+}
+
+// How one direction of a relay went.
+struct DirectionOutcome {
+    relayed: u64,
+    end: DirectionEnd,
+    // Whether the side being read from reset the connection.
+    source_reset: bool,
+}
+
+// Write half of a relay direction.
+trait RelayWrite: AsyncWrite + Unpin + Send + 'static {
+    // Closes the connection with a reset rather than a FIN, once its read half is gone too.
+    fn reset(self);
+}
+
+impl DirectionOutcome {
+    fn error(&self) -> Option<io::ErrorKind> {
+        match self.end {
+            DirectionEnd::Error(kind) => Some(kind),
+            _ => None,
+        }
+    }
+
+    fn stats(&self) -> DirectionStats {
+        DirectionStats {
+            bytes: self.relayed,
+            end: self.end,
+        }
+    }
+}
+
+impl RelayWrite for OwnedWriteHalf {
+    fn reset(self) {
+        let _ = SockRef::from(self.as_ref()).set_linger(Some(Duration::ZERO));
+        self.forget();
+    }
+}
+
+// Arbitrary streams have no notion of a reset, so they are simply dropped without a shutdown.
+impl<T: AsyncWrite + Send + 'static> RelayWrite for WriteHalf<T> {
+    fn reset(self) {}
+}
+
+// Server-wide settings and bookkeeping a relay runs with.
+struct RelaySettings {
+    metrics: Option<Arc<MetricsRegistry>>,
+    buffer_pool: Option<Arc<BufferPool>>,
+    buffer_budget: Option<Arc<BufferBudget>>,
+    chunk_size: usize,
+    max_bytes: Option<u64>,
+    user_account: Option<UserAccount>,
+    first_byte_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+    min_transfer_rate: Option<u64>,
+    write_stall_timeout: Option<Duration>,
+    progress: Option<watch::Sender<RelayProgress>>,
+    // Reading pauses while this is `true`.
+    paused: Option<watch::Receiver<bool>>,
+    mode: RelayMode,
+    // Server shutdown signal, along with how long the relay may keep going once it fires.
+    shutdown: Option<(watch::Receiver<bool>, Duration)>,
+    // Limit on how long closing may wait on a peer, or `None` to wait as long as it takes.
+    close_grace: Option<Duration>,
+}
+
+// State shared by both directions of a relay.
+struct RelayState {
+    settings: RelaySettings,
+    transferred: AtomicU64,
+    bytes_client_to_remote: AtomicU64,
+    bytes_remote_to_client: AtomicU64,
+    // When progress was last sent to `RelaySettings::progress`.
+    last_progress: Mutex<Instant>,
+    data_seen: AtomicBool,
+    // When data was last read from either side.
+    last_activity: Mutex<Instant>,
+    // Notified once, when the first byte is read from either side.
+    first_byte: Notify,
+    // Set once either direction decides the whole relay must stop.
+    stop: watch::Sender<Option<CloseReason>>,
+    // Set by whichever direction is done first.
+    ended_first: OnceLock<RelayDirection>,
+    writes_client_to_remote: WriteProgress,
+    writes_remote_to_client: WriteProgress,
+}
+
+// How far the writes of one direction have come, for telling slow or stalled peers apart.
+#[derive(Default)]
+struct WriteProgress {
+    // Bytes the destination has accepted so far, including those of writes still pending.
+    written: AtomicU64,
+    window: Mutex<RateWindow>,
+}
+
+// The current `TRANSFER_RATE_WINDOW` of a direction. It only fills up while a write is pending,
+// so time spent waiting for the source to send something never counts against the rate.
+#[derive(Default)]
+struct RateWindow {
+    // How long writes have been pending since the window started.
+    pending: Duration,
+    // `WriteProgress::written` when the window started.
+    start_written: u64,
+}
+
+// Adds the time it was alive for to the pending time of a `RateWindow`, however the write it
+// measures ends.
+struct PendingWrite<'a> {
+    window: &'a Mutex<RateWindow>,
+    started: Instant,
+}
+
+impl Drop for PendingWrite<'_> {
+    fn drop(&mut self) {
+        self.window.lock().unwrap().pending += self.started.elapsed();
+    }
+}
+
+impl RelayState {
+    // Accounts for `n` more bytes and returns how many of them may still be forwarded, along
+    // with whether the quota is now used up.
+    fn take_allowance(&self, n: usize) -> (usize, bool) {
+        let Some(max_bytes) = self.settings.max_bytes else {
+            return (n, false);
+        };
+
+        let before = self.transferred.fetch_add(n as u64, Ordering::Relaxed);
+        let remaining = max_bytes.saturating_sub(before);
+
+        (n.min(remaining as usize), before + n as u64 >= max_bytes)
+    }
+
+    // Charges `n` relayed bytes to the authenticated user and returns whether the relay must be
+    // cut off for it.
+    fn charge_user(&self, n: u64) -> bool {
+        self.settings
+            .user_account
+            .as_ref()
+            .is_some_and(|account| account.charge(n))
+    }
+
+    fn write_progress(&self, client_to_remote: bool) -> &WriteProgress {
+        if client_to_remote {
+            &self.writes_client_to_remote
+        } else {
+            &self.writes_remote_to_client
+        }
+    }
+
+    fn record_relayed(&self, client_to_remote: bool, n: u64) {
+        let counter = if client_to_remote {
+            &self.bytes_client_to_remote
+        } else {
+            &self.bytes_remote_to_client
+        };
+        counter.fetch_add(n, Ordering::Relaxed);
+
+        if let Some(metrics) = &self.settings.metrics {
+            metrics.bytes_relayed(client_to_remote, n);
+        }
+        self.report_progress(false);
+    }
+
+    // Sends the current byte counts to whoever watches the relay, at most once per
+    // `PROGRESS_INTERVAL` unless `force` is set.
+    fn report_progress(&self, force: bool) {
+        let Some(progress) = &self.settings.progress else {
+            return;
+        };
+
+        {
+            let now = Instant::now();
+            let mut last_progress = self.last_progress.lock().unwrap();
+            if !force && now - *last_progress < PROGRESS_INTERVAL {
+                return;
+            }
+            *last_progress = now;
+        }
+
+        progress.send_replace(RelayProgress {
+            bytes_client_to_remote: self.bytes_client_to_remote.load(Ordering::Relaxed),
+            bytes_remote_to_client: self.bytes_remote_to_client.load(Ordering::Relaxed),
+        });
+    }
+
+    fn stop(&self, reason: CloseReason) {
+        self.stop.send_if_modified(|current| {
+            if current.is_none() {
+                *current = Some(reason);
+                true
+            } else {
+                false
+            }
+        });
+    }
+}
+
+// Shuts down the write side of `dst` once `src` is done, so that EOF is propagated. When `src`
+// was reset, `dst` is reset as well and the whole relay stops, so that the other side sees an
+// abrupt failure as one too instead of a clean close. The shutdown itself may take at most
+// `RelaySettings::close_grace`, when one is set.
+//
+// When the server shuts down, the FIN sent to `dst` asks its peer to wrap up. Whatever `src`
+// still sends is discarded until it closes too or `RelaySettings::close_grace` runs out, after
+// which the connection is simply dropped.
+async fn relay_packets<R, W>(
+    mut src: R,
+    mut dst: W,
+    client_to_remote: bool,
+    state: Arc<RelayState>,
+    stop: watch::Receiver<Option<CloseReason>>,
+) -> DirectionOutcome
+where
+    R: AsyncRead + Unpin,
+    W: RelayWrite,
+{
+    let outcome = copy_packets(&mut src, &mut dst, client_to_remote, &state, stop).await;
+    state.ended_first.get_or_init(|| {
+        if client_to_remote {
+            RelayDirection::ClientToRemote
+        } else {
+            RelayDirection::RemoteToClient
+        }
+    });
+    if outcome.source_reset {
+        dst.reset();
+        state.stop(CloseReason::Error(io::ErrorKind::ConnectionReset));
+        return outcome;
+    }
+
+    // Streams that flush before shutting down, such as TLS ones, would otherwise wait for good
+    // on a peer that stopped reading.
+    let _ = within_close_grace(state.settings.close_grace, dst.shutdown()).await;
+    if *state.stop.borrow() == Some(CloseReason::Shutdown) {
+        let mut sink = tokio::io::sink();
+        let discard = tokio::io::copy(&mut src, &mut sink);
+        let _ = within_close_grace(state.settings.close_grace, discard).await;
+    }
+
+    outcome
+}
+
+// Runs `fut` to completion, giving up after `close_grace` when there is one.
+async fn within_close_grace<F: Future>(close_grace: Option<Duration>, fut: F) -> Option<F::Output> {
+    match close_grace {
+        Some(close_grace) => time::timeout(close_grace, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+async fn copy_packets<R, W>(
+    src: &mut R,
+    dst: &mut W,
+    client_to_remote: bool,
+    state: &RelayState,
+    mut stop: watch::Receiver<Option<CloseReason>>,
+) -> DirectionOutcome
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut outcome = DirectionOutcome {
+        relayed: 0,
+        end: DirectionEnd::Stopped,
+        source_reset: false,
+    };
+    let mut buf = match &state.settings.buffer_pool {
+        Some(pool) => pool.checkout(),
+        None => RelayBuffer::unpooled(state.settings.chunk_size),
+    };
+
+    let mut paused = state.settings.paused.clone();
+    loop {
+        if let Some(paused) = &mut paused {
+            tokio::select! {
+                _ = paused.wait_for(|&paused| !paused) => {}
+                _ = stop.changed() => return outcome,
+            }
+        }
+        if let Some(budget) = &state.settings.buffer_budget {
+            tokio::select! {
+                _ = budget.wait_for_room() => {}
+                _ = stop.changed() => return outcome,
+            }
+        }
+
+        let n = tokio::select! {
+            result = src.read(&mut buf) => match result {
+                Ok(0) => {
+                    outcome.end = DirectionEnd::Eof;
+                    return outcome;
+                }
+                Ok(n) => n,
+                // Tokio's own streams wait for readiness and retry these internally, but a
+                // stream handed to `relay` may still surface them. Neither means the
+                // connection is gone, so the read is simply retried.
+                Err(e) if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+                ) => {
+                    task::yield_now().await;
+                    continue;
+                }
+                Err(e) => {
+                    outcome.end = DirectionEnd::Error(e.kind());
+                    outcome.source_reset = e.kind() == io::ErrorKind::ConnectionReset;
+                    return outcome;
+                }
+            },
+            // Dropping the pending read loses nothing, so pausing takes effect right away
+            // rather than after whatever the source sends next.
+            _ = pause_requested(&mut paused) => continue,
+            _ = stop.changed() => return outcome,
+        };
+
+        if !state.data_seen.swap(true, Ordering::Relaxed) {
+            state.first_byte.notify_one();
+        }
+        if state.settings.idle_timeout.is_some() {
+            *state.last_activity.lock().unwrap() = Instant::now();
+        }
+
+        let _buffered = state
+            .settings
+            .buffer_budget
+            .as_ref()
+            .map(|budget| budget.fill(n));
+        let (allowed, quota_exhausted) = state.take_allowance(n);
+
+        let progress = state.write_progress(client_to_remote);
+        let mut counted = CountingWrite {
+            inner: &mut *dst,
+            written: &progress.written,
+        };
+        tokio::select! {
+            result = write_chunk(&mut counted, &buf[..allowed], state.settings.mode) => {
+                if let Err(e) = result {
+                    outcome.end = DirectionEnd::Error(e.kind());
+                    return outcome;
+                }
+                outcome.relayed += allowed as u64;
+                state.record_relayed(client_to_remote, allowed as u64);
+                if state.charge_user(allowed as u64) {
+                    state.stop(CloseReason::UserQuotaExceeded);
+                    return outcome;
+                }
+            }
+            _ = transfer_stalled(progress, state.settings.min_transfer_rate) => {
+                state.stop(CloseReason::SlowLoris);
+                return outcome;
+            }
+            _ = write_stalled(&progress.written, state.settings.write_stall_timeout) => {
+                state.stop(CloseReason::WriteStalled);
+                return outcome;
+            }
+            _ = stop.changed() => return outcome,
+        }
+
+        if quota_exhausted {
+            state.stop(CloseReason::QuotaExceeded);
+            return outcome;
+        }
+    }
+}
+
+// Resolves once the relay is paused, or never when it can't be.
+async fn pause_requested(paused: &mut Option<watch::Receiver<bool>>) {
+    if let Some(paused) = paused {
+        if paused.wait_for(|&paused| paused).await.is_ok() {
+            return;
+        }
+    }
+    future::pending().await
+}
+
+// Resolves once writes have been pending for a whole `TRANSFER_RATE_WINDOW`, possibly spread
+// over many of them, while moving less than `min_rate` bytes per second. Never resolves without
+// a `min_rate`.
+async fn transfer_stalled(progress: &WriteProgress, min_rate: Option<u64>) {
+    let Some(min_rate) = min_rate else {
+        return future::pending().await;
+    };
+    let min_per_window = min_rate.saturating_mul(TRANSFER_RATE_WINDOW.as_secs());
+
+    loop {
+        let remaining =
+            TRANSFER_RATE_WINDOW.saturating_sub(progress.window.lock().unwrap().pending);
+        let pending = PendingWrite {
+            window: &progress.window,
+            started: Instant::now(),
+        };
+        time::sleep(remaining).await;
+        drop(pending);
+
+        let mut window = progress.window.lock().unwrap();
+        let now_written = progress.written.load(Ordering::Relaxed);
+        if now_written - window.start_written < min_per_window {
+            return;
+        }
+        *window = RateWindow {
+            pending: Duration::ZERO,
+            start_written: now_written,
+        };
+    }
+}
+
+// Resolves once a write has gone `timeout` without the peer accepting a single byte, and never
+// without a `timeout`. Any progress restarts the wait, so slow writes are left alone.
+async fn write_stalled(written: &AtomicU64, timeout: Option<Duration>) {
+    let Some(timeout) = timeout else {
+        return future::pending().await;
+    };
+
+    let mut last_written = written.load(Ordering::Relaxed);
+    loop {
+        time::sleep(timeout).await;
+        let now_written = written.load(Ordering::Relaxed);
+        if now_written == last_written {
+            return;
+        }
+        last_written = now_written;
+    }
+}
+
+// Passes writes through to `inner`, counting the bytes it accepts into `written`.
+struct CountingWrite<'a, W> {
+    inner: &'a mut W,
+    written: &'a AtomicU64,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWrite<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = &poll {
+            this.written.fetch_add(*n as u64, Ordering::Relaxed);
+        }
+
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+async fn write_chunk<W>(dst: &mut W, chunk: &[u8], mode: RelayMode) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    match mode {
+        RelayMode::Stream => dst.write_all(chunk).await,
+        RelayMode::LineBuffered => {
+            for line in chunk.split_inclusive(|&byte| byte == b'\n') {
+                dst.write_all(line).await?;
+                dst.flush().await?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Relays data in both directions until both are done, or until one of them stops the whole
+// relay.
+async fn run_relay<CR, CW, RR, RW>(
+    (client_rx, client_tx): (CR, CW),
+    (remote_rx, remote_tx): (RR, RW),
+    settings: RelaySettings,
+) -> RelayOutcome
+where
+    CR: AsyncRead + Unpin + Send + 'static,
+    CW: RelayWrite,
+    RR: AsyncRead + Unpin + Send + 'static,
+    RW: RelayWrite,
+{
+    let (stop, _) = watch::channel(None);
+    let state = Arc::new(RelayState {
+        settings,
+        transferred: AtomicU64::new(0),
+        bytes_client_to_remote: AtomicU64::new(0),
+        bytes_remote_to_client: AtomicU64::new(0),
+        last_progress: Mutex::new(Instant::now()),
+        data_seen: AtomicBool::new(false),
+        last_activity: Mutex::new(Instant::now()),
+        first_byte: Notify::new(),
+        stop,
+        ended_first: OnceLock::new(),
+        writes_client_to_remote: WriteProgress::default(),
+        writes_remote_to_client: WriteProgress::default(),
+    });
+
+    let shutdown_watchdog = state
+        .settings
+        .shutdown
+        .clone()
+        .map(|(mut shutdown, grace)| {
+            let state = Arc::clone(&state);
+            task::spawn(async move {
+                if shutdown
+                    .wait_for(|&shutting_down| shutting_down)
+                    .await
+                    .is_ok()
+                {
+                    time::sleep(grace).await;
+                    state.stop(CloseReason::Shutdown);
+                }
+            })
+        });
+    let first_byte_watchdog = state.settings.first_byte_timeout.map(|timeout| {
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            if time::timeout(timeout, state.first_byte.notified())
+                .await
+                .is_err()
+            {
+                state.stop(CloseReason::FirstByteTimeout);
+            }
+        })
+    });
+
+    let idle_watchdog = state.settings.idle_timeout.map(|timeout| {
+        let state = Arc::clone(&state);
+        task::spawn(async move {
+            loop {
+                let idle_until = *state.last_activity.lock().unwrap() + timeout;
+                if idle_until <= Instant::now() {
+                    state.stop(CloseReason::IdleTimeout);
+                    return;
+                }
+                time::sleep_until(idle_until).await;
+            }
+        })
+    });
+
+    // Subscribe before spawning so that neither direction can miss an early stop.
+    let client_to_remote = task::spawn(relay_packets(
+        client_rx,
+        remote_tx,
+        true,
+        Arc::clone(&state),
+        state.stop.subscribe(),
+    ));
+    let remote_to_client = task::spawn(relay_packets(
+        remote_rx,
+        client_tx,
+        false,
+        Arc::clone(&state),
+        state.stop.subscribe(),
+    ));
+
+    let client_to_remote = client_to_remote.await.unwrap();
+    let remote_to_client = remote_to_client.await.unwrap();
+    for watchdog in [first_byte_watchdog, idle_watchdog, shutdown_watchdog]
+        .into_iter()
+        .flatten()
+    {
+        watchdog.abort();
+    }
+    state.report_progress(true);
+
+    // A stop decided by the relay itself explains any error it caused on the other direction.
+    let stop_reason = *state.stop.borrow();
+    let reason = stop_reason.unwrap_or_else(|| {
+        match client_to_remote.error().or(remote_to_client.error()) {
+            Some(kind) => CloseReason::Error(kind),
+            None => CloseReason::Completed,
+        }
+    });
+    if let (CloseReason::Error(kind), Some(metrics)) = (reason, &state.settings.metrics) {
+        metrics.relay_failed(kind);
+    }
+
+    RelayOutcome {
+        reason,
+        bytes_client_to_remote: client_to_remote.relayed,
+        bytes_remote_to_client: remote_to_client.relayed,
+        directions: DirectionalStats {
+            client_to_remote: client_to_remote.stats(),
+            remote_to_client: remote_to_client.stats(),
+            ended_first: state
+                .ended_first
+                .get()
+                .copied()
+                .unwrap_or(RelayDirection::ClientToRemote),
+        },
+    }
+}
+
+pub(crate) async fn handle_packet_relay(
+    client_conn: TcpStream,
+    remote_conn: RemoteConn,
+    server: &Arc<ServerState>,
+    progress: watch::Sender<RelayProgress>,
+    paused: watch::Receiver<bool>,
+    user_account: Option<UserAccount>,
+) -> RelayOutcome {
+    let config = &server.config;
+    let settings = RelaySettings {
+        metrics: Some(Arc::clone(&server.metrics)),
+        buffer_pool: server.buffer_pool.clone(),
+        buffer_budget: Some(Arc::clone(&server.buffer_budget)),
+        chunk_size: config.relay_chunk_size,
+        max_bytes: config.max_bytes_per_connection,
+        user_account,
+        first_byte_timeout: config.first_byte_timeout,
+        idle_timeout: match config.idle_policy {
+            Some(IdlePolicy::Close(timeout)) => Some(timeout),
+            _ => None,
+        },
+        min_transfer_rate: config.min_transfer_rate,
+        write_stall_timeout: config.write_stall_timeout,
+        progress: Some(progress),
+        paused: Some(paused),
+        mode: config.relay_mode,
+        shutdown: config
+            .shutdown_grace
+            .map(|grace| (server.shutdown.subscribe(), grace)),
+        close_grace: Some(config.close_grace),
+    };
+    if let Some(IdlePolicy::KeepAlive(keepalive)) = &config.idle_policy {
+        for conn in [&client_conn, remote_conn.tcp()] {
+            if let Err(e) = set_keepalive(SockRef::from(conn), keepalive) {
+                eprintln!("Failed to enable TCP keepalive for a relay: {}", e);
+            }
+        }
+    }
+    if config.relay_mode == RelayMode::LineBuffered {
+        let _ = client_conn.set_nodelay(true);
+        let _ = remote_conn.tcp().set_nodelay(true);
+    }
+
+    match remote_conn {
+        RemoteConn::Tcp(remote_conn) => {
+            run_relay(client_conn.into_split(), remote_conn.into_split(), settings).await
+        }
+        #[cfg(feature = "tls")]
+        RemoteConn::Tls(remote_conn) => {
+            run_relay(
+                client_conn.into_split(),
+                tokio::io::split(*remote_conn),
+                settings,
+            )
+            .await
+        }
+    }
+}
+
+/// Relays data between `client` and `remote` in both directions until both reach EOF, the
+/// same way the server relays established connections, for callers that already have a
+/// connected pair of streams. When one side reaches EOF, the write side of the other is shut
+/// down and the opposite direction keeps going until it finishes too.
+pub async fn relay<C, R>(client: C, remote: R) -> RelayOutcome
+where
+    C: AsyncRead + AsyncWrite + Send + 'static,
+    R: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let settings = RelaySettings {
+        metrics: None,
+        buffer_pool: None,
+        buffer_budget: None,
+        chunk_size: DEFAULT_RELAY_CHUNK_SIZE,
+        max_bytes: None,
+        user_account: None,
+        first_byte_timeout: None,
+        idle_timeout: None,
+        min_transfer_rate: None,
+        write_stall_timeout: None,
+        progress: None,
+        paused: None,
+        mode: RelayMode::Stream,
+        shutdown: None,
+        close_grace: None,
+    };
+
+    run_relay(tokio::io::split(client), tokio::io::split(remote), settings).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, split, DuplexStream, ReadBuf};
+    use tokio::task::JoinHandle;
+
+    use super::*;
+
+    fn settings() -> RelaySettings {
+        RelaySettings {
+            metrics: None,
+            buffer_pool: None,
+            buffer_budget: None,
+            chunk_size: DEFAULT_RELAY_CHUNK_SIZE,
+            max_bytes: None,
+            user_account: None,
+            first_byte_timeout: None,
+            idle_timeout: None,
+            min_transfer_rate: None,
+            write_stall_timeout: None,
+            progress: None,
+            paused: None,
+            mode: RelayMode::Stream,
+            shutdown: None,
+            close_grace: None,
+        }
+    }
+
+    // Starts a relay with `settings`, returning the client's and the remote's ends of it.
+    fn spawn_relay(
+        settings: RelaySettings,
+    ) -> (DuplexStream, DuplexStream, JoinHandle<RelayOutcome>) {
+        let (client, client_relay_end) = duplex(64 * 1024);
+        let (remote, remote_relay_end) = duplex(64 * 1024);
+        let relay = task::spawn(run_relay(
+            split(client_relay_end),
+            split(remote_relay_end),
+            settings,
+        ));
+
+        (client, remote, relay)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_idle_relay_once_idle_timeout_passes() {
+        let (_client, _remote, relay) = spawn_relay(RelaySettings {
+            idle_timeout: Some(Duration::from_secs(30)),
+            ..settings()
+        });
+
+        time::advance(Duration::from_secs(29)).await;
+        assert!(!relay.is_finished());
+
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(relay.await.unwrap().reason, CloseReason::IdleTimeout);
+    }
+
+    #[tokio::test]
+    async fn closes_relay_once_byte_cap_is_reached() {
+        let (mut client, mut remote, relay) = spawn_relay(RelaySettings {
+            max_bytes: Some(10),
+            ..settings()
+        });
+
+        client.write_all(&[7; 100]).await.unwrap();
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, [7; 10]);
+        let outcome = relay.await.unwrap();
+        assert_eq!(outcome.reason, CloseReason::QuotaExceeded);
+        assert_eq!(outcome.bytes_client_to_remote, 10);
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_both_directions_against_byte_cap() {
+        let (mut client, mut remote, relay) = spawn_relay(RelaySettings {
+            max_bytes: Some(10),
+            ..settings()
+        });
+
+        client.write_all(&[1; 6]).await.unwrap();
+        let mut received = [0; 6];
+        remote.read_exact(&mut received).await.unwrap();
+        remote.write_all(&[2; 6]).await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+
+        assert_eq!(received, [2; 4]);
+        let outcome = relay.await.unwrap();
+        assert_eq!(outcome.reason, CloseReason::QuotaExceeded);
+        assert_eq!(
+            outcome.bytes_client_to_remote + outcome.bytes_remote_to_client,
+            10
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_relay_when_destination_stays_silent() {
+        let (_client, _remote, relay) = spawn_relay(RelaySettings {
+            first_byte_timeout: Some(Duration::from_secs(10)),
+            ..settings()
+        });
+
+        time::advance(Duration::from_secs(11)).await;
+
+        assert_eq!(relay.await.unwrap().reason, CloseReason::FirstByteTimeout);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn first_byte_timeout_ends_once_data_flows() {
+        let (mut client, mut remote, relay) = spawn_relay(RelaySettings {
+            first_byte_timeout: Some(Duration::from_secs(10)),
+            ..settings()
+        });
+
+        remote.write_all(b"220 ready\r\n").await.unwrap();
+        let mut banner = [0; 11];
+        client.read_exact(&mut banner).await.unwrap();
+        time::advance(Duration::from_secs(60)).await;
+        assert!(!relay.is_finished());
+
+        drop(client);
+        drop(remote);
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Completed);
+    }
+
+    // Relays from a client with plenty to send to a remote that reads `read_chunk` bytes every
+    // `read_interval`, in chunks small enough for each write to finish well within a
+    // `TRANSFER_RATE_WINDOW`.
+    fn spawn_throttled_relay(
+        read_chunk: usize,
+        read_interval: Duration,
+    ) -> JoinHandle<RelayOutcome> {
+        let (mut client, client_relay_end) = duplex(64 * 1024);
+        let (mut remote, remote_relay_end) = duplex(100);
+        let relay = task::spawn(run_relay(
+            split(client_relay_end),
+            split(remote_relay_end),
+            RelaySettings {
+                chunk_size: 100,
+                min_transfer_rate: Some(100),
+                ..settings()
+            },
+        ));
+        task::spawn(async move {
+            let _ = client.write_all(&[7; 4000]).await;
+        });
+        task::spawn(async move {
+            let mut buf = vec![0; read_chunk];
+            loop {
+                time::sleep(read_interval).await;
+                if remote.read_exact(&mut buf).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        relay
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_relay_to_peer_slower_than_min_rate_over_many_writes() {
+        // 50 bytes per second, while each 100-byte write only takes two seconds.
+        let relay = spawn_throttled_relay(50, Duration::from_secs(1));
+
+        let started = Instant::now();
+        assert_eq!(relay.await.unwrap().reason, CloseReason::SlowLoris);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_relay_to_peer_at_min_rate() {
+        let relay = spawn_throttled_relay(100, Duration::from_millis(500));
+
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Completed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exempts_idle_relay_from_min_rate() {
+        let (mut client, mut remote, relay) = spawn_relay(RelaySettings {
+            min_transfer_rate: Some(100),
+            ..settings()
+        });
+
+        client.write_all(b"ping").await.unwrap();
+        let mut received = [0; 4];
+        remote.read_exact(&mut received).await.unwrap();
+        time::advance(Duration::from_secs(60)).await;
+        assert!(!relay.is_finished());
+
+        drop(client);
+        drop(remote);
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Completed);
+    }
+
+    // Hands out the results in `0` one read at a time, then never has anything more to read.
+    struct ScriptedRead(Vec<io::Result<&'static [u8]>>);
+
+    impl AsyncRead for ScriptedRead {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if this.0.is_empty() {
+                return Poll::Pending;
+            }
+            Poll::Ready(this.0.remove(0).map(|data| buf.put_slice(data)))
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_reads_that_would_block_or_were_interrupted() {
+        let client_rx = ScriptedRead(vec![
+            Err(io::ErrorKind::WouldBlock.into()),
+            Err(io::ErrorKind::Interrupted.into()),
+            Ok(b"ping"),
+        ]);
+        let (mut client, client_relay_end) = duplex(1024);
+        let (mut remote, remote_relay_end) = duplex(1024);
+        let relay = task::spawn(run_relay(
+            (client_rx, split(client_relay_end).1),
+            split(remote_relay_end),
+            settings(),
+        ));
+
+        let mut received = [0; 4];
+        remote.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ping");
+
+        remote.write_all(b"pong").await.unwrap();
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+        assert!(!relay.is_finished());
+    }
+
+    // A peer that has nothing to send, and whose shutdown never completes, like a TLS stream
+    // trying to flush to a peer that stopped reading.
+    struct HangingShutdown;
+
+    impl AsyncRead for HangingShutdown {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for HangingShutdown {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gives_up_on_shutdown_after_close_grace() {
+        let (client, client_relay_end) = duplex(1024);
+        let relay = task::spawn(run_relay(
+            split(client_relay_end),
+            split(HangingShutdown),
+            RelaySettings {
+                close_grace: Some(Duration::from_secs(5)),
+                ..settings()
+            },
+        ));
+
+        drop(client);
+
+        let started = Instant::now();
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Completed);
+        assert_eq!(started.elapsed(), Duration::from_secs(5));
+    }
+
+    // Sends a patterned payload through a relay with `chunk_size` and checks it arrives intact.
+    async fn relay_payload_in_chunks_of(chunk_size: usize) {
+        let (mut client, mut remote, relay) = spawn_relay(RelaySettings {
+            chunk_size,
+            ..settings()
+        });
+        let payload: Vec<u8> = (0..512 * 1024).map(|i| (i % 251) as u8).collect();
+
+        let sent = payload.clone();
+        let writer = task::spawn(async move {
+            client.write_all(&sent).await.unwrap();
+            client
+        });
+        let mut received = vec![0; payload.len()];
+        remote.read_exact(&mut received).await.unwrap();
+        assert!(received == payload);
+
+        drop(writer.await.unwrap());
+        drop(remote);
+        let outcome = relay.await.unwrap();
+        assert_eq!(outcome.bytes_client_to_remote, payload.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn relays_intact_with_tiny_chunks() {
+        relay_payload_in_chunks_of(64).await;
+    }
+
+    #[tokio::test]
+    async fn relays_intact_with_large_chunks() {
+        relay_payload_in_chunks_of(256 * 1024).await;
+    }
+
+    #[tokio::test]
+    async fn relays_between_stream_pairs_with_half_close() {
+        let (mut client, client_relay_end) = duplex(1024);
+        let (mut remote, remote_relay_end) = duplex(1024);
+        let relay = task::spawn(relay(client_relay_end, remote_relay_end));
+
+        client.write_all(b"request").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        remote.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+
+        // The client's half-close leaves the other direction open.
+        remote.write_all(b"response").await.unwrap();
+        drop(remote);
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response");
+
+        let outcome = relay.await.unwrap();
+        assert_eq!(outcome.reason, CloseReason::Completed);
+        assert_eq!(outcome.bytes_client_to_remote, 7);
+        assert_eq!(outcome.bytes_remote_to_client, 8);
+        assert_eq!(
+            outcome.directions.ended_first,
+            RelayDirection::ClientToRemote
+        );
+    }
+
+    // Records every write and flush made to it, in order.
+    #[derive(Default)]
+    struct RecordingWrite(Vec<String>);
+
+    impl AsyncWrite for RecordingWrite {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.get_mut()
+                .0
+                .push(format!("write {:?}", String::from_utf8_lossy(buf)));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().0.push("flush".to_string());
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_and_flushes_each_line_on_its_own() {
+        let mut dst = RecordingWrite::default();
+        write_chunk(&mut dst, b"HELO a\nMAIL b\nRCPT", RelayMode::LineBuffered)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            dst.0,
+            [
+                r#"write "HELO a\n""#,
+                "flush",
+                r#"write "MAIL b\n""#,
+                "flush",
+                r#"write "RCPT""#,
+                "flush",
+            ]
+        );
+
+        let mut dst = RecordingWrite::default();
+        write_chunk(&mut dst, b"HELO a\nMAIL b\n", RelayMode::Stream)
+            .await
+            .unwrap();
+
+        assert_eq!(dst.0, [r#"write "HELO a\nMAIL b\n""#]);
+    }
+
+    #[tokio::test]
+    async fn relays_line_oriented_exchange_in_order() {
+        let (mut client, mut remote, relay) = spawn_relay(RelaySettings {
+            mode: RelayMode::LineBuffered,
+            ..settings()
+        });
+
+        for (request, response) in [("HELO a\n", "250 hi\n"), ("QUIT\n", "221 bye\n")] {
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut received = vec![0; request.len()];
+            remote.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, request.as_bytes());
+
+            remote.write_all(response.as_bytes()).await.unwrap();
+            let mut received = vec![0; response.len()];
+            client.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, response.as_bytes());
+        }
+        client.write_all(b"1\n2\n3").await.unwrap();
+        drop(client);
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"1\n2\n3");
+
+        drop(remote);
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Completed);
+    }
+
+    // Starts a relay that may keep going for 10s after a shutdown, with a close grace of 5s,
+    // returning it along with the sender that starts the shutdown.
+    fn spawn_relay_with_shutdown_grace() -> (
+        watch::Sender<bool>,
+        DuplexStream,
+        DuplexStream,
+        JoinHandle<RelayOutcome>,
+    ) {
+        let (shutdown, shutdown_rx) = watch::channel(false);
+        let (client, remote, relay) = spawn_relay(RelaySettings {
+            shutdown: Some((shutdown_rx, Duration::from_secs(10))),
+            close_grace: Some(Duration::from_secs(5)),
+            ..settings()
+        });
+
+        (shutdown, client, remote, relay)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_fin_before_dropping_relay_at_shutdown() {
+        let (shutdown, mut client, mut remote, relay) = spawn_relay_with_shutdown_grace();
+        let start = Instant::now();
+        shutdown.send_replace(true);
+
+        // Both peers see a clean close once the shutdown grace has passed.
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        remote.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        assert!(!relay.is_finished());
+
+        // Whatever they still send is discarded, until the close grace runs out as well.
+        client.write_all(b"late").await.unwrap();
+        let outcome = relay.await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(15));
+        assert_eq!(outcome.reason, CloseReason::Shutdown);
+        assert_eq!(outcome.bytes_client_to_remote, 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_relay_once_both_peers_close_after_fin() {
+        let (shutdown, mut client, mut remote, relay) = spawn_relay_with_shutdown_grace();
+        let start = Instant::now();
+        shutdown.send_replace(true);
+
+        client.read_to_end(&mut Vec::new()).await.unwrap();
+        drop(client);
+        remote.read_to_end(&mut Vec::new()).await.unwrap();
+        drop(remote);
+
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Shutdown);
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn keeps_bytes_buffered_across_relays_near_shared_cap() {
+        const CHUNK: usize = 1024;
+        const LIMIT: u64 = 4 * CHUNK as u64;
+        let budget = Arc::new(BufferBudget::new(Some(LIMIT)));
+
+        // Each destination's end holds a single chunk, so every relay whose destination isn't
+        // reading ends up holding one more chunk it can't write out.
+        let mut peers = Vec::new();
+        for _ in 0..8 {
+            let (mut client, client_relay_end) = duplex(64 * 1024);
+            let (remote, remote_relay_end) = duplex(CHUNK);
+            let relay = task::spawn(run_relay(
+                split(client_relay_end),
+                split(remote_relay_end),
+                RelaySettings {
+                    buffer_budget: Some(Arc::clone(&budget)),
+                    chunk_size: CHUNK,
+                    ..settings()
+                },
+            ));
+            client.write_all(&[7; 4 * CHUNK]).await.unwrap();
+            peers.push((client, remote, relay));
+        }
+
+        time::sleep(Duration::from_millis(100)).await;
+        // Without the cap all eight relays would hold a chunk.
+        assert!(budget.high_water_mark() >= LIMIT);
+        assert!(budget.high_water_mark() <= LIMIT + CHUNK as u64);
+
+        for (client, mut remote, relay) in peers {
+            let mut received = [0; 4 * CHUNK];
+            remote.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, [7; 4 * CHUNK]);
+            drop(client);
+            drop(remote);
+            relay.await.unwrap();
+        }
+        assert_eq!(budget.buffered(), 0);
+    }
+
+    // Relays 1000 bytes from the client to a remote whose end holds 100 bytes, with a five
+    // second write stall timeout.
+    async fn spawn_relay_to_small_window() -> (DuplexStream, DuplexStream, JoinHandle<RelayOutcome>)
+    {
+        let (mut client, client_relay_end) = duplex(64 * 1024);
+        let (remote, remote_relay_end) = duplex(100);
+        let relay = task::spawn(run_relay(
+            split(client_relay_end),
+            split(remote_relay_end),
+            RelaySettings {
+                chunk_size: 100,
+                write_stall_timeout: Some(Duration::from_secs(5)),
+                ..settings()
+            },
+        ));
+        client.write_all(&[7; 1000]).await.unwrap();
+
+        (client, remote, relay)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_relay_when_peer_stops_reading_mid_transfer() {
+        let (_client, mut remote, relay) = spawn_relay_to_small_window().await;
+
+        let mut received = [0; 300];
+        remote.read_exact(&mut received).await.unwrap();
+        let stopped_reading = Instant::now();
+
+        let outcome = relay.await.unwrap();
+        assert_eq!(outcome.reason, CloseReason::WriteStalled);
+        assert!(stopped_reading.elapsed() >= Duration::from_secs(5));
+        assert!(stopped_reading.elapsed() < Duration::from_secs(6));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_relay_to_slow_but_progressing_peer() {
+        let (client, mut remote, relay) = spawn_relay_to_small_window().await;
+        drop(client);
+
+        // Ten bytes every two seconds, so each 100-byte write takes far longer than the stall
+        // timeout while never going five seconds without progress.
+        let mut buf = [0; 10];
+        for _ in 0..100 {
+            time::sleep(Duration::from_secs(2)).await;
+            remote.read_exact(&mut buf).await.unwrap();
+        }
+        drop(remote);
+
+        let outcome = relay.await.unwrap();
+        assert_eq!(outcome.reason, CloseReason::Completed);
+        assert_eq!(outcome.bytes_client_to_remote, 1000);
+    }
+
+    // Passes everything through to `inner`, except that shutting down only completes once
+    // `flushed` has elapsed, as with streams that have to flush first.
+    struct SlowShutdown {
+        inner: DuplexStream,
+        flushed: Pin<Box<time::Sleep>>,
+    }
+
+    impl AsyncRead for SlowShutdown {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for SlowShutdown {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            if self.flushed.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    #[tokio::test]
+    async fn propagates_eof_through_slow_shutdown() {
+        let (mut client, client_relay_end) = duplex(1024);
+        let (mut remote, remote_relay_end) = duplex(1024);
+        let relay = task::spawn(relay(
+            client_relay_end,
+            SlowShutdown {
+                inner: remote_relay_end,
+                flushed: Box::pin(time::sleep(Duration::from_millis(50))),
+            },
+        ));
+
+        client.write_all(b"hello").await.unwrap();
+        drop(client);
+        let mut received = Vec::new();
+        time::timeout(Duration::from_secs(5), remote.read_to_end(&mut received))
+            .await
+            .expect("remote did not see EOF")
+            .unwrap();
+        assert_eq!(received, b"hello");
+
+        drop(remote);
+        assert_eq!(relay.await.unwrap().reason, CloseReason::Completed);
+    }
+}
@@ -0,0 +1,37 @@
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use tokio::net;
+
+use crate::ResolveError;
+
+/// Future returned by `Resolver::resolve`.
+pub type ResolveFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<SocketAddr>, ResolveError>> + Send + 'a>>;
+
+/// Resolves the domain names clients ask to connect to. Every address returned is tried in
+/// order until one of them accepts the connection. How resolving failed decides the reply the
+/// client gets, see `ResolveError`.
+pub trait Resolver: fmt::Debug + Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a>;
+}
+
+/// Resolves names through the operating system, as `tokio::net::lookup_host` does. The
+/// operating system doesn't say why a lookup failed, so every failure is reported as
+/// `ResolveError::Failed`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str, port: u16) -> ResolveFuture<'a> {
+        Box::pin(async move {
+            let addrs = net::lookup_host((host, port))
+                .await
+                .map_err(ResolveError::Failed)?;
+
+            Ok(addrs.collect())
+        })
+    }
+}
@@ -0,0 +1,224 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use crate::{AuthMethod, AuthSettings, Config};
+
+/// How a listener came up: where it's bound and which configuration it runs with, for
+/// operators to confirm the server started the way they intended. Credentials are never part
+/// of it. Printed on startup with `Config::log_startup_report`, as a single `key=value` line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartupReport {
+    pub listen_addr: SocketAddr,
+    pub auth_methods: Vec<AuthMethod>,
+    /// Number of static username/password logins. The logins themselves are left out.
+    pub logins: usize,
+    /// Optional features that are turned on, by `Config` field name, e.g. `access_control`.
+    pub features: Vec<&'static str>,
+    /// Limits and timeouts that are set, by `Config` field name, along with their values.
+    pub limits: Vec<(&'static str, String)>,
+}
+
+impl StartupReport {
+    pub(crate) fn new(
+        listen_addr: SocketAddr,
+        auth_settings: &AuthSettings,
+        config: &Config,
+    ) -> Self {
+        let features = [
+            ("expect_proxy_protocol", config.expect_proxy_protocol),
+            ("transparent", config.transparent),
+            (
+                "match_client_address_family",
+                config.match_client_address_family,
+            ),
+            ("log_timings", config.log_timings),
+            ("log_control_packets", config.log_control_packets),
+            ("log_malformed_hellos", config.log_malformed_hellos),
+            ("handshake_capture", config.handshake_capture.is_some()),
+            ("http_misconnect_response", config.http_misconnect_response),
+            ("authenticators", !config.authenticators.is_empty()),
+            ("user_byte_quota", config.user_byte_quota.is_some()),
+            ("access_control", config.access_control.is_some()),
+            ("check_tls_server_name", config.check_tls_server_name),
+            ("inspector", config.inspector.is_some()),
+            ("health_check_domain", config.health_check_domain.is_some()),
+            (
+                "allow_unspecified_destinations",
+                config.allow_unspecified_destinations,
+            ),
+            ("connector", config.connector.is_some()),
+            #[cfg(feature = "tls")]
+            ("outbound_tls", config.outbound_tls.is_some()),
+            (
+                "outbound_source_port_range",
+                config.outbound_source_port_range.is_some(),
+            ),
+            ("outbound_bind_pool", !config.outbound_bind_pool.is_empty()),
+            ("outbound_keepalive", config.outbound_keepalive.is_some()),
+            ("relay_buffer_pool", config.relay_buffer_pool.is_some()),
+            (
+                "connect_failure_cache_ttl",
+                config.connect_failure_cache_ttl.is_some(),
+            ),
+            ("metrics_sink", config.metrics_sink.is_some()),
+            ("accept_gate", config.accept_gate.is_some()),
+            ("allow_bind", config.allow_bind),
+            ("bind_allow_any_peer", config.bind_allow_any_peer),
+            ("allow_udp_associate", config.allow_udp_associate),
+        ]
+        .into_iter()
+        .filter_map(|(name, enabled)| enabled.then_some(name))
+        .collect();
+
+        let limits = [
+            ("max_connections", value_if_set(config.max_connections)),
+            (
+                "max_connections_per_ip",
+                value_if_set(config.max_connections_per_ip),
+            ),
+            (
+                "max_connections_per_destination",
+                value_if_set(config.max_connections_per_destination),
+            ),
+            (
+                "max_handshakes_in_progress",
+                value_if_set(config.max_handshakes_in_progress),
+            ),
+            (
+                "max_concurrent_resolutions",
+                value_if_set(config.max_concurrent_resolutions),
+            ),
+            (
+                "max_bytes_per_connection",
+                value_if_set(config.max_bytes_per_connection),
+            ),
+            (
+                "max_buffered_bytes",
+                value_if_set(config.max_buffered_bytes),
+            ),
+            ("max_pending_binds", value_if_set(config.max_pending_binds)),
+            (
+                "max_pending_associations",
+                value_if_set(config.max_pending_associations),
+            ),
+            ("min_transfer_rate", value_if_set(config.min_transfer_rate)),
+            (
+                "queue_wait_timeout",
+                value_if_set(Some(config.queue_wait_timeout)),
+            ),
+            ("auth_timeout", value_if_set(Some(config.auth_timeout))),
+            (
+                "resolve_timeout",
+                value_if_set(Some(config.resolve_timeout)),
+            ),
+            (
+                "first_byte_timeout",
+                value_if_set(config.first_byte_timeout),
+            ),
+            (
+                "write_stall_timeout",
+                value_if_set(config.write_stall_timeout),
+            ),
+            (
+                "bind_accept_timeout",
+                value_if_set(config.allow_bind.then_some(config.bind_accept_timeout)),
+            ),
+            ("idle_policy", value_if_set(config.idle_policy)),
+            ("shutdown_grace", value_if_set(config.shutdown_grace)),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect();
+
+        Self {
+            listen_addr,
+            auth_methods: vec![auth_settings.method],
+            logins: auth_settings
+                .params
+                .as_ref()
+                .map_or(0, |params| params.logins.len()),
+            features,
+            limits,
+        }
+    }
+}
+
+// Renders a setting's value, if it has one, without spaces, so that it fits in a `key=value` pair.
+fn value_if_set<T: fmt::Debug>(value: Option<T>) -> Option<String> {
+    value.map(|value| format!("{:?}", value).replace(' ', ""))
+}
+
+impl fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let auth_methods: Vec<String> = self
+            .auth_methods
+            .iter()
+            .map(|method| format!("{:?}", method))
+            .collect();
+        write!(
+            f,
+            "listen_addr={} auth_methods={} logins={} features={}",
+            self.listen_addr,
+            auth_methods.join(","),
+            self.logins,
+            self.features.join(",")
+        )?;
+        for (name, value) in &self.limits {
+            write!(f, " {}={}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{AccessControl, AuthParams};
+
+    fn report() -> StartupReport {
+        let auth_settings = AuthSettings {
+            method: AuthMethod::UserPassword,
+            params: Some(AuthParams {
+                logins: HashMap::from([("alice".to_string(), "secret".to_string())]),
+            }),
+        };
+        let config = Config {
+            access_control: Some(AccessControl::parse("deny 10.0.0.0/8")),
+            max_connections: Some(10),
+            shutdown_grace: Some(Duration::from_secs(30)),
+            ..Config::default()
+        };
+
+        StartupReport::new("127.0.0.1:1080".parse().unwrap(), &auth_settings, &config)
+    }
+
+    #[test]
+    fn reports_known_configuration() {
+        let report = report();
+
+        assert_eq!(report.auth_methods, [AuthMethod::UserPassword]);
+        assert_eq!(report.logins, 1);
+        assert_eq!(report.features, ["log_malformed_hellos", "access_control"]);
+        assert_eq!(report.limits[0], ("max_connections", "10".to_string()));
+        assert_eq!(
+            report.limits.last().unwrap(),
+            &("shutdown_grace", "30s".to_string())
+        );
+        assert!(report.to_string().starts_with(
+            "listen_addr=127.0.0.1:1080 auth_methods=UserPassword logins=1 \
+             features=log_malformed_hellos,access_control max_connections=10 "
+        ));
+    }
+
+    #[test]
+    fn leaves_credentials_out() {
+        let report = report().to_string();
+
+        assert!(!report.contains("alice"));
+        assert!(!report.contains("secret"));
+    }
+}
@@ -0,0 +1,321 @@
+use std::future::{self, Future};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::pin::{pin, Pin};
+use std::time::Duration;
+
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::{watch, OwnedSemaphorePermit};
+use tokio::time::{self, Instant};
+
+use crate::packets::udp_relay::UdpHeader;
+use crate::user_quota::UserAccount;
+use crate::{
+    first_finished, is_unspecified, resolve_destination, CloseReason, DestinationAddress,
+    IdlePolicy, ServerState,
+};
+
+// Largest datagram UDP can carry.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+// Datagrams for domain names that may wait on their name being resolved at once. Any more are
+// dropped, as UDP is free to.
+const MAX_PENDING_RESOLUTIONS: usize = 64;
+
+// Payload of a datagram from the client whose destination name is being resolved, resolving to
+// the address to send it to unless the datagram must be dropped.
+type PendingDatagram<'a> = Pin<Box<dyn Future<Output = Option<(SocketAddr, Vec<u8>)>> + Send + 'a>>;
+
+// Relays datagrams between a client and whichever destinations it names in them, for as long
+// as the connection it was requested over stays open.
+pub(crate) struct UdpAssociation {
+    // Socket the client sends its datagrams to and gets replies from.
+    socket: UdpSocket,
+    // Where the client's datagrams must come from. The port is only known up front when the
+    // request gave it; otherwise it's taken from the first datagram.
+    client_ip: IpAddr,
+    client_port: Option<u16>,
+    // Slot under `Config::max_pending_associations`, held for as long as the association.
+    _slot: Option<OwnedSemaphorePermit>,
+}
+
+// How an association ended and what it relayed, payloads only.
+pub(crate) struct UdpOutcome {
+    pub reason: CloseReason,
+    pub bytes_client_to_remote: u64,
+    pub bytes_remote_to_client: u64,
+}
+
+impl UdpAssociation {
+    pub fn new(
+        socket: UdpSocket,
+        client_ip: IpAddr,
+        client_port: Option<u16>,
+        slot: Option<OwnedSemaphorePermit>,
+    ) -> Self {
+        Self {
+            socket,
+            client_ip: client_ip.to_canonical(),
+            client_port,
+            _slot: slot,
+        }
+    }
+
+    // Relays datagrams until `control`, the connection the association was requested over,
+    // closes or something else ends the association. Datagrams that can't be relayed, e.g.
+    // because of a malformed header or a destination `Config::access_control` denies, are
+    // dropped.
+    pub async fn run(
+        self,
+        control: &mut TcpStream,
+        state: &ServerState,
+        user_account: Option<UserAccount>,
+    ) -> UdpOutcome {
+        let config = &state.config;
+        let _open = state.metrics.association_opened();
+        let mut outcome = UdpOutcome {
+            reason: CloseReason::Completed,
+            bytes_client_to_remote: 0,
+            bytes_remote_to_client: 0,
+        };
+
+        let mut client_addr = self
+            .client_port
+            .map(|port| SocketAddr::new(self.client_ip, port));
+        // Datagrams go out to destinations from sockets of their own, one per address family,
+        // opened on first use.
+        let mut outbound_v4 = None;
+        let mut outbound_v6 = None;
+        let idle_timeout = match config.idle_policy {
+            Some(IdlePolicy::Close(timeout)) => Some(timeout),
+            _ => None,
+        };
+        let mut last_activity = Instant::now();
+        let mut shutdown = state.shutdown.subscribe();
+        let mut shutdown_grace = pin!(shutdown_grace_elapsed(&mut shutdown, config.shutdown_grace));
+        let mut control_buf = [0; 64];
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        // Resolving a name can take a while, so it happens alongside relaying everything else
+        // rather than holding the whole association up.
+        let mut resolving: Vec<PendingDatagram<'_>> = Vec::new();
+
+        loop {
+            let (client_to_remote, n) = tokio::select! {
+                read = control.read(&mut control_buf) => match read {
+                    Ok(0) => return outcome,
+                    // There's nothing for the client to say on the control connection once the
+                    // association is set up, so whatever it sends is ignored.
+                    Ok(_) => continue,
+                    Err(e) => {
+                        outcome.reason = CloseReason::Error(e.kind());
+                        return outcome;
+                    }
+                },
+                _ = self.socket.readable() => {
+                    let received = self.receive_datagram(&mut client_addr, &mut buf);
+                    let Some((header, payload)) = received else {
+                        continue;
+                    };
+                    if let DestinationAddress::DomainName(_) = header.destination_addr {
+                        if resolving.len() < MAX_PENDING_RESOLUTIONS {
+                            let payload = buf[payload].to_vec();
+                            resolving.push(Box::pin(async move {
+                                let addr = datagram_destination(header, state).await?;
+                                Some((addr, payload))
+                            }));
+                        }
+                        continue;
+                    }
+                    let Some(addr) = datagram_destination(header, state).await else {
+                        continue;
+                    };
+                    let sent =
+                        send_to_destination(addr, &buf[payload], &mut outbound_v4, &mut outbound_v6)
+                            .await;
+                    match sent {
+                        Some(n) => (true, n),
+                        None => continue,
+                    }
+                }
+                resolved = first_finished(&mut resolving), if !resolving.is_empty() => {
+                    let Some((addr, payload)) = resolved else {
+                        continue;
+                    };
+                    let sent =
+                        send_to_destination(addr, &payload, &mut outbound_v4, &mut outbound_v6)
+                            .await;
+                    match sent {
+                        Some(n) => (true, n),
+                        None => continue,
+                    }
+                }
+                _ = readable(&outbound_v4) => {
+                    match self.relay_reply(outbound_v4.as_ref(), client_addr, &mut buf).await {
+                        Some(n) => (false, n),
+                        None => continue,
+                    }
+                }
+                _ = readable(&outbound_v6) => {
+                    match self.relay_reply(outbound_v6.as_ref(), client_addr, &mut buf).await {
+                        Some(n) => (false, n),
+                        None => continue,
+                    }
+                }
+                _ = time::sleep_until(last_activity + idle_timeout.unwrap_or_default()),
+                    if idle_timeout.is_some() =>
+                {
+                    outcome.reason = CloseReason::IdleTimeout;
+                    return outcome;
+                }
+                _ = &mut shutdown_grace => {
+                    outcome.reason = CloseReason::Shutdown;
+                    return outcome;
+                }
+            };
+
+            last_activity = Instant::now();
+            let n = n as u64;
+            state.metrics.bytes_relayed(client_to_remote, n);
+            if client_to_remote {
+                outcome.bytes_client_to_remote += n;
+            } else {
+                outcome.bytes_remote_to_client += n;
+            }
+            if user_account
+                .as_ref()
+                .is_some_and(|account| account.charge(n))
+            {
+                outcome.reason = CloseReason::UserQuotaExceeded;
+                return outcome;
+            }
+            let relayed = outcome.bytes_client_to_remote + outcome.bytes_remote_to_client;
+            if config
+                .max_bytes_per_connection
+                .is_some_and(|max_bytes| relayed >= max_bytes)
+            {
+                outcome.reason = CloseReason::QuotaExceeded;
+                return outcome;
+            }
+        }
+    }
+
+    // Takes a datagram waiting from the client, returning its header and where in `buf` its
+    // payload is, unless the datagram must be dropped.
+    fn receive_datagram(
+        &self,
+        client_addr: &mut Option<SocketAddr>,
+        buf: &mut [u8],
+    ) -> Option<(UdpHeader, Range<usize>)> {
+        // A failed receive only loses the datagram at hand.
+        let (n, from) = self.socket.try_recv_from(buf).ok()?;
+        if from.ip().to_canonical() != self.client_ip
+            || client_addr.is_some_and(|addr| addr.port() != from.port())
+        {
+            return None;
+        }
+        *client_addr = Some(from);
+
+        let (header, payload) = UdpHeader::parse(&buf[..n]).ok()?;
+
+        Some((header, n - payload.len()..n))
+    }
+
+    // Sends a datagram waiting on `outbound` on to the client, behind a header naming where it
+    // came from, returning the payload's size unless the datagram was dropped.
+    async fn relay_reply(
+        &self,
+        outbound: Option<&UdpSocket>,
+        client_addr: Option<SocketAddr>,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let (n, from) = outbound?.try_recv_from(buf).ok()?;
+        let datagram = UdpHeader::wrap(from, &buf[..n]);
+        self.socket.send_to(&datagram, client_addr?).await.ok()?;
+
+        Some(n)
+    }
+}
+
+// Sends `payload` on to `addr` from the outbound socket of its address family, opening that
+// socket on first use, and returns the payload's size unless it was dropped.
+async fn send_to_destination(
+    addr: SocketAddr,
+    payload: &[u8],
+    outbound_v4: &mut Option<UdpSocket>,
+    outbound_v6: &mut Option<UdpSocket>,
+) -> Option<usize> {
+    let outbound = if addr.is_ipv4() {
+        outbound_v4
+    } else {
+        outbound_v6
+    };
+    if outbound.is_none() {
+        let unspecified = if addr.is_ipv4() {
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+        } else {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
+        };
+        *outbound = Some(UdpSocket::bind((unspecified, 0)).await.ok()?);
+    }
+    outbound.as_ref()?.send_to(payload, addr).await.ok()
+}
+
+// Where to send a datagram from the client, unless it must be dropped. Domain names are
+// resolved the same way as for CONNECT requests, and the first address they resolve to is used.
+async fn datagram_destination(header: UdpHeader, state: &ServerState) -> Option<SocketAddr> {
+    let config = &state.config;
+    let port = header.destination_port;
+    if port == 0 {
+        return None;
+    }
+    let (addr, domain) = match header.destination_addr {
+        DestinationAddress::Ipv4(v4_addr) => (SocketAddr::from((v4_addr, port)), None),
+        DestinationAddress::Ipv6(v6_addr) => (SocketAddr::from((v6_addr, port)), None),
+        DestinationAddress::DomainName(domain) => {
+            let addrs = time::timeout(
+                config.resolve_timeout,
+                resolve_destination(&domain, port, state),
+            )
+            .await
+            .ok()?
+            .ok()?;
+            (*addrs.first()?, Some(domain))
+        }
+    };
+
+    if !config.allow_unspecified_destinations && is_unspecified(addr.ip()) {
+        return None;
+    }
+    if let Some(access_control) = &config.access_control {
+        if !access_control.is_allowed(domain.as_deref(), addr) {
+            return None;
+        }
+    }
+
+    Some(addr)
+}
+
+// Resolves once `socket` has a datagram waiting, or never without a socket.
+async fn readable(socket: &Option<UdpSocket>) -> io::Result<()> {
+    match socket {
+        Some(socket) => socket.readable().await,
+        None => future::pending().await,
+    }
+}
+
+// Resolves once the server has been shutting down for `grace`, or never without one, in which
+// case associations run until their client closes them.
+async fn shutdown_grace_elapsed(shutdown: &mut watch::Receiver<bool>, grace: Option<Duration>) {
+    if let Some(grace) = grace {
+        if shutdown
+            .wait_for(|&shutting_down| shutting_down)
+            .await
+            .is_ok()
+        {
+            time::sleep(grace).await;
+            return;
+        }
+    }
+    future::pending().await
+}
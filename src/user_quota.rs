@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+const MAX_USERS: usize = 4096;
+
+/// Limit on the bytes each authenticated user may relay per window, counted across all of
+/// their connections. Once a user reaches it, their new requests are answered with a connection
+/// not allowed reply until the window ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserByteQuota {
+    /// Bytes a user may relay per window, counting both directions of every connection.
+    pub max_bytes: u64,
+    /// How long a window lasts, e.g. a day. A user's window starts with the first byte they
+    /// relay and the next one with the first byte after it ended.
+    pub window: Duration,
+    /// Also close the user's running relays once they're over quota, instead of only refusing
+    /// their new requests. Each relay is closed as soon as it relays anything past the quota,
+    /// after delivering the chunk that went over, so usage can end up slightly above
+    /// `max_bytes`. Relays that stay idle are left alone.
+    pub cut_off_relays: bool,
+}
+
+// Bytes relayed per username in the current window of each user, shared by all connections of
+// a server. Users whose window ended hold nothing worth keeping and are swept out once the map
+// fills up; if it's still full after that, the user whose window would end soonest is evicted
+// early, which only ever resets their usage a bit ahead of time.
+#[derive(Debug)]
+pub(crate) struct UserUsage {
+    users: Mutex<HashMap<String, WindowUsage>>,
+}
+
+#[derive(Debug)]
+struct WindowUsage {
+    started_at: Instant,
+    bytes: u64,
+}
+
+impl UserUsage {
+    pub fn new() -> Self {
+        Self {
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Bytes `username` relayed so far in their current window.
+    pub fn used(&self, username: &str, quota: &UserByteQuota) -> u64 {
+        let users = self.users.lock().unwrap();
+        match users.get(username) {
+            Some(usage) if usage.started_at.elapsed() < quota.window => usage.bytes,
+            _ => 0,
+        }
+    }
+
+    // Counts `n` more bytes for `username`, returning their total in the current window.
+    fn add(&self, username: &str, n: u64, quota: &UserByteQuota) -> u64 {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+
+        if users.len() >= MAX_USERS && !users.contains_key(username) {
+            users.retain(|_, usage| now - usage.started_at < quota.window);
+        }
+        if users.len() >= MAX_USERS && !users.contains_key(username) {
+            let soonest_to_reset = users
+                .iter()
+                .min_by_key(|(_, usage)| usage.started_at)
+                .map(|(username, _)| username.clone());
+            if let Some(soonest_to_reset) = soonest_to_reset {
+                users.remove(&soonest_to_reset);
+            }
+        }
+
+        let usage = users.entry(username.to_string()).or_insert(WindowUsage {
+            started_at: now,
+            bytes: 0,
+        });
+        if now - usage.started_at >= quota.window {
+            usage.started_at = now;
+            usage.bytes = 0;
+        }
+        usage.bytes += n;
+
+        usage.bytes
+    }
+}
+
+// Charges what a connection relays to the user it authenticated as.
+#[derive(Debug)]
+pub(crate) struct UserAccount {
+    usage: Arc<UserUsage>,
+    username: String,
+    quota: UserByteQuota,
+}
+
+impl UserAccount {
+    pub fn new(usage: &Arc<UserUsage>, username: String, quota: UserByteQuota) -> Self {
+        Self {
+            usage: Arc::clone(usage),
+            username,
+            quota,
+        }
+    }
+
+    // Returns `true` when the user's running relays should be cut off.
+    pub fn charge(&self, n: u64) -> bool {
+        let used = self.usage.add(&self.username, n, &self.quota);
+
+        self.quota.cut_off_relays && used >= self.quota.max_bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA: UserByteQuota = UserByteQuota {
+        max_bytes: 100,
+        window: Duration::from_secs(60),
+        cut_off_relays: true,
+    };
+
+    #[tokio::test]
+    async fn sums_charges_of_a_users_accounts() {
+        let usage = Arc::new(UserUsage::new());
+        let first = UserAccount::new(&usage, "alice".to_string(), QUOTA);
+        let second = UserAccount::new(&usage, "alice".to_string(), QUOTA);
+        let other = UserAccount::new(&usage, "bob".to_string(), QUOTA);
+
+        assert!(!first.charge(60));
+        assert!(!other.charge(60));
+        assert!(second.charge(40));
+        assert_eq!(usage.used("alice", &QUOTA), 100);
+        assert_eq!(usage.used("bob", &QUOTA), 60);
+    }
+
+    #[tokio::test]
+    async fn leaves_relays_running_without_cut_off() {
+        let quota = UserByteQuota {
+            cut_off_relays: false,
+            ..QUOTA
+        };
+        let account = UserAccount::new(&Arc::new(UserUsage::new()), "alice".to_string(), quota);
+
+        assert!(!account.charge(500));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn starts_a_new_window_once_the_last_one_ends() {
+        let usage = UserUsage::new();
+        usage.add("alice", 90, &QUOTA);
+
+        tokio::time::advance(QUOTA.window).await;
+        assert_eq!(usage.used("alice", &QUOTA), 0);
+        assert_eq!(usage.add("alice", 10, &QUOTA), 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn makes_room_for_new_users_once_full() {
+        let usage = UserUsage::new();
+        for i in 0..MAX_USERS {
+            usage.add(&format!("user{i}"), 1, &QUOTA);
+            tokio::time::advance(Duration::from_millis(1)).await;
+        }
+
+        usage.add("newcomer", 1, &QUOTA);
+        assert_eq!(usage.users.lock().unwrap().len(), MAX_USERS);
+        assert_eq!(usage.used("newcomer", &QUOTA), 1);
+        // The user whose window started first is the one evicted.
+        assert_eq!(usage.used("user0", &QUOTA), 0);
+        assert_eq!(usage.used("user1", &QUOTA), 1);
+    }
+}